@@ -0,0 +1,80 @@
+#[cfg(target_os = "linux")]
+mod linux {
+    use hwbp::{Context, Session, SessionEvent};
+    use std::{process::Command, time::Duration};
+
+    static mut COUNTER: u64 = 0;
+
+    /// Gets the address the executable is loaded at in a process.
+    fn base_address(pid: u32) -> u64 {
+        let maps = std::fs::read_to_string(format!("/proc/{pid}/maps")).unwrap();
+        let start = maps.split('-').next().unwrap();
+        u64::from_str_radix(start, 16).unwrap()
+    }
+
+    fn child() {
+        let worker = std::thread::spawn(|| loop {
+            unsafe { core::ptr::write_volatile(&raw mut COUNTER, COUNTER + 1) };
+            std::thread::sleep(Duration::from_millis(10));
+        });
+        worker.join().unwrap();
+    }
+
+    fn parent() {
+        let exe = std::env::current_exe().unwrap();
+        let mut child = Command::new(exe).arg("child").spawn().unwrap();
+        std::thread::sleep(Duration::from_millis(100));
+
+        let session = Session::attach(child.id()).unwrap();
+
+        // The child runs the same executable, so the variable is at the same offset.
+        let offset = &raw const COUNTER as u64 - base_address(std::process::id());
+        let address = base_address(session.pid()) + offset;
+
+        let mut ctx = Context::current_with(&session).unwrap();
+        ctx.unused()
+            .unwrap()
+            .with_address(address)
+            .with_condition(hwbp::Condition::Write)
+            .with_size(hwbp::Size::EightBytes)
+            .with_callback(|_| println!("counter written"))
+            .with_enabled(true)
+            .build_and_set()
+            .unwrap();
+        ctx.apply_for_all_threads().expect("Failed to apply");
+
+        for _ in 0..3 {
+            if let SessionEvent::Breakpoint { thread_id } = session.wait().unwrap() {
+                println!("hit by thread {thread_id}");
+            }
+        }
+
+        session.detach().unwrap();
+        println!("detached");
+
+        child.kill().unwrap();
+        child.wait().unwrap();
+
+        // Output:
+        // counter written
+        // hit by thread ...
+        // counter written
+        // hit by thread ...
+        // counter written
+        // hit by thread ...
+        // detached
+    }
+
+    pub fn main() {
+        if std::env::args().nth(1).as_deref() == Some("child") {
+            child();
+        } else {
+            parent();
+        }
+    }
+}
+
+fn main() {
+    #[cfg(target_os = "linux")]
+    linux::main();
+}
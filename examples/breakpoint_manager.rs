@@ -0,0 +1,49 @@
+use hwbp::{BreakpointManager, Condition, Scope, Size};
+
+fn main() {
+    hwbp::init();
+
+    let mut x = 42u32;
+    let mut y = 42u32;
+
+    let mut manager = BreakpointManager::new();
+    let id = manager
+        .add(
+            Scope::CurrentThread,
+            &raw const x as u64,
+            Condition::Write,
+            Size::FourBytes,
+            |hit| println!("callback at {:#x}", hit.get_address()),
+        )
+        .unwrap();
+
+    unsafe { core::ptr::write_volatile(&mut x, 1) };
+
+    // Watch `y` instead, then stop watching for a while.
+    manager
+        .modify(id, &raw const y as u64, Condition::Write, Size::FourBytes)
+        .unwrap();
+    unsafe { core::ptr::write_volatile(&mut x, 2) };
+    unsafe { core::ptr::write_volatile(&mut y, 1) };
+
+    manager.disable(id).unwrap();
+    unsafe { core::ptr::write_volatile(&mut y, 2) };
+
+    for info in manager.list() {
+        println!(
+            "{:?}: {:#x} enabled = {}, hit count = {}",
+            info.id, info.address, info.is_enabled, info.hit_count
+        );
+    }
+
+    manager.remove(id).unwrap();
+    println!("breakpoints = {}", manager.list().len());
+
+    // Output:
+    // callback at 0x...
+    // callback at 0x...
+    // BreakpointId(0): 0x... enabled = false, hit count = 2
+    // breakpoints = 0
+
+    hwbp::free();
+}
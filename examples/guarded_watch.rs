@@ -0,0 +1,42 @@
+use hwbp::{Condition, Context};
+use std::cell::Cell;
+
+fn count_down(ctx: &mut Context, from: u32) {
+    let counter = Cell::new(from);
+
+    // The guard borrows `counter`, so it cannot outlive this stack frame.
+    let mut guard = ctx
+        .unused()
+        .unwrap()
+        .with_enabled(true)
+        .watch_guarded(&counter, Condition::Write, |_, value| {
+            println!("counter = {}", value.get())
+        })
+        .unwrap();
+    guard.apply_for_current_thread().expect("Failed to apply");
+
+    while counter.get() > 0 {
+        counter.set(counter.get() - 1);
+    }
+
+    println!("hit count = {}", guard.get_hit_count());
+
+    // Dropping the guard restores the hardware breakpoint.
+}
+
+fn main() {
+    hwbp::init();
+
+    let mut ctx = Context::current().unwrap();
+    count_down(&mut ctx, 2);
+    count_down(&mut ctx, 1);
+
+    // Output:
+    // counter = 1
+    // counter = 0
+    // hit count = 2
+    // counter = 0
+    // hit count = 1
+
+    hwbp::free();
+}
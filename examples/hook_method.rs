@@ -1,14 +1,20 @@
-use hwbp::windows::CONTEXT;
-use hwbp::Context;
+use hwbp::{Context, HitContext};
 
 #[inline(never)]
 extern "system" fn test_method(num: u32) {
     println!("test_method called with {}", num);
 }
 
-fn hooked_method(ctx: &mut CONTEXT) {
+#[cfg(windows)]
+fn hooked_method(hit: &mut HitContext) {
     println!("hooked_method called");
-    ctx.Rcx += 27;
+    hit.registers_mut().Rcx += 27;
+}
+
+#[cfg(target_os = "linux")]
+fn hooked_method(hit: &mut HitContext) {
+    println!("hooked_method called");
+    hit.registers_mut().uc_mcontext.gregs[libc::REG_RDI as usize] += 27;
 }
 
 fn main() {
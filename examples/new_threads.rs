@@ -0,0 +1,47 @@
+use hwbp::Context;
+use std::sync::{
+    atomic::{AtomicU32, Ordering},
+    Arc,
+};
+
+fn main() {
+    hwbp::init();
+
+    let mut x = 42;
+    let triggered = Arc::new(AtomicU32::new(0));
+
+    let mut ctx = Context::current().unwrap();
+    ctx.unused()
+        .unwrap()
+        .watch_variable_write(&x, {
+            let triggered = triggered.clone();
+            move |_| {
+                triggered.fetch_add(1, Ordering::Relaxed);
+            }
+        })
+        .unwrap()
+        .with_enabled(true)
+        .build_and_set()
+        .unwrap();
+
+    // Threads created from now on get the breakpoint too.
+    ctx.set_inherited(true);
+    ctx.apply_for_current_thread().expect("Failed to apply");
+
+    let address = &raw mut x as usize;
+    std::thread::spawn(move || unsafe { core::ptr::write_volatile(address as *mut i32, 69) })
+        .join()
+        .unwrap();
+
+    println!("x = {}", x);
+    println!("triggered = {}", triggered.load(Ordering::Relaxed));
+
+    // Output:
+    // x = 69
+    // triggered = 1
+
+    ctx.disable_all();
+    ctx.apply_for_current_thread().expect("Failed to apply");
+
+    hwbp::free();
+}
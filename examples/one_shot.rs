@@ -0,0 +1,42 @@
+use hwbp::{Context, HitAction};
+use std::sync::{
+    atomic::{AtomicU32, Ordering},
+    Arc,
+};
+
+fn main() {
+    hwbp::init();
+
+    let mut x = 42;
+    let triggered = Arc::new(AtomicU32::new(0));
+
+    let mut ctx = Context::current().unwrap();
+    ctx.unused()
+        .unwrap()
+        .watch_variable_write(&x, {
+            let triggered = triggered.clone();
+            move |_| {
+                triggered.fetch_add(1, Ordering::Relaxed);
+                // Only the first write is of interest.
+                HitAction::Remove
+            }
+        })
+        .unwrap()
+        .with_enabled(true)
+        .build_and_set()
+        .unwrap();
+    ctx.apply_for_current_thread().expect("Failed to apply");
+
+    for i in 0..3 {
+        unsafe { core::ptr::write_volatile(&mut x, i) };
+    }
+
+    println!("x = {}", x);
+    println!("triggered = {}", triggered.load(Ordering::Relaxed));
+
+    // Output:
+    // x = 2
+    // triggered = 1
+
+    hwbp::free();
+}
@@ -0,0 +1,50 @@
+use hwbp::{Condition, Context, WatchStrategy};
+
+/// Page watches protect whole pages, so keep the watched memory on its own page.
+#[repr(C, align(4096))]
+struct Page {
+    header: [u8; 64],
+    buffer: [u8; 256],
+}
+
+pub fn main() {
+    hwbp::init();
+
+    let mut page = Box::new(Page {
+        header: [0; 64],
+        buffer: [0; 256],
+    });
+
+    let mut ctx = Context::current().unwrap();
+    ctx.set_strategy(WatchStrategy::Fallback);
+
+    // Too big for the debug registers, so this protects the page instead.
+    let range = ctx
+        .watch_range(
+            page.buffer.as_ptr() as u64,
+            page.buffer.len() as u64,
+            Condition::Write,
+            |hit| println!("write at {:#x}", hit.get_address()),
+        )
+        .unwrap();
+    println!("paging: {}", range.get_page_watch().is_some());
+
+    unsafe {
+        // Outside of the range, but on the same page.
+        core::ptr::write_volatile(&mut page.header[0], 1);
+        core::ptr::write_volatile(&mut page.buffer[0], 2);
+        core::ptr::write_volatile(&mut page.buffer[200], 3);
+    }
+
+    range.get_page_watch().unwrap().clone().remove();
+
+    println!("hit count = {}", range.get_hit_count());
+
+    // Output:
+    // paging: true
+    // write at 0x...
+    // write at 0x...
+    // hit count = 2
+
+    hwbp::free();
+}
@@ -0,0 +1,79 @@
+use hwbp::{Backend, Context, NativeBackend, Scope};
+use std::sync::{
+    atomic::{AtomicU32, Ordering},
+    mpsc, Arc, Barrier,
+};
+
+static X: AtomicU32 = AtomicU32::new(0);
+static Y: AtomicU32 = AtomicU32::new(0);
+
+fn main() {
+    hwbp::init();
+
+    // The worker takes its first hardware breakpoint for something else.
+    let ready = Arc::new(Barrier::new(2));
+    let done = Arc::new(Barrier::new(2));
+    let (send, receive) = mpsc::channel();
+    let worker = std::thread::spawn({
+        let (ready, done) = (ready.clone(), done.clone());
+        move || {
+            let mut ctx = Context::current().unwrap();
+            ctx.unused()
+                .unwrap()
+                .watch_variable_write(&Y, |_| {})
+                .unwrap()
+                .with_enabled(true)
+                .build_and_set()
+                .unwrap();
+            ctx.apply_for_current_thread().expect("Failed to apply");
+            send.send(NativeBackend::default().current_thread_id())
+                .unwrap();
+
+            ready.wait();
+            X.store(1, Ordering::Relaxed);
+            done.wait();
+
+            ctx.disable_all();
+            ctx.apply_for_current_thread().expect("Failed to apply");
+        }
+    });
+    let worker_id = receive.recv().unwrap();
+
+    let triggered = Arc::new(AtomicU32::new(0));
+    let mut ctx = Context::current().unwrap();
+    let mut breakpoint = ctx
+        .builder()
+        .watch_variable_write(&X, {
+            let triggered = triggered.clone();
+            move |hit| {
+                println!("thread {} hit {:?}", hit.get_thread_id(), hit.get_index());
+                triggered.fetch_add(1, Ordering::Relaxed);
+            }
+        })
+        .unwrap()
+        .with_enabled(true)
+        .build_scoped(Scope::Process)
+        .unwrap();
+
+    let main_id = NativeBackend::default().current_thread_id();
+    println!("main thread: {:?}", breakpoint.get_index(main_id));
+    println!("worker thread: {:?}", breakpoint.get_index(worker_id));
+
+    X.store(1, Ordering::Relaxed);
+    ready.wait();
+    done.wait();
+    worker.join().unwrap();
+
+    println!("triggered = {}", triggered.load(Ordering::Relaxed));
+
+    // Output:
+    // main thread: Some(First)
+    // worker thread: Some(Second)
+    // thread ... hit First
+    // thread ... hit Second
+    // triggered = 2
+
+    breakpoint.disable().expect("Failed to disable");
+
+    hwbp::free();
+}
@@ -0,0 +1,43 @@
+use hwbp::{ArmedWatch, Context, WatchStrategy};
+
+#[inline(never)]
+fn some_function(x: u64) -> u64 {
+    std::hint::black_box(x) * 2
+}
+
+pub fn main() {
+    hwbp::init();
+
+    let mut ctx = Context::current().unwrap();
+
+    // Patches an `int3` into the function instead of taking up a debug register.
+    let watch = ctx
+        .builder()
+        .with_strategy(WatchStrategy::Software)
+        .watch_memory_execute(some_function as _, |hit| {
+            println!("called at {:#x}", hit.get_address());
+        })
+        .with_enabled(true)
+        .build_and_watch()
+        .unwrap();
+
+    for i in 0..3 {
+        some_function(i);
+    }
+
+    let ArmedWatch::Software(breakpoint) = watch else {
+        unreachable!();
+    };
+    breakpoint.disable().unwrap();
+    some_function(3);
+
+    println!("hit count = {}", breakpoint.get_hit_count());
+
+    // Output:
+    // called at 0x...
+    // called at 0x...
+    // called at 0x...
+    // hit count = 3
+
+    hwbp::free();
+}
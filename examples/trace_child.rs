@@ -0,0 +1,67 @@
+#[cfg(target_os = "linux")]
+mod linux {
+    use hwbp::{linux::user_regs_struct, Context, HitContext, Session};
+    use std::process::Command;
+
+    static mut VALUE: u32 = 42;
+
+    /// Gets the address the executable is loaded at in a process.
+    fn base_address(pid: u32) -> u64 {
+        let maps = std::fs::read_to_string(format!("/proc/{pid}/maps")).unwrap();
+        let start = maps.split('-').next().unwrap();
+        u64::from_str_radix(start, 16).unwrap()
+    }
+
+    fn child() {
+        unsafe { core::ptr::write_volatile(&raw mut VALUE, 69) };
+        println!("child: value = {}", unsafe { VALUE });
+    }
+
+    fn parent() {
+        let exe = std::env::current_exe().unwrap();
+        let session = Session::spawn(Command::new(exe).arg("child")).unwrap();
+
+        // The child runs the same executable, so the variable is at the same offset.
+        let offset = &raw const VALUE as u64 - base_address(std::process::id());
+        let address = base_address(session.pid()) + offset;
+
+        let mut ctx = Context::current_with(&session).unwrap();
+        ctx.unused()
+            .unwrap()
+            .with_address(address)
+            .with_condition(hwbp::Condition::Write)
+            .with_size(hwbp::Size::FourBytes)
+            .with_callback(|hit: &mut HitContext<user_regs_struct>| {
+                println!(
+                    "parent: thread {} wrote the value at {:#x}",
+                    hit.get_thread_id(),
+                    hit.get_instruction_pointer()
+                );
+            })
+            .with_enabled(true)
+            .build_and_set()
+            .unwrap();
+        ctx.apply_for_current_thread().expect("Failed to apply");
+
+        let code = session.run().unwrap();
+        println!("parent: child exited with {code}");
+
+        // Output:
+        // parent: thread ... wrote the value at 0x...
+        // child: value = 69
+        // parent: child exited with 0
+    }
+
+    pub fn main() {
+        if std::env::args().nth(1).as_deref() == Some("child") {
+            child();
+        } else {
+            parent();
+        }
+    }
+}
+
+fn main() {
+    #[cfg(target_os = "linux")]
+    linux::main();
+}
@@ -0,0 +1,53 @@
+use hwbp::{Context, Trace};
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+#[inline(never)]
+extern "system" fn test_method(num: u32) -> u32 {
+    std::hint::black_box(num) * 2 + 1
+}
+
+fn main() {
+    hwbp::init();
+
+    let steps = Arc::new(AtomicUsize::new(0));
+
+    let mut ctx = Context::current().unwrap();
+    ctx.unused()
+        .unwrap()
+        .watch_memory_execute(test_method as _, {
+            let steps = steps.clone();
+            move |hit| {
+                let steps = steps.clone();
+                // Trace the first few instructions of the function.
+                hit.start_trace(
+                    Trace::new(move |step| {
+                        steps.fetch_add(1, Ordering::Relaxed);
+                        println!("step at {:#x}", step.get_instruction_pointer());
+                    })
+                    .with_max_steps(3),
+                );
+            }
+        })
+        .with_enabled(true)
+        .build_and_set()
+        .unwrap();
+    ctx.apply_for_current_thread().expect("Failed to apply");
+
+    println!("result = {}", test_method(20));
+    println!("steps = {}", steps.load(Ordering::Relaxed));
+
+    // Output:
+    // step at 0x...
+    // step at 0x...
+    // step at 0x...
+    // result = 41
+    // steps = 3
+
+    ctx.disable_all();
+    ctx.apply_for_current_thread().expect("Failed to apply");
+
+    hwbp::free();
+}
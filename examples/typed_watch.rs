@@ -0,0 +1,33 @@
+use hwbp::{Condition, Context};
+use std::sync::atomic::{AtomicU32, Ordering};
+
+pub fn main() {
+    hwbp::init();
+
+    let x = AtomicU32::new(42);
+
+    let mut ctx = Context::current().unwrap();
+    let mut watch = ctx
+        .unused()
+        .unwrap()
+        .with_enabled(true)
+        .watch(&x, Condition::Write, |_, x| {
+            println!("x = {}", x.load(Ordering::Relaxed))
+        })
+        .unwrap();
+    watch.apply_for_current_thread().expect("Failed to apply");
+
+    x.store(69, Ordering::Relaxed);
+
+    println!("hit count = {}", watch.get_hit_count());
+
+    // Dropping the watch removes it, as it would when `x` goes away.
+    drop(watch);
+    x.store(1, Ordering::Relaxed);
+
+    // Output:
+    // x = 69
+    // hit count = 1
+
+    hwbp::free();
+}
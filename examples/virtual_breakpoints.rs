@@ -0,0 +1,48 @@
+use hwbp::{Condition, Context, MultiplexPolicy, Multiplexer, Size, VirtualBreakpoint};
+
+pub fn main() {
+    hwbp::init();
+
+    let mut values = [0u64; 6];
+
+    let ctx = Context::current().unwrap();
+    let mut multiplexer = Multiplexer::new(ctx, MultiplexPolicy::RoundRobin);
+    let ids = values
+        .iter()
+        .enumerate()
+        .map(|(i, value)| {
+            let breakpoint = VirtualBreakpoint::new(
+                value as *const u64 as u64,
+                Condition::Write,
+                Size::EightBytes,
+                move |_| println!("values[{}] written", i),
+            );
+            multiplexer.add(breakpoint).unwrap()
+        })
+        .collect::<Vec<_>>();
+
+    // Every round arms the next four breakpoints.
+    for round in 0..3 {
+        multiplexer.rearm();
+        multiplexer
+            .context()
+            .apply_for_current_thread()
+            .expect("Failed to apply");
+        println!("round {}: live {:?}", round, multiplexer.get_live());
+
+        for value in values.iter_mut() {
+            unsafe { core::ptr::write_volatile(value, round) };
+        }
+    }
+
+    for id in ids {
+        println!("{:?} hit {:?} times", id, multiplexer.get_hit_count(id));
+    }
+
+    // Output:
+    // round 0: live [Some(VirtualId(0)), Some(VirtualId(1)), Some(VirtualId(2)), Some(VirtualId(3))]
+    // values[0] written
+    // ...
+
+    hwbp::free_and_clear().unwrap();
+}
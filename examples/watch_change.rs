@@ -0,0 +1,37 @@
+use hwbp::Context;
+
+pub fn main() {
+    hwbp::init();
+
+    let mut x = 42u32;
+
+    let mut ctx = Context::current().unwrap();
+    let mut hwbp = ctx
+        .unused()
+        .unwrap()
+        .watch_variable_change(&x, true, |_, change| {
+            println!("x changed from {} to {}", change.old, change.new)
+        })
+        .unwrap()
+        .with_enabled(true)
+        .build_and_set()
+        .unwrap();
+    ctx.apply_for_current_thread().expect("Failed to apply");
+
+    for value in [42, 69, 69, 7] {
+        unsafe { core::ptr::write_volatile(&mut x, value) };
+    }
+
+    hwbp.disable();
+    ctx.set(&hwbp);
+    ctx.apply_for_current_thread().expect("Failed to apply");
+
+    println!("hit count = {}", hwbp.get_hit_count());
+
+    // Output:
+    // x changed from 42 to 69
+    // x changed from 69 to 7
+    // hit count = 4
+
+    hwbp::free();
+}
@@ -0,0 +1,49 @@
+use hwbp::{Condition, Context};
+
+#[repr(C)]
+struct Player {
+    health: u32,
+    position: [f32; 3],
+    score: u32,
+}
+
+pub fn main() {
+    hwbp::init();
+
+    let mut player = Player {
+        health: 100,
+        position: [0.0; 3],
+        score: 0,
+    };
+
+    let mut ctx = Context::current().unwrap();
+    let mut range = ctx
+        .watch_range(
+            &player as *const Player as u64,
+            std::mem::size_of::<Player>() as u64,
+            Condition::Write,
+            |hit| println!("write at {:#x}", hit.get_address()),
+        )
+        .unwrap();
+    ctx.apply_for_current_thread().expect("Failed to apply");
+    println!("watching with {} slots", range.get_hwbps().len());
+
+    unsafe {
+        core::ptr::write_volatile(&mut player.health, 50);
+        core::ptr::write_volatile(&mut player.position, [1.0, 2.0, 3.0]);
+        core::ptr::write_volatile(&mut player.score, 10);
+    }
+
+    range.disable();
+    ctx.set_range(&range);
+    ctx.apply_for_current_thread().expect("Failed to apply");
+
+    println!("hit count = {}", range.get_hit_count());
+
+    // Output:
+    // watching with 3 slots
+    // write at 0x..., once for every instruction writing to the player
+    // hit count = ...
+
+    hwbp::free();
+}
@@ -1,7 +1,4 @@
 use hwbp::Context;
-use std::sync::atomic::AtomicU32;
-
-static TRIGGERED: AtomicU32 = AtomicU32::new(0);
 
 pub fn main() {
     hwbp::init();
@@ -12,32 +9,38 @@ pub fn main() {
     let mut hwbp = ctx
         .unused()
         .unwrap()
-        .watch_variable_write(&x, |_| {
-            TRIGGERED.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
-            println!("callback")
+        .watch_variable_write(&x, |hit| {
+            println!(
+                "callback for {:?} at {:#x}, x = {}",
+                hit.get_index(),
+                hit.get_address(),
+                hit.read_value().unwrap()
+            )
         })
         .unwrap()
+        .with_ignore_count(1)
+        .with_predicate(|hit| hit.read_value().is_ok_and(|x| x % 2 == 1))
         .with_enabled(true)
         .build_and_set()
         .unwrap();
     ctx.apply_for_current_thread().expect("Failed to apply");
 
-    unsafe { core::ptr::write_volatile(&mut x, 69) };
+    for value in [1, 2, 3, 4, 69] {
+        unsafe { core::ptr::write_volatile(&mut x, value) };
+    }
 
     hwbp.disable();
     ctx.set(&hwbp);
     ctx.apply_for_current_thread().expect("Failed to apply");
 
     println!("x = {}", x);
-    println!(
-        "triggered = {}",
-        TRIGGERED.load(std::sync::atomic::Ordering::Relaxed)
-    );
+    println!("hit count = {}", hwbp.get_hit_count());
 
     // Output:
-    // callback
+    // callback for First at 0x..., x = 3
+    // callback for First at 0x..., x = 69
     // x = 69
-    // triggered = 1
+    // hit count = 5
 
     hwbp::free();
 }
@@ -0,0 +1,162 @@
+use std::{
+    fmt,
+    sync::mpsc::{self, RecvTimeoutError, Sender},
+    thread::JoinHandle,
+    time::Duration,
+};
+
+use crate::{x86::DR7, Backend, Condition, DebugRegisters, HWBPSlot, Index, Size};
+
+/// A hardware breakpoint as described by the debug registers of a thread.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct SlotRegisters {
+    /// Whether it is locally enabled.
+    pub is_enabled: bool,
+    pub address: u64,
+    pub condition: Condition,
+    pub size: Size,
+}
+
+impl SlotRegisters {
+    fn from_registers(registers: &DebugRegisters, index: Index) -> Self {
+        let dr7 = DR7::from_bits(registers.dr7);
+        let slot = HWBPSlot::from_dr7(registers.address[index as usize], &dr7, index);
+        Self {
+            is_enabled: slot.is_enabled,
+            address: slot.address,
+            condition: slot.condition,
+            size: slot.size,
+        }
+    }
+}
+
+/// A hardware breakpoint whose debug registers differ from what was applied.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct SlotMismatch {
+    pub index: Index,
+    /// The breakpoint as applied.
+    pub expected: SlotRegisters,
+    /// The breakpoint as read back.
+    pub actual: SlotRegisters,
+}
+
+/// The debug registers of a thread drifting away from what was last applied to it,
+/// see `Context::audit`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Drift {
+    pub thread_id: u32,
+    pub mismatches: Vec<SlotMismatch>,
+}
+
+/// The debug registers last applied to a thread,
+/// and which of the breakpoints in them are ours.
+#[derive(Copy, Clone, Debug)]
+pub(crate) struct Applied {
+    pub(crate) registers: DebugRegisters,
+    pub(crate) is_ours: [bool; 4],
+}
+
+impl Applied {
+    /// Compares the breakpoints that are ours to the debug registers read back.
+    ///
+    /// Disabled breakpoints only differ if they got enabled,
+    /// whatever is left in their other bits.
+    pub(crate) fn diff(&self, actual: &DebugRegisters) -> Vec<SlotMismatch> {
+        Index::ALL
+            .into_iter()
+            .filter(|index| self.is_ours[*index as usize])
+            .filter_map(|index| {
+                let expected = SlotRegisters::from_registers(&self.registers, index);
+                let actual = SlotRegisters::from_registers(actual, index);
+                let is_same = match expected.is_enabled {
+                    true => expected == actual,
+                    false => !actual.is_enabled,
+                };
+                (!is_same).then_some(SlotMismatch {
+                    index,
+                    expected,
+                    actual,
+                })
+            })
+            .collect()
+    }
+}
+
+/// Reads back the debug registers of every thread a context was applied to,
+/// and compares them to what was last applied.
+///
+/// Threads whose registers cannot be read anymore, because they exited,
+/// are forgotten.
+pub(crate) fn audit<B: Backend>(backend: &B) -> Vec<Drift> {
+    let callbacks = backend.callbacks();
+    let mut drifts = Vec::new();
+    for (thread_id, applied) in callbacks.get_applied() {
+        let Ok(actual) = backend.get_debug_registers(thread_id) else {
+            callbacks.forget_applied(thread_id);
+            continue;
+        };
+
+        let mismatches = applied.diff(&actual);
+        if !mismatches.is_empty() {
+            drifts.push(Drift {
+                thread_id,
+                mismatches,
+            });
+        }
+    }
+
+    drifts
+}
+
+/// Audits the debug registers in the background, see `Context::audit_in_background`.
+///
+/// Dropping it stops the audits.
+pub struct Auditor {
+    stop: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl fmt::Debug for Auditor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Auditor").finish_non_exhaustive()
+    }
+}
+
+impl Auditor {
+    pub(crate) fn spawn<B: Backend + Send + 'static>(
+        backend: B,
+        interval: Duration,
+        callback: impl Fn(&Drift) + Send + 'static,
+    ) -> Self {
+        let (stop, stopped) = mpsc::channel();
+        // Stopping disconnects the channel, which ends the wait right away.
+        let thread = std::thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                audit(&backend).iter().for_each(&callback);
+            }
+        });
+
+        Self {
+            stop: Some(stop),
+            thread: Some(thread),
+        }
+    }
+
+    /// Stops the audits, waiting for the one in progress.
+    pub fn stop(mut self) {
+        self.join();
+    }
+
+    fn join(&mut self) {
+        drop(self.stop.take());
+        if let Some(thread) = self.thread.take() {
+            _ = thread.join();
+        }
+    }
+}
+
+impl Drop for Auditor {
+    fn drop(&mut self) {
+        self.join();
+    }
+}
@@ -0,0 +1,437 @@
+use crate::{x86::DR7, CallbackRegistry, ContextError, Index, PageWatcher, SoftwareBreakpoints};
+
+#[cfg(windows)]
+mod windows;
+#[cfg(windows)]
+pub(crate) use self::windows::exception_handler;
+#[cfg(windows)]
+pub use self::windows::WindowsBackend;
+
+#[cfg(target_os = "linux")]
+mod perf;
+#[cfg(target_os = "linux")]
+pub(crate) use self::perf::signal_dispatch;
+
+#[cfg(target_os = "linux")]
+mod ptrace;
+#[cfg(target_os = "linux")]
+pub use self::perf::PerfBackend;
+#[cfg(target_os = "linux")]
+pub use self::ptrace::{Session, SessionEvent};
+
+/// The backend for the platform the crate is compiled for.
+#[cfg(windows)]
+pub type NativeBackend = WindowsBackend;
+
+/// The backend for the platform the crate is compiled for.
+#[cfg(target_os = "linux")]
+pub type NativeBackend = PerfBackend;
+
+pub type Result<T> = std::result::Result<T, ContextError>;
+
+/// Reads memory of a process with `process_vm_readv`,
+/// which works for the current process as well as for a tracee.
+#[cfg(target_os = "linux")]
+fn read_process_memory(pid: u32, address: u64, buffer: &mut [u8]) -> std::io::Result<()> {
+    let local = libc::iovec {
+        iov_base: buffer.as_mut_ptr().cast(),
+        iov_len: buffer.len(),
+    };
+    let remote = libc::iovec {
+        iov_base: address as *mut libc::c_void,
+        iov_len: buffer.len(),
+    };
+
+    let read = unsafe { libc::process_vm_readv(pid as libc::pid_t, &local, 1, &remote, 1, 0) };
+    match read {
+        -1 => Err(std::io::Error::last_os_error()),
+        read if read as usize != buffer.len() => Err(std::io::ErrorKind::UnexpectedEof.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Debug registers of a thread, but only the ones describing breakpoints.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct DebugRegisters {
+    /// DR0-DR3.
+    pub address: [u64; 4],
+    pub dr7: u64,
+}
+
+impl DebugRegisters {
+    /// Gets whether any breakpoint is locally enabled.
+    pub fn has_enabled(&self) -> bool {
+        let dr7 = DR7::from_bits(self.dr7);
+        dr7.bp_local_0() || dr7.bp_local_1() || dr7.bp_local_2() || dr7.bp_local_3()
+    }
+
+    /// Gets whether a breakpoint is enabled, locally or globally.
+    pub fn is_enabled(&self, index: Index) -> bool {
+        self.dr7 & 0b11 << (index as u64 * 2) != 0
+    }
+
+    /// Copies a breakpoint from other registers: its address, enable bits, condition and size.
+    pub fn copy_slot(&mut self, other: &DebugRegisters, index: Index) {
+        let i = index as usize;
+        let mask = 0b11 << (i * 2) | 0b1111 << (16 + i * 4);
+        self.address[i] = other.address[i];
+        self.dr7 = self.dr7 & !mask | other.dr7 & mask;
+    }
+}
+
+/// Access to the debug registers of threads.
+///
+/// `Context` goes through a backend for everything platform-specific,
+/// so implementing this trait is all it takes to support another platform,
+/// a remote target or a fake one for testing.
+pub trait Backend {
+    /// The registers passed to the callbacks when a breakpoint is hit.
+    type Registers;
+
+    /// Gets the id of the calling thread.
+    fn current_thread_id(&self) -> u32;
+
+    /// Reads the debug registers of a thread.
+    fn get_debug_registers(&self, thread_id: u32) -> Result<DebugRegisters>;
+
+    /// Writes the debug registers of a thread.
+    fn set_debug_registers(&self, thread_id: u32, registers: &DebugRegisters) -> Result<()>;
+
+    /// Gets the ids of all threads of the target.
+    fn enumerate_threads(&self) -> Result<Vec<u32>>;
+
+    /// Sets the debug registers that threads created from now on start with,
+    /// together with the callbacks inherited through `CallbackRegistry::inherit`.
+    ///
+    /// Registers without enabled breakpoints stop the inheritance.
+    /// Backends that cannot follow thread creation keep the default,
+    /// which fails.
+    fn set_inherited_debug_registers(&self, registers: &DebugRegisters) -> Result<()> {
+        _ = registers;
+        Err(ContextError::BackendError(Box::new(std::io::Error::from(
+            std::io::ErrorKind::Unsupported,
+        ))))
+    }
+
+    /// Suspends a thread other than the calling one, until `resume_thread`,
+    /// so that `Context` can swap its callbacks and debug registers at once.
+    ///
+    /// The thread must not be suspended inside the handler,
+    /// nor while holding a lock that `set_debug_registers` takes.
+    /// Since it may be suspended holding the allocator's lock,
+    /// setting its debug registers must not allocate until it is resumed.
+    /// Backends whose threads are stopped anyway keep the default, which does nothing.
+    fn suspend_thread(&self, thread_id: u32) -> Result<()> {
+        _ = thread_id;
+        Ok(())
+    }
+
+    /// Resumes a thread suspended by `suspend_thread`.
+    fn resume_thread(&self, thread_id: u32) -> Result<()> {
+        _ = thread_id;
+        Ok(())
+    }
+
+    /// Installs the handler that dispatches breakpoint hits to `callbacks`.
+    fn install_handler(&self);
+
+    /// Uninstalls the handler installed by `install_handler`.
+    fn uninstall_handler(&self);
+
+    /// Gets the callbacks of the threads of the target.
+    fn callbacks(&self) -> &CallbackRegistry<Self::Registers>;
+
+    /// Gets the watcher arming page watches in the target, see `WatchStrategy`.
+    ///
+    /// Backends that cannot protect pages keep the default, which has none.
+    fn page_watcher(&self) -> Option<&PageWatcher<Self::Registers>> {
+        None
+    }
+
+    /// Gets the software breakpoints patched into the code of the target,
+    /// see `WatchStrategy`.
+    ///
+    /// Backends that cannot patch code keep the default, which has none.
+    fn software_breakpoints(&self) -> Option<&SoftwareBreakpoints<Self::Registers>> {
+        None
+    }
+}
+
+impl<B: Backend + ?Sized> Backend for &B {
+    type Registers = B::Registers;
+
+    fn current_thread_id(&self) -> u32 {
+        (**self).current_thread_id()
+    }
+
+    fn get_debug_registers(&self, thread_id: u32) -> Result<DebugRegisters> {
+        (**self).get_debug_registers(thread_id)
+    }
+
+    fn set_debug_registers(&self, thread_id: u32, registers: &DebugRegisters) -> Result<()> {
+        (**self).set_debug_registers(thread_id, registers)
+    }
+
+    fn enumerate_threads(&self) -> Result<Vec<u32>> {
+        (**self).enumerate_threads()
+    }
+
+    fn set_inherited_debug_registers(&self, registers: &DebugRegisters) -> Result<()> {
+        (**self).set_inherited_debug_registers(registers)
+    }
+
+    fn suspend_thread(&self, thread_id: u32) -> Result<()> {
+        (**self).suspend_thread(thread_id)
+    }
+
+    fn resume_thread(&self, thread_id: u32) -> Result<()> {
+        (**self).resume_thread(thread_id)
+    }
+
+    fn install_handler(&self) {
+        (**self).install_handler()
+    }
+
+    fn uninstall_handler(&self) {
+        (**self).uninstall_handler()
+    }
+
+    fn callbacks(&self) -> &CallbackRegistry<Self::Registers> {
+        (**self).callbacks()
+    }
+
+    fn page_watcher(&self) -> Option<&PageWatcher<Self::Registers>> {
+        (**self).page_watcher()
+    }
+
+    fn software_breakpoints(&self) -> Option<&SoftwareBreakpoints<Self::Registers>> {
+        (**self).software_breakpoints()
+    }
+}
+
+/// A backend for tests, whose threads are only debug registers in memory.
+///
+/// The first thread is the current one, and the others are never really suspended.
+#[cfg(test)]
+pub(crate) struct FakeBackend {
+    threads: std::sync::Mutex<std::collections::BTreeMap<u32, DebugRegisters>>,
+    /// A thread whose debug registers cannot be written.
+    failing: std::sync::Mutex<Option<u32>>,
+    callbacks: CallbackRegistry<()>,
+    page_watcher: Option<PageWatcher<()>>,
+    software_breakpoints: Option<SoftwareBreakpoints<()>>,
+}
+
+#[cfg(test)]
+impl FakeBackend {
+    pub(crate) fn new(threads: &[u32]) -> Self {
+        Self {
+            threads: std::sync::Mutex::new(
+                threads
+                    .iter()
+                    .map(|x| (*x, DebugRegisters::default()))
+                    .collect(),
+            ),
+            failing: std::sync::Mutex::new(None),
+            callbacks: CallbackRegistry::new(),
+            page_watcher: None,
+            software_breakpoints: None,
+        }
+    }
+
+    /// Makes writing the debug registers of a thread fail.
+    pub(crate) fn with_failing(self, thread_id: u32) -> Self {
+        self.set_failing(Some(thread_id));
+        self
+    }
+
+    /// Makes writing the debug registers of a thread fail, or none.
+    pub(crate) fn set_failing(&self, thread_id: Option<u32>) {
+        *self.failing.lock().unwrap() = thread_id;
+    }
+
+    /// Starts a thread.
+    pub(crate) fn add_thread(&self, thread_id: u32) {
+        self.threads
+            .lock()
+            .unwrap()
+            .insert(thread_id, DebugRegisters::default());
+    }
+
+    /// Watches pages, changing their protection with a fake.
+    pub(crate) fn with_page_watcher(
+        mut self,
+        set_protection: crate::paging::SetProtection,
+    ) -> Self {
+        self.page_watcher = Some(PageWatcher::new(set_protection));
+        self
+    }
+
+    /// Sets software breakpoints, patching code with a fake.
+    pub(crate) fn with_software_breakpoints(
+        mut self,
+        write_code: crate::software::WriteCode,
+    ) -> Self {
+        self.software_breakpoints = Some(SoftwareBreakpoints::new(write_code));
+        self
+    }
+}
+
+#[cfg(test)]
+impl Backend for FakeBackend {
+    type Registers = ();
+
+    fn current_thread_id(&self) -> u32 {
+        *self.threads.lock().unwrap().keys().next().unwrap()
+    }
+
+    fn get_debug_registers(&self, thread_id: u32) -> Result<DebugRegisters> {
+        self.threads
+            .lock()
+            .unwrap()
+            .get(&thread_id)
+            .copied()
+            .ok_or_else(|| {
+                ContextError::BackendError(Box::new(std::io::Error::from(
+                    std::io::ErrorKind::NotFound,
+                )))
+            })
+    }
+
+    fn set_debug_registers(&self, thread_id: u32, registers: &DebugRegisters) -> Result<()> {
+        if *self.failing.lock().unwrap() == Some(thread_id) {
+            return Err(ContextError::BackendError(Box::new(std::io::Error::from(
+                std::io::ErrorKind::PermissionDenied,
+            ))));
+        }
+
+        self.threads.lock().unwrap().insert(thread_id, *registers);
+        Ok(())
+    }
+
+    fn enumerate_threads(&self) -> Result<Vec<u32>> {
+        Ok(self.threads.lock().unwrap().keys().copied().collect())
+    }
+
+    fn install_handler(&self) {}
+
+    fn uninstall_handler(&self) {}
+
+    fn callbacks(&self) -> &CallbackRegistry<Self::Registers> {
+        &self.callbacks
+    }
+
+    fn page_watcher(&self) -> Option<&PageWatcher<Self::Registers>> {
+        self.page_watcher.as_ref()
+    }
+
+    fn software_breakpoints(&self) -> Option<&SoftwareBreakpoints<Self::Registers>> {
+        self.software_breakpoints.as_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Context, Ownership, Size};
+
+    /// Sets an enabled hardware breakpoint in a context.
+    fn watch(context: &mut Context<&FakeBackend>, address: u64) -> Index {
+        context
+            .unused()
+            .unwrap()
+            .watch_memory_write(address as *const u8, Size::FourBytes, |_| {})
+            .with_enabled(true)
+            .build_and_set()
+            .unwrap()
+            .get_index()
+    }
+
+    #[test]
+    fn apply_for_all_threads() {
+        let backend = FakeBackend::new(&[1, 2, 3]);
+        let mut context = Context::current_with(&backend).unwrap();
+        let index = watch(&mut context, 0x1000);
+        context.apply_for_all_threads().unwrap();
+
+        for thread_id in [1, 2, 3] {
+            let registers = backend.get_debug_registers(thread_id).unwrap();
+            assert!(registers.is_enabled(index));
+            assert_eq!(registers.address[index as usize], 0x1000);
+            assert!(backend.callbacks().get(thread_id, index).is_some());
+        }
+    }
+
+    #[test]
+    fn apply_for_all_threads_rolls_back() {
+        let backend = FakeBackend::new(&[1, 2, 3]);
+        let mut previous = Context::current_with(&backend).unwrap();
+        let index = watch(&mut previous, 0x1000);
+        previous.apply_for_thread(2).unwrap();
+
+        let backend = backend.with_failing(3);
+        let mut context = Context::current_with(&backend).unwrap();
+        watch(&mut context, 0x2000);
+        let error = context.apply_for_all_threads().unwrap_err();
+        assert!(matches!(
+            error,
+            ContextError::ThreadFailed { thread_id: 3, .. }
+        ));
+
+        // The current thread goes back to nothing, the second one to the previous context.
+        assert_eq!(
+            backend.get_debug_registers(1).unwrap(),
+            DebugRegisters::default()
+        );
+        assert!(Index::ALL
+            .into_iter()
+            .all(|x| backend.callbacks().get(1, x).is_none()));
+
+        let registers = backend.get_debug_registers(2).unwrap();
+        assert_eq!(registers.address[index as usize], 0x1000);
+        assert!(backend.callbacks().get(2, index).is_some());
+        assert!(Index::ALL
+            .into_iter()
+            .filter(|x| *x != index)
+            .all(|x| !registers.is_enabled(x) && backend.callbacks().get(2, x).is_none()));
+    }
+
+    #[test]
+    fn foreign_slots() {
+        let backend = FakeBackend::new(&[1, 2]);
+        let mut foreign = DebugRegisters::default();
+        let other = DebugRegisters {
+            address: [0x3000, 0, 0, 0],
+            dr7: 0b1,
+        };
+        foreign.copy_slot(&other, Index::First);
+        backend.set_debug_registers(2, &foreign).unwrap();
+
+        let mut context = Context::for_thread_with(&backend, 2).unwrap();
+        assert_eq!(context.get_ownership(Index::First), Ownership::Foreign);
+        assert_eq!(context.get_ownership(Index::Second), Ownership::Free);
+        assert!(!context.get(Index::First).is_enabled());
+
+        // The foreign breakpoint is never handed out, and applying leaves it as it is.
+        let index = watch(&mut context, 0x1000);
+        assert_eq!(index, Index::Second);
+        context.apply_for_thread(2).unwrap();
+        let registers = backend.get_debug_registers(2).unwrap();
+        assert!(registers.is_enabled(Index::First));
+        assert_eq!(registers.address[0], 0x3000);
+        assert!(registers.is_enabled(Index::Second));
+        assert!(backend.callbacks().get(2, Index::First).is_none());
+
+        // Enabling a breakpoint in its place fails.
+        let mut context = Context::current_with(&backend).unwrap();
+        assert_eq!(watch(&mut context, 0x2000), Index::First);
+        let error = context.apply_for_thread(2).unwrap_err();
+        assert!(matches!(
+            error,
+            ContextError::ForeignSlot {
+                thread_id: 2,
+                index: Index::First,
+            }
+        ));
+    }
+}
@@ -0,0 +1,893 @@
+use std::{
+    cell::Cell,
+    collections::HashMap,
+    io,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    sync::{
+        atomic::{AtomicU32, AtomicU64, AtomicU8, Ordering},
+        RwLock, RwLockReadGuard, TryLockError,
+    },
+    time::{Duration, Instant},
+};
+
+use bitfield_struct::bitfield;
+use lazy_static::lazy_static;
+use libc::{c_int, c_void, siginfo_t, SIGSEGV, SIGTRAP, SIG_DFL, SIG_IGN, TRAP_PERF, TRAP_TRACE};
+
+use crate::{
+    linux::CONTEXT,
+    paging::{PageWatcher, PAGE_SIZE},
+    software::SoftwareBreakpoints,
+    x86::DR7,
+    CallbackRegistry, Condition, ContextError, Dispatch, HWBPSlot, HitAction, Index,
+};
+
+use super::{read_process_memory, Backend, DebugRegisters, Result};
+
+const PERF_TYPE_BREAKPOINT: u32 = 5;
+const PERF_FLAG_FD_CLOEXEC: libc::c_ulong = 1 << 3;
+
+const HW_BREAKPOINT_W: u32 = 2;
+const HW_BREAKPOINT_RW: u32 = 3;
+const HW_BREAKPOINT_X: u32 = 4;
+
+/// The value of DR6 after a reset, with no detection bits set.
+const DR6_INIT: u64 = 0xFFFF_0FF0;
+/// BS, set in DR6 by single-step traps.
+const DR6_SINGLE_STEP: u64 = 1 << 14;
+
+const PERF_EVENT_IOC_DISABLE: libc::c_ulong = 0x2401;
+
+/// The `si_code` of faults on mapped pages without the permissions for the access.
+const SEGV_ACCERR: c_int = 2;
+
+/// The `si_code` of signals sent by the kernel, which is what `int3` traps get.
+const SI_KERNEL: c_int = 0x80;
+
+/// TF, set in EFLAGS to single-step.
+const TRAP_FLAG: i64 = 1 << 8;
+
+/// Where the thread being suspended is at, see `suspend_thread`.
+const SUSPEND_IDLE: u8 = 0;
+const SUSPEND_REQUESTED: u8 = 1;
+const SUSPEND_DONE: u8 = 2;
+const SUSPEND_RESUMED: u8 = 3;
+
+/// How long to wait for a thread to suspend, which it defers while in the handler.
+const SUSPEND_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How many threads the handler can register without the maps growing,
+/// as they inherit events, see `inherit_from_handler`.
+const SPARE_THREADS: usize = 64;
+
+/// See: https://man7.org/linux/man-pages/man2/perf_event_open.2.html
+#[bitfield(u64)]
+struct PerfEventFlags {
+    disabled: bool,
+    inherit: bool,
+    pinned: bool,
+    exclusive: bool,
+    exclude_user: bool,
+    exclude_kernel: bool,
+    exclude_hv: bool,
+    exclude_idle: bool,
+    mmap: bool,
+    comm: bool,
+    freq: bool,
+    inherit_stat: bool,
+    enable_on_exec: bool,
+    task: bool,
+    watermark: bool,
+    #[bits(2)]
+    precise_ip: u8,
+    mmap_data: bool,
+    sample_id_all: bool,
+    exclude_host: bool,
+    exclude_guest: bool,
+    exclude_callchain_kernel: bool,
+    exclude_callchain_user: bool,
+    mmap2: bool,
+    comm_exec: bool,
+    use_clockid: bool,
+    context_switch: bool,
+    write_backward: bool,
+    namespaces: bool,
+    ksymbol: bool,
+    bpf_event: bool,
+    aux_output: bool,
+    cgroup: bool,
+    text_poke: bool,
+    build_id: bool,
+    inherit_thread: bool,
+    remove_on_exec: bool,
+    sigtrap: bool,
+    #[bits(26)]
+    __: u32,
+}
+
+/// `struct perf_event_attr` up to `PERF_ATTR_SIZE_VER7`.
+#[repr(C)]
+#[derive(Default)]
+#[allow(dead_code)] // Only read by the kernel.
+struct PerfEventAttr {
+    kind: u32,
+    size: u32,
+    config: u64,
+    sample_period: u64,
+    sample_type: u64,
+    read_format: u64,
+    flags: PerfEventFlags,
+    wakeup_events: u32,
+    bp_type: u32,
+    bp_addr: u64,
+    bp_len: u64,
+    branch_sample_type: u64,
+    sample_regs_user: u64,
+    sample_stack_user: u32,
+    clockid: i32,
+    sample_regs_intr: u64,
+    aux_watermark: u32,
+    sample_max_stack: u16,
+    reserved_2: u16,
+    aux_sample_size: u32,
+    reserved_3: u32,
+    sig_data: u64,
+}
+
+/// The layout of `siginfo_t` for `TRAP_PERF` signals.
+#[repr(C)]
+struct PerfSigInfo {
+    si_signo: c_int,
+    si_errno: c_int,
+    si_code: c_int,
+    si_addr: *mut c_void,
+    si_perf_data: u64,
+    si_perf_type: u32,
+    si_perf_flags: u32,
+}
+
+/// Breakpoints armed by this crate for a single thread.
+#[derive(Default)]
+struct ThreadEvents {
+    registers: DebugRegisters,
+    events: [Option<OwnedFd>; 4],
+    /// Whether the events are inherited by threads the thread creates.
+    is_inherited: [bool; 4],
+    /// How many times the events have been hit, as of the last dispatch.
+    hits: [AtomicU64; 4],
+}
+
+impl ThreadEvents {
+    /// Gets the slots whose events were hit since the last call.
+    ///
+    /// The kernel only signals one event per trap, so this is how the others are found.
+    /// Inherited events count the hits of other threads too, so they are left out.
+    fn take_hits(&self) -> u64 {
+        let mut detected = 0;
+        for (i, event) in self.events.iter().enumerate() {
+            let Some(event) = event.as_ref().filter(|_| !self.is_inherited[i]) else {
+                continue;
+            };
+
+            let mut count = 0u64;
+            let read =
+                unsafe { libc::read(event.as_raw_fd(), &mut count as *mut u64 as *mut c_void, 8) };
+            if read == 8 && self.hits[i].swap(count, Ordering::Relaxed) != count {
+                detected |= 1 << i;
+            }
+        }
+        detected
+    }
+}
+
+lazy_static! {
+    static ref THREADS: RwLock<HashMap<u32, ThreadEvents>> = RwLock::new(HashMap::new());
+    static ref CALLBACKS: CallbackRegistry<CONTEXT> =
+        CallbackRegistry::with_memory_reader(|address, buffer| {
+            read_process_memory(std::process::id(), address, buffer)
+        });
+    static ref PAGES: PageWatcher<CONTEXT> = PageWatcher::new(set_protection);
+    static ref SOFTWARE: SoftwareBreakpoints<CONTEXT> = SoftwareBreakpoints::new(write_code);
+}
+
+/// The handlers replaced by ours, for every signal we handle.
+static PREVIOUS_HANDLERS: RwLock<Vec<(c_int, libc::sigaction)>> = RwLock::new(Vec::new());
+
+/// The thread being suspended, if any, as threads are suspended one at a time.
+static SUSPENDED: AtomicU32 = AtomicU32::new(0);
+static SUSPEND_STATE: AtomicU8 = AtomicU8::new(SUSPEND_IDLE);
+
+static INHERITED: RwLock<DebugRegisters> = RwLock::new(DebugRegisters {
+    address: [0; 4],
+    dr7: 0,
+});
+
+/// The threads that existed when the inherited debug registers were set,
+/// which did not inherit them.
+static PREEXISTING: RwLock<Vec<u32>> = RwLock::new(Vec::new());
+
+/// The backend for the threads of the current process on Linux.
+///
+/// Breakpoints are armed as `perf_event_open` hardware breakpoint events,
+/// which deliver a synchronous `SIGTRAP` to the thread that hit them.
+/// The debug registers themselves cannot be read from user mode,
+/// so only the breakpoints armed through this backend are reported.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct PerfBackend;
+
+impl Backend for PerfBackend {
+    type Registers = CONTEXT;
+
+    fn current_thread_id(&self) -> u32 {
+        current_thread_id()
+    }
+
+    fn get_debug_registers(&self, thread_id: u32) -> Result<DebugRegisters> {
+        unsuspendable(|| get_debug_registers(thread_id))
+    }
+
+    fn set_debug_registers(&self, thread_id: u32, registers: &DebugRegisters) -> Result<()> {
+        unsuspendable(|| set_debug_registers(thread_id, registers))
+    }
+
+    fn enumerate_threads(&self) -> Result<Vec<u32>> {
+        enumerate_threads().map_err(ContextError::EnumeratingThreadsFailed)
+    }
+
+    /// New threads inherit the events of the thread that creates them,
+    /// so only the slots armed afterwards that match `registers` are inherited.
+    fn set_inherited_debug_registers(&self, registers: &DebugRegisters) -> Result<()> {
+        let threads = enumerate_threads().map_err(ContextError::EnumeratingThreadsFailed)?;
+        unsuspendable(|| {
+            *INHERITED
+                .write()
+                .expect("Failed to acquire write lock for perf events") = *registers;
+            *PREEXISTING
+                .write()
+                .expect("Failed to acquire write lock for perf events") = threads;
+            reserve_threads();
+        });
+        Ok(())
+    }
+
+    /// Threads are suspended by a signal, see `suspend_signal`,
+    /// whose handler waits until they are resumed.
+    /// It is deferred while they are in the `SIGTRAP` or `SIGSEGV` handler,
+    /// or holding the locks of the backend.
+    /// If the handlers are not installed, threads are not suspended.
+    fn suspend_thread(&self, thread_id: u32) -> Result<()> {
+        suspend_thread(thread_id)
+    }
+
+    fn resume_thread(&self, thread_id: u32) -> Result<()> {
+        resume_thread(thread_id);
+        Ok(())
+    }
+
+    fn install_handler(&self) {
+        install_handler();
+    }
+
+    fn uninstall_handler(&self) {
+        uninstall_handler();
+    }
+
+    fn callbacks(&self) -> &CallbackRegistry<CONTEXT> {
+        &CALLBACKS
+    }
+
+    fn page_watcher(&self) -> Option<&PageWatcher<CONTEXT>> {
+        Some(&PAGES)
+    }
+
+    fn software_breakpoints(&self) -> Option<&SoftwareBreakpoints<CONTEXT>> {
+        Some(&SOFTWARE)
+    }
+}
+
+fn current_thread_id() -> u32 {
+    unsafe { libc::gettid() as u32 }
+}
+
+/// Checks that a thread of the current process exists, without allocating,
+/// by sending it no signal.
+fn ensure_thread_exists(thread_id: u32) -> Result<()> {
+    let sent = unsafe { libc::syscall(libc::SYS_tgkill, libc::getpid(), thread_id as c_int, 0) };
+    match sent {
+        0 => Ok(()),
+        _ => Err(ContextError::OpenThreadFailed(io::Error::last_os_error())),
+    }
+}
+
+/// Opens a perf event that delivers a synchronous `SIGTRAP` to the thread
+/// whenever the breakpoint is hit.
+///
+/// Inherited events are copied by the kernel into the threads the thread creates.
+fn open_event(
+    thread_id: u32,
+    index: Index,
+    slot: &HWBPSlot,
+    is_inherited: bool,
+) -> io::Result<OwnedFd> {
+    let (bp_type, bp_len) = match slot.condition {
+        Condition::Execute => (HW_BREAKPOINT_X, std::mem::size_of::<libc::c_long>()),
+        Condition::Write => (HW_BREAKPOINT_W, slot.size.into_bytes()),
+        Condition::ReadWrite => (HW_BREAKPOINT_RW, slot.size.into_bytes()),
+        Condition::IoReadWrite => return Err(io::ErrorKind::Unsupported.into()),
+    };
+
+    let attr = PerfEventAttr {
+        kind: PERF_TYPE_BREAKPOINT,
+        size: std::mem::size_of::<PerfEventAttr>() as u32,
+        sample_period: 1,
+        flags: PerfEventFlags::new()
+            .with_inherit(is_inherited)
+            .with_inherit_thread(is_inherited)
+            .with_exclude_kernel(true)
+            .with_exclude_hv(true)
+            .with_remove_on_exec(true)
+            .with_sigtrap(true),
+        bp_type,
+        bp_addr: slot.address,
+        bp_len: bp_len as u64,
+        sig_data: index as u64,
+        ..Default::default()
+    };
+
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_perf_event_open,
+            &attr as *const PerfEventAttr,
+            thread_id as libc::pid_t,
+            -1 as c_int,
+            -1 as c_int,
+            PERF_FLAG_FD_CLOEXEC,
+        )
+    };
+
+    if fd < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(unsafe { OwnedFd::from_raw_fd(fd as c_int) })
+    }
+}
+
+fn get_debug_registers(thread_id: u32) -> Result<DebugRegisters> {
+    ensure_thread_exists(thread_id)?;
+    register_inherited(thread_id);
+
+    let threads = THREADS
+        .read()
+        .expect("Failed to acquire read lock for perf events");
+    Ok(threads
+        .get(&thread_id)
+        .map(|thread| thread.registers)
+        .unwrap_or_default())
+}
+
+fn set_debug_registers(thread_id: u32, registers: &DebugRegisters) -> Result<()> {
+    ensure_thread_exists(thread_id)?;
+    register_inherited(thread_id);
+
+    let mut threads = THREADS
+        .write()
+        .expect("Failed to acquire write lock for perf events");
+    let thread = threads.entry(thread_id).or_default();
+
+    let new_dr7 = DR7::from_bits(registers.dr7);
+    let mut dr7 = DR7::from_bits(thread.registers.dr7);
+
+    let inherited = *INHERITED
+        .read()
+        .expect("Failed to acquire read lock for perf events");
+    let inherited_dr7 = DR7::from_bits(inherited.dr7);
+
+    for index in [Index::First, Index::Second, Index::Third, Index::Fourth] {
+        let i = index as usize;
+        let old = HWBPSlot::from_dr7(thread.registers.address[i], &dr7, index);
+        let new = HWBPSlot::from_dr7(registers.address[i], &new_dr7, index);
+        let is_inherited = new.is_enabled
+            && new == HWBPSlot::from_dr7(inherited.address[i], &inherited_dr7, index);
+        if old == new && thread.is_inherited[i] == is_inherited {
+            continue;
+        }
+
+        thread.events[i] = None;
+        thread.is_inherited[i] = false;
+        thread.hits[i] = AtomicU64::new(0);
+        let slot = if new.is_enabled {
+            match open_event(thread_id, index, &new, is_inherited) {
+                Ok(fd) => {
+                    thread.events[i] = Some(fd);
+                    thread.is_inherited[i] = is_inherited;
+                    new
+                }
+                Err(e) => {
+                    let old = HWBPSlot {
+                        is_enabled: false,
+                        ..old
+                    };
+                    old.apply_to_dr7(&index, &mut thread.registers.address[i], &mut dr7);
+                    thread.registers.dr7 = dr7.into_bits();
+                    return Err(ContextError::SetContextFailed(e));
+                }
+            }
+        } else {
+            new
+        };
+
+        slot.apply_to_dr7(&index, &mut thread.registers.address[i], &mut dr7);
+        thread.registers.dr7 = dr7.into_bits();
+    }
+
+    Ok(())
+}
+
+/// The signal suspending threads, a real-time one so that it is not taken already.
+fn suspend_signal() -> c_int {
+    libc::SIGRTMIN() + 4
+}
+
+thread_local! {
+    /// Whether the thread may hold the locks of the backend,
+    /// so that its `SIGTRAP` handler does not wait for itself.
+    static IS_LOCKING: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Runs `f` with the suspension of the calling thread deferred,
+/// for `f` takes locks that `set_debug_registers` takes too.
+fn unsuspendable<T>(f: impl FnOnce() -> T) -> T {
+    unsafe {
+        let mut set: libc::sigset_t = std::mem::zeroed();
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, suspend_signal());
+        let mut previous: libc::sigset_t = std::mem::zeroed();
+        libc::pthread_sigmask(libc::SIG_BLOCK, &set, &mut previous);
+        let is_locking = IS_LOCKING.replace(true);
+
+        let result = f();
+
+        IS_LOCKING.set(is_locking);
+        libc::pthread_sigmask(libc::SIG_SETMASK, &previous, std::ptr::null_mut());
+        result
+    }
+}
+
+/// Reads the events from the handler, waiting for other threads to release the lock
+/// without blocking, and giving up if the calling thread may hold it.
+fn read_threads() -> Option<RwLockReadGuard<'static, HashMap<u32, ThreadEvents>>> {
+    loop {
+        match THREADS.try_read() {
+            Ok(threads) => return Some(threads),
+            Err(TryLockError::WouldBlock) if !IS_LOCKING.get() => std::thread::yield_now(),
+            Err(_) => return None,
+        }
+    }
+}
+
+fn suspend_thread(thread_id: u32) -> Result<()> {
+    let signal = suspend_signal();
+    let is_installed = PREVIOUS_HANDLERS
+        .read()
+        .is_ok_and(|x| x.iter().any(|(x, _)| *x == signal));
+    if !is_installed || thread_id == current_thread_id() {
+        return Ok(());
+    }
+
+    // The thread may be suspended holding the allocator's lock,
+    // so its entry is made now for setting its debug registers not to allocate.
+    ensure_thread_exists(thread_id)?;
+    unsuspendable(|| {
+        register_inherited(thread_id);
+        THREADS
+            .write()
+            .expect("Failed to acquire write lock for perf events")
+            .entry(thread_id)
+            .or_default();
+    });
+
+    while SUSPENDED
+        .compare_exchange(0, thread_id, Ordering::AcqRel, Ordering::Acquire)
+        .is_err()
+    {
+        std::thread::yield_now();
+    }
+    SUSPEND_STATE.store(SUSPEND_REQUESTED, Ordering::Release);
+
+    let sent =
+        unsafe { libc::syscall(libc::SYS_tgkill, libc::getpid(), thread_id as c_int, signal) };
+    if sent != 0 {
+        let error = io::Error::last_os_error();
+        SUSPEND_STATE.store(SUSPEND_IDLE, Ordering::Release);
+        SUSPENDED.store(0, Ordering::Release);
+        return Err(ContextError::OpenThreadFailed(error));
+    }
+
+    let start = Instant::now();
+    while SUSPEND_STATE.load(Ordering::Acquire) != SUSPEND_DONE {
+        // The signal stays pending, but finds the request withdrawn.
+        let is_withdrawn = start.elapsed() > SUSPEND_TIMEOUT
+            && SUSPEND_STATE
+                .compare_exchange(
+                    SUSPEND_REQUESTED,
+                    SUSPEND_IDLE,
+                    Ordering::AcqRel,
+                    Ordering::Acquire,
+                )
+                .is_ok();
+        if is_withdrawn {
+            SUSPENDED.store(0, Ordering::Release);
+            return Err(ContextError::SuspendTimedOut { thread_id });
+        }
+
+        std::thread::yield_now();
+    }
+
+    Ok(())
+}
+
+fn resume_thread(thread_id: u32) {
+    if SUSPENDED.load(Ordering::Acquire) != thread_id {
+        return;
+    }
+
+    // Wait for it to leave the handler, so that the next suspension does not find it there.
+    SUSPEND_STATE.store(SUSPEND_RESUMED, Ordering::Release);
+    while SUSPEND_STATE.load(Ordering::Acquire) != SUSPEND_IDLE {
+        std::thread::yield_now();
+    }
+    SUSPENDED.store(0, Ordering::Release);
+}
+
+extern "C" fn suspend_handler(_: c_int) {
+    if SUSPENDED.load(Ordering::Acquire) != current_thread_id() {
+        return;
+    }
+    let is_requested = SUSPEND_STATE
+        .compare_exchange(
+            SUSPEND_REQUESTED,
+            SUSPEND_DONE,
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        )
+        .is_ok();
+    if !is_requested {
+        return;
+    }
+
+    while SUSPEND_STATE.load(Ordering::Acquire) != SUSPEND_RESUMED {
+        unsafe { libc::sched_yield() };
+    }
+    SUSPEND_STATE.store(SUSPEND_IDLE, Ordering::Release);
+}
+
+fn enumerate_threads() -> io::Result<Vec<u32>> {
+    let mut threads = Vec::new();
+    for entry in std::fs::read_dir("/proc/self/task")? {
+        if let Some(id) = entry?.file_name().to_str().and_then(|x| x.parse().ok()) {
+            threads.push(id);
+        }
+    }
+
+    Ok(threads)
+}
+
+fn install_handler() {
+    let mut lock = PREVIOUS_HANDLERS.write().unwrap();
+    if !lock.is_empty() {
+        return;
+    }
+
+    // Initializing them allocates, which the handlers must not do.
+    lazy_static::initialize(&PAGES);
+    lazy_static::initialize(&SOFTWARE);
+
+    // SIGSEGV is for page watches.
+    // Threads are not suspended while handling them, see `suspend_thread`.
+    // Callbacks run in the SIGTRAP handler, so it needs the thread's own stack,
+    // not the small alternate one that Rust gives threads for stack overflows,
+    // and so does the suspend handler, which breakpoints can interrupt too.
+    let handlers = [
+        (
+            SIGTRAP,
+            signal_handler as *const () as usize,
+            libc::SA_SIGINFO,
+        ),
+        (
+            SIGSEGV,
+            signal_handler as *const () as usize,
+            libc::SA_SIGINFO | libc::SA_ONSTACK,
+        ),
+        // Suspending a thread should not interrupt its system calls.
+        (
+            suspend_signal(),
+            suspend_handler as *const () as usize,
+            libc::SA_RESTART,
+        ),
+    ];
+    for (signal, handler, flags) in handlers {
+        unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = handler;
+            action.sa_flags = flags;
+            libc::sigemptyset(&mut action.sa_mask);
+            libc::sigaddset(&mut action.sa_mask, suspend_signal());
+
+            let mut previous: libc::sigaction = std::mem::zeroed();
+            if libc::sigaction(signal, &action, &mut previous) == 0 {
+                lock.push((signal, previous));
+            }
+        }
+    }
+}
+
+fn uninstall_handler() {
+    let mut lock = PREVIOUS_HANDLERS.write().unwrap();
+
+    for (signal, previous) in lock.drain(..) {
+        unsafe { libc::sigaction(signal, &previous, std::ptr::null_mut()) };
+    }
+}
+
+/// Protects a page of the current process for a page watch.
+///
+/// Watched pages are expected to be readable and writable,
+/// which is what they are restored to.
+fn set_protection(page: u64, condition: Option<Condition>) -> io::Result<()> {
+    let protection = match condition {
+        None => libc::PROT_READ | libc::PROT_WRITE,
+        Some(Condition::Write) => libc::PROT_READ,
+        Some(_) => libc::PROT_NONE,
+    };
+
+    match unsafe { libc::mprotect(page as *mut c_void, PAGE_SIZE as usize, protection) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// Writes a byte of code of the current process for a software breakpoint.
+///
+/// Code pages are expected to be readable and executable,
+/// which is what they are restored to.
+unsafe fn write_code(address: u64, byte: u8) -> io::Result<u8> {
+    let page = (address & !(PAGE_SIZE - 1)) as *mut c_void;
+    let protect = |protection| match libc::mprotect(page, PAGE_SIZE as usize, protection) {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    };
+
+    protect(libc::PROT_READ | libc::PROT_WRITE | libc::PROT_EXEC)?;
+    let original = std::ptr::replace(address as *mut u8, byte);
+    protect(libc::PROT_READ | libc::PROT_EXEC)?;
+    Ok(original)
+}
+
+unsafe extern "C" fn signal_handler(signal: c_int, info: *mut siginfo_t, context: *mut c_void) {
+    if let (Some(info), Some(cr)) = (info.as_ref(), (context as *mut CONTEXT).as_mut()) {
+        if signal_dispatch(info, cr) {
+            return;
+        }
+    }
+
+    let previous = PREVIOUS_HANDLERS.read().ok().and_then(|x| {
+        x.iter()
+            .find(|(x, _)| *x == signal)
+            .map(|(_, previous)| *previous)
+    });
+    match previous.map(|x| (x.sa_sigaction, x.sa_flags)) {
+        Some((SIG_IGN, _)) => {}
+        None | Some((SIG_DFL, _)) => {
+            libc::signal(signal, SIG_DFL);
+            libc::raise(signal);
+        }
+        Some((handler, flags)) if flags & libc::SA_SIGINFO != 0 => {
+            let handler: extern "C" fn(c_int, *mut siginfo_t, *mut c_void) =
+                std::mem::transmute(handler);
+            handler(signal, info, context);
+        }
+        Some((handler, _)) => {
+            let handler: extern "C" fn(c_int) = std::mem::transmute(handler);
+            handler(signal);
+        }
+    }
+}
+
+/// Makes room for the threads the handler registers, see `inherit_from_handler`.
+fn reserve_threads() {
+    THREADS
+        .write()
+        .expect("Failed to acquire write lock for perf events")
+        .reserve(SPARE_THREADS);
+    CALLBACKS.reserve(SPARE_THREADS);
+}
+
+/// Registers the inherited events of a thread created since they were set,
+/// unless it is registered already.
+///
+/// Until then the thread has no entry, so its inherited hardware breakpoints
+/// would look free while their events are still armed.
+fn register_inherited(thread_id: u32) {
+    let registers = *INHERITED
+        .read()
+        .expect("Failed to acquire read lock for perf events");
+    if !registers.has_enabled() {
+        return;
+    }
+
+    reserve_threads();
+    let is_preexisting = PREEXISTING
+        .read()
+        .expect("Failed to acquire read lock for perf events")
+        .contains(&thread_id);
+    let mut threads = THREADS
+        .write()
+        .expect("Failed to acquire write lock for perf events");
+    if !is_preexisting && !threads.contains_key(&thread_id) {
+        CALLBACKS.inherit(thread_id);
+        threads.insert(
+            thread_id,
+            ThreadEvents {
+                registers,
+                ..Default::default()
+            },
+        );
+    }
+}
+
+/// Registers a thread that got its events from the thread that created it,
+/// from the handler, when it hits one of them before anything else registered it.
+///
+/// It neither blocks nor allocates, so it gives up if a lock is taken
+/// or if there is no room left, see `reserve_threads`.
+fn inherit_from_handler(thread_id: u32) -> Option<DebugRegisters> {
+    let registers = *INHERITED.try_read().ok()?;
+    if !registers.has_enabled() {
+        return None;
+    }
+
+    let mut threads = THREADS.try_write().ok()?;
+    if let Some(thread) = threads.get(&thread_id) {
+        return Some(thread.registers);
+    }
+
+    if threads.len() == threads.capacity() || !CALLBACKS.try_inherit(thread_id) {
+        return None;
+    }
+    threads.insert(
+        thread_id,
+        ThreadEvents {
+            registers,
+            ..Default::default()
+        },
+    );
+
+    Some(registers)
+}
+
+pub unsafe fn signal_dispatch(info: &siginfo_t, cr: &mut CONTEXT) -> bool {
+    let tid = current_thread_id();
+    match (info.si_signo, info.si_code) {
+        (SIGSEGV, SEGV_ACCERR) => {
+            // Bit 1 of the page fault error code is set for writes.
+            let is_write = cr.uc_mcontext.gregs[libc::REG_ERR as usize] & 2 != 0;
+            if !PAGES.on_fault(tid, info.si_addr() as u64, is_write) {
+                return false;
+            }
+
+            // Single-step the access, which protects the page again.
+            cr.uc_mcontext.gregs[libc::REG_EFL as usize] |= TRAP_FLAG;
+            return true;
+        }
+        (SIGTRAP, SI_KERNEL) => {
+            // The trap is reported after the `int3`, rewind to the instruction it replaced.
+            let rip = cr.uc_mcontext.gregs[libc::REG_RIP as usize];
+            cr.uc_mcontext.gregs[libc::REG_RIP as usize] = rip - 1;
+            let Some(is_break) = SOFTWARE.on_hit(tid, rip as u64 - 1, cr, CALLBACKS.memory())
+            else {
+                cr.uc_mcontext.gregs[libc::REG_RIP as usize] = rip;
+                return false;
+            };
+
+            // Single-step the original instruction, which inserts the `int3` again.
+            cr.uc_mcontext.gregs[libc::REG_EFL as usize] |= TRAP_FLAG;
+            if is_break {
+                libc::raise(SIGTRAP);
+            }
+            return true;
+        }
+        (SIGTRAP, TRAP_TRACE) if PAGES.is_pending(tid) || SOFTWARE.is_pending(tid) => {
+            cr.uc_mcontext.gregs[libc::REG_EFL as usize] &= !TRAP_FLAG;
+            SOFTWARE.on_step(tid);
+            let ip = cr.uc_mcontext.gregs[libc::REG_RIP as usize] as u64;
+            if PAGES.on_step(tid, ip, cr, CALLBACKS.memory()) == Some(true) {
+                // Blocked while handling, so it is raised once the handler returns.
+                libc::raise(SIGTRAP);
+            }
+
+            // The step may also be one the callbacks asked for.
+            if !CALLBACKS.is_stepping(tid) {
+                return true;
+            }
+        }
+        (SIGTRAP, _) => {}
+        _ => return false,
+    }
+
+    // perf only tells which event fired, the rest of DR6 is as after a reset.
+    let dr6 = match info.si_code {
+        TRAP_PERF => {
+            let info = &*(info as *const siginfo_t as *const PerfSigInfo);
+            if info.si_perf_type != PERF_TYPE_BREAKPOINT || info.si_perf_data >= 4 {
+                return false;
+            }
+            DR6_INIT | 1 << info.si_perf_data
+        }
+        TRAP_TRACE if CALLBACKS.is_stepping(tid) => DR6_INIT | DR6_SINGLE_STEP,
+        _ => return false,
+    };
+
+    // Interrupted while holding the lock, the thread's breakpoints are unknown,
+    // though it can still be single-stepping.
+    let thread = match read_threads() {
+        Some(threads) => threads
+            .get(&tid)
+            .map(|thread| (thread.registers, thread.take_hits())),
+        None => Some((DebugRegisters::default(), 0)),
+    };
+    let Some((registers, hits)) = thread.or_else(|| Some((inherit_from_handler(tid)?, 0))) else {
+        return false;
+    };
+    let dr6 = dr6 | hits;
+
+    let ip = cr.uc_mcontext.gregs[libc::REG_RIP as usize] as u64;
+    let dispatch = CALLBACKS.dispatch(tid, dr6, &registers, ip, cr);
+    // Every event is ours, so only pass on what the callbacks ask to.
+    if dispatch.is_passed_on() {
+        return false;
+    }
+
+    disable(tid, &dispatch);
+    if dispatch.is_break() {
+        // Blocked while handling, so it is raised once the handler returns.
+        libc::raise(SIGTRAP);
+    }
+
+    let eflags = &mut cr.uc_mcontext.gregs[libc::REG_EFL as usize];
+    *eflags = dispatch.apply_to_flags(*eflags as u64) as i64;
+    true
+}
+
+/// Disables the breakpoints of the calling thread that the callbacks asked to,
+/// from its signal handler.
+///
+/// Events inherited by other threads stay open, as disabling them would disable those too,
+/// but their hits are ignored since the breakpoint is disabled in the registers.
+fn disable(thread_id: u32, dispatch: &Dispatch) {
+    let disabled = Index::ALL.map(|index| {
+        matches!(
+            dispatch.get_action(index),
+            Some(HitAction::Disable | HitAction::Remove)
+        )
+    });
+    if !disabled.contains(&true) {
+        return;
+    }
+
+    // The thread may have been interrupted while holding the lock.
+    let Ok(mut threads) = THREADS.try_write() else {
+        return;
+    };
+
+    if let Some(thread) = threads.get_mut(&thread_id) {
+        thread.registers.dr7 = dispatch.apply_to_dr7(thread.registers.dr7);
+        for index in Index::ALL.into_iter().filter(|x| disabled[*x as usize]) {
+            if let (Some(event), false) = (
+                &thread.events[index as usize],
+                thread.is_inherited[index as usize],
+            ) {
+                unsafe { libc::ioctl(event.as_raw_fd(), PERF_EVENT_IOC_DISABLE, 0) };
+            }
+        }
+    }
+}
@@ -0,0 +1,597 @@
+use std::{
+    cell::RefCell, collections::HashMap, fmt, io, mem::offset_of, os::unix::process::CommandExt,
+    process::Command,
+};
+
+use libc::{c_int, c_long, c_void, pid_t, user, user_regs_struct, SIGSTOP, SIGTRAP};
+
+use crate::{CallbackRegistry, ContextError};
+
+use super::{read_process_memory, Backend, DebugRegisters, Result};
+
+const DR6: usize = 6;
+const DR7: usize = 7;
+
+/// BS, set in DR6 by single-step traps.
+const DR6_SINGLE_STEP: u64 = 1 << 14;
+
+/// What happened to the tracee while waiting in `Session::wait`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum SessionEvent {
+    /// A hardware breakpoint was hit and its callback has been run.
+    ///
+    /// The thread stays stopped until the next `Session::wait`,
+    /// so its breakpoints can be changed in the meantime.
+    Breakpoint { thread_id: u32 },
+    /// A callback returned `HitAction::Break`, asking the owner of the session to look at the thread.
+    ///
+    /// The thread stays stopped until the next `Session::wait`, like for `Breakpoint`.
+    Break { thread_id: u32 },
+    /// The tracee exited with the given code.
+    Exited(i32),
+    /// The tracee was terminated by the given signal.
+    Signaled(i32),
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+struct ThreadState {
+    /// Whether the thread is in a ptrace-stop.
+    is_stopped: bool,
+    /// Whether the thread will report a stop that we caused,
+    /// because it was just created or we asked it to stop.
+    expects_stop: bool,
+    /// Whether the thread was created while traced and has not stopped yet.
+    is_new: bool,
+    /// The signal to deliver when the thread is resumed.
+    pending_signal: c_int,
+    /// The debug registers the thread had before it was attached to.
+    original: Option<DebugRegisters>,
+}
+
+/// How a thread stop reported by `waitpid` was handled.
+enum Stop {
+    /// The thread exited, which ends the session if it was the main thread.
+    Exited(Option<SessionEvent>),
+    /// A breakpoint was hit and dispatched.
+    Breakpoint,
+    /// A breakpoint was hit, and its callback asked to break.
+    Break,
+    /// The thread stopped because we caused it to.
+    Expected,
+    /// The thread stopped for a reason of its own,
+    /// and should be resumed with the given signal.
+    Other(c_int),
+}
+
+/// A backend for the threads of a traced process on Linux.
+///
+/// Debug registers are accessed through `PTRACE_PEEKUSER`/`PTRACE_POKEUSER`,
+/// and breakpoint hits are handled by `Session::wait`,
+/// which passes the registers of the tracee to the callbacks.
+///
+/// Ptrace only works from the thread that started tracing,
+/// so a session should not leave it.
+/// The debug registers of a thread can only be accessed while it is stopped,
+/// see `Session::stop`.
+pub struct Session {
+    pid: u32,
+    /// Whether the process was attached to rather than spawned.
+    is_attached: bool,
+    threads: RefCell<HashMap<u32, ThreadState>>,
+    callbacks: CallbackRegistry<user_regs_struct>,
+    /// The debug registers that new threads start with.
+    inherited: RefCell<DebugRegisters>,
+}
+
+impl Session {
+    /// Spawns a process under tracing.
+    ///
+    /// The process is stopped right after `exec`,
+    /// so breakpoints can be set before it runs any code.
+    /// It is killed when the session is dropped.
+    pub fn spawn(command: &mut Command) -> io::Result<Self> {
+        unsafe {
+            command.pre_exec(|| {
+                check(libc::ptrace(libc::PTRACE_TRACEME, 0, 0, 0))?;
+                Ok(())
+            });
+        }
+
+        let pid = command.spawn()?.id();
+        let (_, status) = wait_for(pid as pid_t)?;
+        if !libc::WIFSTOPPED(status) {
+            return Err(io::Error::other("process exited before being traced"));
+        }
+
+        let options =
+            libc::PTRACE_O_TRACECLONE | libc::PTRACE_O_TRACEEXEC | libc::PTRACE_O_EXITKILL;
+        check(unsafe {
+            libc::ptrace(libc::PTRACE_SETOPTIONS, pid as pid_t, 0, options as c_long)
+        })?;
+
+        let threads = HashMap::from([(
+            pid,
+            ThreadState {
+                is_stopped: true,
+                ..Default::default()
+            },
+        )]);
+
+        Ok(Self {
+            pid,
+            is_attached: false,
+            threads: RefCell::new(threads),
+            callbacks: CallbackRegistry::with_memory_reader(move |address, buffer| {
+                read_process_memory(pid, address, buffer)
+            }),
+            inherited: RefCell::default(),
+        })
+    }
+
+    /// Attaches to every thread of a running process.
+    ///
+    /// All threads are stopped when this returns,
+    /// and their debug registers are remembered so that `detach` can restore them.
+    /// The session detaches when dropped.
+    pub fn attach(pid: u32) -> io::Result<Self> {
+        let session = Self {
+            pid,
+            is_attached: true,
+            threads: RefCell::new(HashMap::new()),
+            callbacks: CallbackRegistry::with_memory_reader(move |address, buffer| {
+                read_process_memory(pid, address, buffer)
+            }),
+            inherited: RefCell::default(),
+        };
+
+        // Threads can be created while we are attaching,
+        // so keep going until no new ones show up.
+        let options = libc::PTRACE_O_TRACECLONE | libc::PTRACE_O_TRACEEXEC;
+        loop {
+            let mut found_new = false;
+            for tid in task_ids(pid)? {
+                if session.threads.borrow().contains_key(&tid) {
+                    continue;
+                }
+
+                let seized =
+                    unsafe { libc::ptrace(libc::PTRACE_SEIZE, tid as pid_t, 0, options as c_long) };
+                if seized == -1 {
+                    // The thread has exited in the meantime.
+                    continue;
+                }
+
+                check(unsafe { libc::ptrace(libc::PTRACE_INTERRUPT, tid as pid_t, 0, 0) })?;
+                session.threads.borrow_mut().insert(
+                    tid,
+                    ThreadState {
+                        expects_stop: true,
+                        ..Default::default()
+                    },
+                );
+                found_new = true;
+            }
+
+            if !found_new {
+                break;
+            }
+        }
+
+        session.stop()?;
+
+        for (&tid, state) in session.threads.borrow_mut().iter_mut() {
+            state.original = session.get_debug_registers(tid).ok();
+        }
+
+        Ok(session)
+    }
+
+    /// Gets the process id of the tracee.
+    pub fn pid(&self) -> u32 {
+        self.pid
+    }
+
+    /// Resumes the tracee and handles breakpoint hits until it exits.
+    ///
+    /// # Return value
+    /// The exit code of the tracee, or the negated signal that terminated it.
+    pub fn run(&self) -> io::Result<i32> {
+        loop {
+            match self.wait()? {
+                SessionEvent::Breakpoint { .. } | SessionEvent::Break { .. } => {}
+                SessionEvent::Exited(code) => return Ok(code),
+                SessionEvent::Signaled(signal) => return Ok(-signal),
+            }
+        }
+    }
+
+    /// Resumes all stopped threads and waits for the next breakpoint hit
+    /// or for the tracee to exit.
+    pub fn wait(&self) -> io::Result<SessionEvent> {
+        self.resume()?;
+
+        loop {
+            let (tid, status) = wait_for(-1)?;
+            match self.handle_stop(tid, status)? {
+                Stop::Exited(Some(event)) => return Ok(event),
+                Stop::Exited(None) => {}
+                Stop::Breakpoint => {
+                    self.set_stopped(tid, 0);
+                    return Ok(SessionEvent::Breakpoint { thread_id: tid });
+                }
+                Stop::Break => {
+                    self.set_stopped(tid, 0);
+                    return Ok(SessionEvent::Break { thread_id: tid });
+                }
+                Stop::Expected => cont(tid, 0)?,
+                Stop::Other(signal) => cont(tid, signal)?,
+            }
+        }
+    }
+
+    /// Stops all threads of the tracee,
+    /// so that their debug registers can be accessed.
+    ///
+    /// Breakpoints hit while stopping are still dispatched.
+    pub fn stop(&self) -> io::Result<()> {
+        for (&tid, state) in self.threads.borrow_mut().iter_mut() {
+            if state.is_stopped || state.expects_stop {
+                continue;
+            }
+
+            if self.is_attached {
+                check(unsafe { libc::ptrace(libc::PTRACE_INTERRUPT, tid as pid_t, 0, 0) })?;
+            } else {
+                check(unsafe { libc::tgkill(self.pid as pid_t, tid as pid_t, SIGSTOP) } as c_long)?;
+            }
+            state.expects_stop = true;
+        }
+
+        while self.threads.borrow().values().any(|x| !x.is_stopped) {
+            let (tid, status) = wait_for(-1)?;
+            match self.handle_stop(tid, status)? {
+                Stop::Exited(Some(_)) => break,
+                Stop::Exited(None) => {}
+                Stop::Breakpoint | Stop::Break | Stop::Expected => self.set_stopped(tid, 0),
+                Stop::Other(signal) => self.set_stopped(tid, signal),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Restores the original debug registers of all threads and detaches from them.
+    ///
+    /// Threads created after attaching get their breakpoints cleared.
+    pub fn detach(self) -> io::Result<()> {
+        self.detach_all()
+    }
+
+    /// Goes through every thread even if some fail, returning the first error.
+    fn detach_all(&self) -> io::Result<()> {
+        self.stop()?;
+
+        let threads = std::mem::take(&mut *self.threads.borrow_mut());
+        let mut result = Ok(());
+        for (tid, state) in threads {
+            let original = state.original.unwrap_or_default();
+            let restored = self
+                .set_debug_registers(tid, &original)
+                .map_err(io::Error::other);
+            let detached = check(unsafe {
+                libc::ptrace(
+                    libc::PTRACE_DETACH,
+                    tid as pid_t,
+                    0,
+                    state.pending_signal as c_long,
+                )
+            });
+            if result.is_ok() {
+                result = restored.and(detached.map(drop));
+            }
+        }
+
+        result
+    }
+
+    /// Resumes all stopped threads.
+    fn resume(&self) -> io::Result<()> {
+        for (&tid, state) in self.threads.borrow_mut().iter_mut() {
+            if state.is_stopped {
+                cont(tid, state.pending_signal)?;
+                state.is_stopped = false;
+                state.pending_signal = 0;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn set_stopped(&self, thread_id: u32, pending_signal: c_int) {
+        if let Some(state) = self.threads.borrow_mut().get_mut(&thread_id) {
+            state.is_stopped = true;
+            state.pending_signal = pending_signal;
+        }
+    }
+
+    fn handle_stop(&self, tid: u32, status: c_int) -> io::Result<Stop> {
+        if libc::WIFEXITED(status) || libc::WIFSIGNALED(status) {
+            self.threads.borrow_mut().remove(&tid);
+            if tid != self.pid {
+                return Ok(Stop::Exited(None));
+            }
+
+            self.threads.borrow_mut().clear();
+            return Ok(Stop::Exited(Some(if libc::WIFEXITED(status) {
+                SessionEvent::Exited(libc::WEXITSTATUS(status))
+            } else {
+                SessionEvent::Signaled(libc::WTERMSIG(status))
+            })));
+        }
+
+        // A thread we have not heard of yet was created by a clone.
+        let state = *self.threads.borrow_mut().entry(tid).or_insert(ThreadState {
+            expects_stop: true,
+            is_new: true,
+            ..Default::default()
+        });
+
+        let expected = || {
+            if let Some(state) = self.threads.borrow_mut().get_mut(&tid) {
+                state.expects_stop = false;
+                state.is_new = false;
+            }
+
+            // Ptrace breakpoints are not inherited by the kernel, so do it ourselves.
+            let inherited = *self.inherited.borrow();
+            if state.is_new && inherited.has_enabled() {
+                self.callbacks.inherit(tid);
+                _ = self.set_debug_registers(tid, &inherited);
+            }
+
+            Ok(Stop::Expected)
+        };
+
+        match (libc::WSTOPSIG(status), status >> 16) {
+            (SIGTRAP, libc::PTRACE_EVENT_CLONE) => {
+                let mut new_tid: libc::c_ulong = 0;
+                check(unsafe {
+                    libc::ptrace(
+                        libc::PTRACE_GETEVENTMSG,
+                        tid as pid_t,
+                        0,
+                        &mut new_tid as *mut _,
+                    )
+                })?;
+                self.threads
+                    .borrow_mut()
+                    .entry(new_tid as u32)
+                    .or_insert(ThreadState {
+                        expects_stop: true,
+                        is_new: true,
+                        ..Default::default()
+                    });
+                Ok(Stop::Other(0))
+            }
+            (SIGTRAP, 0) => Ok(self.dispatch(tid)?.unwrap_or(Stop::Other(SIGTRAP))),
+            (SIGSTOP, 0) if state.expects_stop => expected(),
+            (_, libc::PTRACE_EVENT_STOP) if state.expects_stop => expected(),
+            (signal, 0) => Ok(Stop::Other(signal)),
+            _ => Ok(Stop::Other(0)),
+        }
+    }
+
+    /// Runs the callbacks of the breakpoints that stopped the thread, if any,
+    /// and carries out the actions they returned.
+    fn dispatch(&self, thread_id: u32) -> io::Result<Option<Stop>> {
+        let dr6 = peek_debug_register(thread_id, DR6)?;
+        let dr7 = peek_debug_register(thread_id, DR7)?;
+        let is_stepping = dr6 & DR6_SINGLE_STEP != 0 && self.callbacks.is_stepping(thread_id);
+        if dr6 & 0b1111 == 0 && !is_stepping {
+            return Ok(None);
+        }
+
+        let mut regs: user_regs_struct = unsafe { std::mem::zeroed() };
+        check(unsafe {
+            libc::ptrace(
+                libc::PTRACE_GETREGS,
+                thread_id as pid_t,
+                0,
+                &mut regs as *mut _,
+            )
+        })?;
+
+        let mut registers = DebugRegisters {
+            dr7,
+            ..Default::default()
+        };
+        for (n, address) in registers.address.iter_mut().enumerate() {
+            *address = peek_debug_register(thread_id, n)?;
+        }
+
+        let dispatch = self
+            .callbacks
+            .dispatch(thread_id, dr6, &registers, regs.rip, &mut regs);
+        if !dispatch.is_dispatched() {
+            return Ok(None);
+        }
+
+        regs.eflags = dispatch.apply_to_flags(regs.eflags);
+        check(unsafe {
+            libc::ptrace(
+                libc::PTRACE_SETREGS,
+                thread_id as pid_t,
+                0,
+                &regs as *const _,
+            )
+        })?;
+        poke_debug_register(thread_id, DR6, dispatch.dr6())?;
+        if dispatch.apply_to_dr7(dr7) != dr7 {
+            poke_debug_register(thread_id, DR7, dispatch.apply_to_dr7(dr7))?;
+        }
+
+        Ok(Some(if !dispatch.is_handled() {
+            Stop::Other(SIGTRAP)
+        } else if dispatch.is_break() {
+            Stop::Break
+        } else {
+            Stop::Breakpoint
+        }))
+    }
+}
+
+impl fmt::Debug for Session {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Session")
+            .field("pid", &self.pid)
+            .field("threads", &self.threads.borrow())
+            .finish()
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        if self.is_attached {
+            _ = self.detach_all();
+            return;
+        }
+
+        if self.threads.borrow().contains_key(&self.pid) {
+            unsafe { libc::kill(self.pid as pid_t, libc::SIGKILL) };
+            while self.threads.borrow().contains_key(&self.pid) {
+                match wait_for(-1) {
+                    Ok((tid, status)) if libc::WIFEXITED(status) || libc::WIFSIGNALED(status) => {
+                        self.threads.borrow_mut().remove(&tid);
+                    }
+                    Ok(_) => {}
+                    Err(_) => break,
+                }
+            }
+        }
+    }
+}
+
+impl Backend for Session {
+    type Registers = user_regs_struct;
+
+    /// Gets the id of the main thread of the tracee.
+    fn current_thread_id(&self) -> u32 {
+        self.pid
+    }
+
+    fn get_debug_registers(&self, thread_id: u32) -> Result<DebugRegisters> {
+        let mut registers = DebugRegisters::default();
+        for (n, address) in registers.address.iter_mut().enumerate() {
+            *address = peek_debug_register(thread_id, n).map_err(ContextError::GetContextFailed)?;
+        }
+        registers.dr7 =
+            peek_debug_register(thread_id, DR7).map_err(ContextError::GetContextFailed)?;
+
+        Ok(registers)
+    }
+
+    fn set_debug_registers(&self, thread_id: u32, registers: &DebugRegisters) -> Result<()> {
+        // Disable everything first, so that the kernel never
+        // validates a new address against an old length or vice versa.
+        poke_debug_register(thread_id, DR7, 0).map_err(ContextError::SetContextFailed)?;
+        for (n, address) in registers.address.iter().enumerate() {
+            poke_debug_register(thread_id, n, *address).map_err(ContextError::SetContextFailed)?;
+        }
+        poke_debug_register(thread_id, DR7, registers.dr7).map_err(ContextError::SetContextFailed)
+    }
+
+    /// Gets the ids of all traced threads.
+    fn enumerate_threads(&self) -> Result<Vec<u32>> {
+        let mut threads: Vec<u32> = self.threads.borrow().keys().copied().collect();
+        threads.sort_unstable();
+        Ok(threads)
+    }
+
+    fn set_inherited_debug_registers(&self, registers: &DebugRegisters) -> Result<()> {
+        *self.inherited.borrow_mut() = *registers;
+        Ok(())
+    }
+
+    /// Does nothing, breakpoint hits are handled by `Session::wait`.
+    fn install_handler(&self) {}
+
+    /// Does nothing, breakpoint hits are handled by `Session::wait`.
+    fn uninstall_handler(&self) {}
+
+    fn callbacks(&self) -> &CallbackRegistry<user_regs_struct> {
+        &self.callbacks
+    }
+}
+
+fn check(result: c_long) -> io::Result<c_long> {
+    if result == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(result)
+    }
+}
+
+/// Waits for a state change of a traced thread, `-1` meaning any.
+fn wait_for(tid: pid_t) -> io::Result<(u32, c_int)> {
+    let mut status = 0;
+    loop {
+        let result = unsafe { libc::waitpid(tid, &mut status, libc::__WALL) };
+        if result != -1 {
+            return Ok((result as u32, status));
+        }
+
+        let error = io::Error::last_os_error();
+        if error.kind() != io::ErrorKind::Interrupted {
+            return Err(error);
+        }
+    }
+}
+
+fn debug_register_offset(n: usize) -> usize {
+    offset_of!(user, u_debugreg) + n * std::mem::size_of::<u64>()
+}
+
+fn peek_debug_register(thread_id: u32, n: usize) -> io::Result<u64> {
+    unsafe {
+        // PTRACE_PEEKUSER returns the value, so errors are only reported through errno.
+        *libc::__errno_location() = 0;
+        let value = libc::ptrace(
+            libc::PTRACE_PEEKUSER,
+            thread_id as pid_t,
+            debug_register_offset(n),
+            std::ptr::null_mut::<c_void>(),
+        );
+        if value == -1 && *libc::__errno_location() != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(value as u64)
+    }
+}
+
+fn poke_debug_register(thread_id: u32, n: usize, value: u64) -> io::Result<()> {
+    check(unsafe {
+        libc::ptrace(
+            libc::PTRACE_POKEUSER,
+            thread_id as pid_t,
+            debug_register_offset(n),
+            value as c_long,
+        )
+    })?;
+    Ok(())
+}
+
+fn cont(thread_id: u32, signal: c_int) -> io::Result<()> {
+    check(unsafe { libc::ptrace(libc::PTRACE_CONT, thread_id as pid_t, 0, signal as c_long) })?;
+    Ok(())
+}
+
+fn task_ids(pid: u32) -> io::Result<Vec<u32>> {
+    let mut threads = Vec::new();
+    for entry in std::fs::read_dir(format!("/proc/{pid}/task"))? {
+        if let Some(id) = entry?.file_name().to_str().and_then(|x| x.parse().ok()) {
+            threads.push(id);
+        }
+    }
+
+    Ok(threads)
+}
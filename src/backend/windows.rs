@@ -0,0 +1,482 @@
+use std::{
+    ffi::c_void,
+    io,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Mutex, RwLock,
+    },
+};
+
+use windows::Win32::{
+    Foundation::{
+        CloseHandle, EXCEPTION_BREAKPOINT, EXCEPTION_GUARD_PAGE, EXCEPTION_SINGLE_STEP, HANDLE,
+    },
+    System::{
+        Diagnostics::{
+            Debug::{
+                AddVectoredExceptionHandler, DebugBreak, FlushInstructionCache, GetThreadContext,
+                ReadProcessMemory, RemoveVectoredExceptionHandler, SetThreadContext,
+                CONTEXT_DEBUG_REGISTERS_AMD64, EXCEPTION_CONTINUE_EXECUTION,
+                EXCEPTION_CONTINUE_SEARCH, EXCEPTION_POINTERS,
+            },
+            ToolHelp::{
+                CreateToolhelp32Snapshot, Thread32First, Thread32Next, TH32CS_SNAPTHREAD,
+                THREADENTRY32,
+            },
+        },
+        Memory::{
+            VirtualProtect, VirtualQuery, MEMORY_BASIC_INFORMATION, PAGE_EXECUTE_READWRITE,
+            PAGE_GUARD, PAGE_PROTECTION_FLAGS,
+        },
+        SystemServices::DLL_THREAD_ATTACH,
+        Threading::{
+            GetCurrentProcess, GetCurrentProcessId, GetCurrentThread, GetCurrentThreadId,
+            OpenThread, ResumeThread, SuspendThread, THREAD_GET_CONTEXT, THREAD_SET_CONTEXT,
+            THREAD_SUSPEND_RESUME,
+        },
+    },
+};
+
+use lazy_static::lazy_static;
+
+use crate::{
+    paging::{PageWatcher, PAGE_SIZE},
+    software::SoftwareBreakpoints,
+    windows::{AlignedContext, CONTEXT},
+    CallbackRegistry, Condition, ContextError,
+};
+
+use super::{Backend, DebugRegisters, Result};
+
+/// TF, set in EFLAGS to single-step.
+const TRAP_FLAG: u32 = 1 << 8;
+
+static HANDLER_HANDLE: Mutex<Option<usize>> = Mutex::new(None);
+
+/// The threads in the exception handler, which are not to be suspended,
+/// as the context they resume with would undo the new debug registers.
+/// Threads beyond the first 64 at once are not tracked.
+static HANDLING: [AtomicU32; 64] = [const { AtomicU32::new(0) }; 64];
+
+/// The protection of the pages guarded for page watches, before they were.
+static ORIGINAL_PROTECTION: Mutex<Vec<(u64, PAGE_PROTECTION_FLAGS)>> = Mutex::new(Vec::new());
+
+static INHERITED: RwLock<DebugRegisters> = RwLock::new(DebugRegisters {
+    address: [0; 4],
+    dr7: 0,
+});
+
+/// Called by the loader in every new thread before it runs.
+///
+/// See: https://learn.microsoft.com/en-us/windows/win32/debug/pe-format#tls-callback-functions
+#[used]
+#[link_section = ".CRT$XLB"]
+static TLS_CALLBACK: unsafe extern "system" fn(*mut c_void, u32, *mut c_void) = on_thread_event;
+
+lazy_static! {
+    static ref CALLBACKS: CallbackRegistry<CONTEXT> =
+        CallbackRegistry::with_memory_reader(read_memory);
+    static ref PAGES: PageWatcher<CONTEXT> = PageWatcher::new(set_protection);
+    static ref SOFTWARE: SoftwareBreakpoints<CONTEXT> = SoftwareBreakpoints::new(write_code);
+}
+
+/// The backend for the threads of the current process on Windows.
+///
+/// Debug registers are accessed through `GetThreadContext`/`SetThreadContext`,
+/// and breakpoints are handled by a vectored exception handler.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct WindowsBackend;
+
+impl Backend for WindowsBackend {
+    type Registers = CONTEXT;
+
+    fn current_thread_id(&self) -> u32 {
+        unsafe { GetCurrentThreadId() }
+    }
+
+    fn get_debug_registers(&self, thread_id: u32) -> Result<DebugRegisters> {
+        get_debug_registers(thread_id)
+    }
+
+    fn set_debug_registers(&self, thread_id: u32, registers: &DebugRegisters) -> Result<()> {
+        set_debug_registers(thread_id, registers)
+    }
+
+    fn enumerate_threads(&self) -> Result<Vec<u32>> {
+        enumerate_threads().map_err(ContextError::EnumeratingThreadsFailed)
+    }
+
+    fn set_inherited_debug_registers(&self, registers: &DebugRegisters) -> Result<()> {
+        *INHERITED.write().unwrap() = *registers;
+        Ok(())
+    }
+
+    fn suspend_thread(&self, thread_id: u32) -> Result<()> {
+        suspend_thread(thread_id)
+    }
+
+    fn resume_thread(&self, thread_id: u32) -> Result<()> {
+        resume_thread(thread_id)
+    }
+
+    fn install_handler(&self) {
+        install_handler();
+    }
+
+    fn uninstall_handler(&self) {
+        uninstall_handler();
+    }
+
+    fn callbacks(&self) -> &CallbackRegistry<CONTEXT> {
+        &CALLBACKS
+    }
+
+    fn page_watcher(&self) -> Option<&PageWatcher<CONTEXT>> {
+        Some(&PAGES)
+    }
+
+    fn software_breakpoints(&self) -> Option<&SoftwareBreakpoints<CONTEXT>> {
+        Some(&SOFTWARE)
+    }
+}
+
+/// Runs `f` with a handle to the thread, which is closed afterwards.
+fn with_thread<T>(thread_id: u32, f: impl FnOnce(HANDLE) -> Result<T>) -> Result<T> {
+    if thread_id == unsafe { GetCurrentThreadId() } {
+        return f(unsafe { GetCurrentThread() });
+    }
+
+    let handle = unsafe { OpenThread(THREAD_GET_CONTEXT | THREAD_SET_CONTEXT, false, thread_id) }
+        .map_err(ContextError::OpenThreadFailed)?;
+
+    let result = f(handle);
+
+    _ = unsafe { CloseHandle(handle) };
+
+    result
+}
+
+fn get_debug_registers(thread_id: u32) -> Result<DebugRegisters> {
+    with_thread(thread_id, |handle| {
+        let mut actx = AlignedContext(CONTEXT {
+            ContextFlags: CONTEXT_DEBUG_REGISTERS_AMD64,
+            ..Default::default()
+        });
+        unsafe { GetThreadContext(handle, &mut actx.0) }.map_err(ContextError::GetContextFailed)?;
+
+        let ctx = &actx.0;
+        Ok(DebugRegisters {
+            address: [ctx.Dr0, ctx.Dr1, ctx.Dr2, ctx.Dr3],
+            dr7: ctx.Dr7,
+        })
+    })
+}
+
+fn set_debug_registers(thread_id: u32, registers: &DebugRegisters) -> Result<()> {
+    with_thread(thread_id, |handle| {
+        let mut actx = AlignedContext(CONTEXT {
+            ContextFlags: CONTEXT_DEBUG_REGISTERS_AMD64,
+            ..Default::default()
+        });
+
+        let ctx = &mut actx.0;
+        [ctx.Dr0, ctx.Dr1, ctx.Dr2, ctx.Dr3] = registers.address;
+        ctx.Dr7 = registers.dr7;
+
+        unsafe { SetThreadContext(handle, ctx) }.map_err(ContextError::SetContextFailed)
+    })
+}
+
+/// Suspends a thread, outside of the exception handler.
+fn suspend_thread(thread_id: u32) -> Result<()> {
+    if thread_id == unsafe { GetCurrentThreadId() } {
+        return Ok(());
+    }
+
+    let handle =
+        unsafe { OpenThread(THREAD_SUSPEND_RESUME | THREAD_GET_CONTEXT, false, thread_id) }
+            .map_err(ContextError::OpenThreadFailed)?;
+
+    let result = loop {
+        if unsafe { SuspendThread(handle) } == u32::MAX {
+            break Err(ContextError::OpenThreadFailed(
+                windows::core::Error::from_win32(),
+            ));
+        }
+
+        // Suspending is asynchronous, getting the context waits for it.
+        let mut actx = AlignedContext(CONTEXT {
+            ContextFlags: CONTEXT_DEBUG_REGISTERS_AMD64,
+            ..Default::default()
+        });
+        _ = unsafe { GetThreadContext(handle, &mut actx.0) };
+
+        let is_handling = HANDLING
+            .iter()
+            .any(|x| x.load(Ordering::Acquire) == thread_id);
+        if !is_handling {
+            break Ok(());
+        }
+
+        unsafe { ResumeThread(handle) };
+        std::thread::yield_now();
+    };
+
+    _ = unsafe { CloseHandle(handle) };
+    result
+}
+
+fn resume_thread(thread_id: u32) -> Result<()> {
+    if thread_id == unsafe { GetCurrentThreadId() } {
+        return Ok(());
+    }
+
+    let handle = unsafe { OpenThread(THREAD_SUSPEND_RESUME, false, thread_id) }
+        .map_err(ContextError::OpenThreadFailed)?;
+
+    let result = match unsafe { ResumeThread(handle) } {
+        u32::MAX => Err(ContextError::OpenThreadFailed(
+            windows::core::Error::from_win32(),
+        )),
+        _ => Ok(()),
+    };
+
+    _ = unsafe { CloseHandle(handle) };
+    result
+}
+
+/// Reads memory of the current process, failing for unreadable pages
+/// instead of raising an access violation.
+fn read_memory(address: u64, buffer: &mut [u8]) -> std::io::Result<()> {
+    let mut read = 0;
+    unsafe {
+        ReadProcessMemory(
+            GetCurrentProcess(),
+            address as *const c_void,
+            buffer.as_mut_ptr().cast(),
+            buffer.len(),
+            Some(&mut read),
+        )
+    }
+    .map_err(std::io::Error::from)?;
+
+    match read == buffer.len() {
+        true => Ok(()),
+        false => Err(std::io::ErrorKind::UnexpectedEof.into()),
+    }
+}
+
+/// Guards a page of the current process for a page watch,
+/// which faults on any access, so the condition is left to `PageWatcher`.
+///
+/// The system removes the guard when it faults, so it is put back after every step.
+fn set_protection(page: u64, condition: Option<Condition>) -> io::Result<()> {
+    let mut originals = ORIGINAL_PROTECTION.lock().unwrap();
+    let position = originals.iter().position(|(x, _)| *x == page);
+    let address = page as *const c_void;
+    let mut old = PAGE_PROTECTION_FLAGS::default();
+
+    match (condition, position) {
+        (Some(_), Some(position)) => unsafe {
+            VirtualProtect(
+                address,
+                PAGE_SIZE as usize,
+                originals[position].1 | PAGE_GUARD,
+                &mut old,
+            )
+        },
+        (Some(_), None) => {
+            let mut info = MEMORY_BASIC_INFORMATION::default();
+            let size = std::mem::size_of::<MEMORY_BASIC_INFORMATION>();
+            if unsafe { VirtualQuery(Some(address), &mut info, size) } == 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            unsafe {
+                VirtualProtect(
+                    address,
+                    PAGE_SIZE as usize,
+                    info.Protect | PAGE_GUARD,
+                    &mut old,
+                )
+            }
+            .inspect(|_| originals.push((page, info.Protect)))
+        }
+        (None, Some(position)) => {
+            let (_, original) = originals.remove(position);
+            unsafe { VirtualProtect(address, PAGE_SIZE as usize, original, &mut old) }
+        }
+        (None, None) => Ok(()),
+    }
+    .map_err(io::Error::from)
+}
+
+/// Writes a byte of code of the current process for a software breakpoint,
+/// restoring the protection of its page afterwards.
+unsafe fn write_code(address: u64, byte: u8) -> io::Result<u8> {
+    let code = address as *mut u8;
+    let mut old = PAGE_PROTECTION_FLAGS::default();
+    VirtualProtect(code as *const c_void, 1, PAGE_EXECUTE_READWRITE, &mut old)?;
+    let original = std::ptr::replace(code, byte);
+    VirtualProtect(code as *const c_void, 1, old, &mut old)?;
+    FlushInstructionCache(GetCurrentProcess(), Some(code as *const c_void), 1)?;
+    Ok(original)
+}
+
+fn enumerate_threads() -> windows::core::Result<Vec<u32>> {
+    let pid = unsafe { GetCurrentProcessId() };
+    let snapshot = unsafe { CreateToolhelp32Snapshot(TH32CS_SNAPTHREAD, 0)? };
+
+    let mut threads = Vec::new();
+    let mut entry = THREADENTRY32 {
+        dwSize: std::mem::size_of::<THREADENTRY32>() as u32,
+        ..Default::default()
+    };
+
+    if unsafe { Thread32First(snapshot, &mut entry) }.is_err() {
+        // No threads to enumerate?
+        _ = unsafe { CloseHandle(snapshot) };
+        return Ok(threads);
+    }
+
+    loop {
+        if entry.th32OwnerProcessID == pid {
+            threads.push(entry.th32ThreadID);
+        }
+
+        if unsafe { Thread32Next(snapshot, &mut entry) }.is_err() {
+            break;
+        }
+    }
+
+    _ = unsafe { CloseHandle(snapshot) };
+    Ok(threads)
+}
+
+unsafe extern "system" fn on_thread_event(_: *mut c_void, reason: u32, _: *mut c_void) {
+    if reason != DLL_THREAD_ATTACH {
+        return;
+    }
+
+    let Some(registers) = INHERITED.read().ok().map(|x| *x) else {
+        return;
+    };
+    if registers.has_enabled() {
+        let tid = GetCurrentThreadId();
+        CALLBACKS.inherit(tid);
+        _ = set_debug_registers(tid, &registers);
+    }
+}
+
+fn install_handler() {
+    let mut lock = HANDLER_HANDLE.lock().unwrap();
+    if lock.is_some() {
+        return;
+    }
+
+    // Initializing them allocates, which the handler should not do.
+    lazy_static::initialize(&PAGES);
+    lazy_static::initialize(&SOFTWARE);
+
+    let handler = unsafe { AddVectoredExceptionHandler(1, Some(exception_handler)) };
+    *lock = Some(handler as usize);
+}
+
+fn uninstall_handler() {
+    let mut lock = HANDLER_HANDLE.lock().unwrap();
+
+    if let Some(handler) = lock.take() {
+        unsafe { RemoveVectoredExceptionHandler(handler as _) };
+    }
+}
+
+pub unsafe extern "system" fn exception_handler(ex: *mut EXCEPTION_POINTERS) -> i32 {
+    let tid = GetCurrentThreadId();
+    let entry = HANDLING.iter().find(|x| {
+        x.compare_exchange(0, tid, Ordering::AcqRel, Ordering::Acquire)
+            .is_ok()
+    });
+
+    let result = handle_exception(ex);
+
+    if let Some(entry) = entry {
+        entry.store(0, Ordering::Release);
+    }
+    result
+}
+
+unsafe fn handle_exception(ex: *mut EXCEPTION_POINTERS) -> i32 {
+    if let Some(ex) = ex.as_mut() {
+        let cr = ex.ContextRecord;
+        let er = ex.ExceptionRecord;
+        if let (Some(cr), Some(er)) = (cr.as_mut(), er.as_ref()) {
+            if er.ExceptionCode == EXCEPTION_GUARD_PAGE {
+                // The kind of access, 1 for writes, and the accessed address.
+                let is_write = er.ExceptionInformation[0] == 1;
+                let address = er.ExceptionInformation[1] as u64;
+                if !PAGES.on_fault(GetCurrentThreadId(), address, is_write) {
+                    return EXCEPTION_CONTINUE_SEARCH;
+                }
+
+                // Single-step the access, which guards the page again.
+                cr.EFlags |= TRAP_FLAG;
+                return EXCEPTION_CONTINUE_EXECUTION;
+            }
+
+            if er.ExceptionCode == EXCEPTION_BREAKPOINT {
+                // Point at the `int3` itself, which is where the original instruction goes.
+                let (rip, address) = (cr.Rip, er.ExceptionAddress as u64);
+                cr.Rip = address;
+                let tid = GetCurrentThreadId();
+                let Some(is_break) = SOFTWARE.on_hit(tid, address, cr, CALLBACKS.memory()) else {
+                    cr.Rip = rip;
+                    return EXCEPTION_CONTINUE_SEARCH;
+                };
+
+                // Single-step the original instruction, which inserts the `int3` again.
+                cr.EFlags |= TRAP_FLAG;
+                if is_break {
+                    DebugBreak();
+                }
+                return EXCEPTION_CONTINUE_EXECUTION;
+            }
+
+            if er.ExceptionCode == EXCEPTION_SINGLE_STEP {
+                let tid = GetCurrentThreadId();
+                if PAGES.is_pending(tid) || SOFTWARE.is_pending(tid) {
+                    cr.EFlags &= !TRAP_FLAG;
+                    SOFTWARE.on_step(tid);
+                    if PAGES.on_step(tid, cr.Rip, cr, CALLBACKS.memory()) == Some(true) {
+                        DebugBreak();
+                    }
+
+                    // The step may also be one the callbacks asked for, or hit breakpoints.
+                    if !CALLBACKS.is_stepping(tid) && cr.Dr6 & 0b1111 == 0 {
+                        return EXCEPTION_CONTINUE_EXECUTION;
+                    }
+                }
+
+                let registers = DebugRegisters {
+                    address: [cr.Dr0, cr.Dr1, cr.Dr2, cr.Dr3],
+                    dr7: cr.Dr7,
+                };
+                let dispatch = CALLBACKS.dispatch(tid, cr.Dr6, &registers, cr.Rip, cr);
+                cr.Dr6 = dispatch.dr6();
+                cr.Dr7 = dispatch.apply_to_dr7(cr.Dr7);
+
+                // Leave single steps and breakpoints of others to their handlers.
+                if !dispatch.is_handled() {
+                    return EXCEPTION_CONTINUE_SEARCH;
+                }
+                if dispatch.is_break() {
+                    DebugBreak();
+                }
+
+                cr.EFlags = dispatch.apply_to_flags(cr.EFlags as u64) as u32;
+                return EXCEPTION_CONTINUE_EXECUTION;
+            }
+        }
+    }
+
+    EXCEPTION_CONTINUE_SEARCH
+}
@@ -1,30 +1,741 @@
-use lazy_static::lazy_static;
 use std::{
+    cell::Cell,
     collections::HashMap,
-    sync::{RwLock, RwLockWriteGuard},
+    io,
+    mem::ManuallyDrop,
+    ops::{Deref, DerefMut},
+    sync::{
+        atomic::{AtomicU32, AtomicU64, AtomicU8, AtomicUsize, Ordering},
+        Arc, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard, TryLockError,
+    },
 };
 
-use crate::{HWBPCallback, Index};
+use crate::{
+    audit::Applied,
+    handler,
+    hwbp::Hook,
+    x86::{DR6, DR7},
+    DebugRegisters, HitAction, Index, MemoryReader, Trace,
+};
+
+const TRAP_FLAG: u64 = 1 << 8;
+const RESUME_FLAG: u64 = 1 << 16;
+
+/// The bits of DR6 telling why a debug exception was raised:
+/// B0-B3, BD, BS and BT.
+const DR6_CAUSES: u64 = 0b1111 | 0b111 << 13;
+
+/// How many callbacks removed from the handler can wait for the lock at once.
+const MAX_DEFERRED: usize = 64;
+
+/// A callback removed from the handler while the lock was taken,
+/// which is removed when the lock is next taken for writing.
+///
+/// Handlers must not block nor allocate, so this is preallocated.
+struct DeferredRemoval {
+    /// The thread id and the index, plus one, 0 if none.
+    key: AtomicU64,
+    /// The filter of the removed hook, so that a hook set since is kept.
+    filter: AtomicUsize,
+}
+
+impl DeferredRemoval {
+    /// The key while the removal is being filled in.
+    const FILLING: u64 = u64::MAX;
+
+    fn new() -> Self {
+        Self {
+            key: AtomicU64::new(0),
+            filter: AtomicUsize::new(0),
+        }
+    }
+
+    fn key(thread_id: u32, index: Index) -> u64 {
+        ((thread_id as u64) << 2 | index as u64) + 1
+    }
+
+    fn filter<R>(hook: &Hook<R>) -> usize {
+        Arc::as_ptr(&hook.filter) as *const () as usize
+    }
+}
+
+/// How many threads can single-step on behalf of breakpoints or trace at once.
+const MAX_STEPPING: usize = 64;
+
+/// What a thread is single-stepping for.
+///
+/// Kept apart from the callbacks and preallocated,
+/// so that its handler neither allocates nor waits for a lock to update it.
+/// Only the handler of the thread touches it, save for reads.
+struct Stepping<R> {
+    /// The thread, 0 if none.
+    thread_id: AtomicU32,
+    /// The breakpoints it is single-stepping on behalf of, one bit each.
+    indices: AtomicU8,
+    trace: Mutex<Option<Trace<R>>>,
+}
+
+impl<R> Stepping<R> {
+    fn new() -> Self {
+        Self {
+            thread_id: AtomicU32::new(0),
+            indices: AtomicU8::new(0),
+            trace: Mutex::new(None),
+        }
+    }
+
+    fn has_trace(&self) -> bool {
+        self.trace.try_lock().is_ok_and(|x| x.is_some())
+    }
+
+    /// Gives it up if the thread is done stepping.
+    fn release_if_done(&self) {
+        if self.indices.load(Ordering::Acquire) == 0 && !self.has_trace() {
+            self.thread_id.store(0, Ordering::Release);
+        }
+    }
+}
+
+thread_local! {
+    /// Whether the thread holds the callbacks for writing,
+    /// so that its handler does not wait for itself.
+    static IS_WRITING: Cell<bool> = const { Cell::new(false) };
+}
+
+/// The callbacks locked for writing, see `CallbackRegistry::get_write_lock`.
+pub(crate) struct CallbacksWriteGuard<'a, R> {
+    lock: ManuallyDrop<RwLockWriteGuard<'a, HashMap<u32, ThreadCallbacks<R>>>>,
+}
+
+impl<R> Deref for CallbacksWriteGuard<'_, R> {
+    type Target = HashMap<u32, ThreadCallbacks<R>>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.lock
+    }
+}
+
+impl<R> DerefMut for CallbacksWriteGuard<'_, R> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.lock
+    }
+}
+
+impl<R> Drop for CallbacksWriteGuard<'_, R> {
+    fn drop(&mut self) {
+        // Unlocked first, so that the handler never finds the lock taken with the flag cleared.
+        unsafe { ManuallyDrop::drop(&mut self.lock) };
+        IS_WRITING.set(false);
+    }
+}
+
+/// The callbacks set for a thread.
+pub(crate) struct ThreadCallbacks<R> {
+    pub(crate) callbacks: [Option<Hook<R>>; 4],
+    /// Whether breakpoints hit together get a single callback.
+    pub(crate) is_coalesced: bool,
+}
 
-type HWBPCallbackList = [Option<HWBPCallback>; 4];
+impl<R> Clone for ThreadCallbacks<R> {
+    fn clone(&self) -> Self {
+        Self {
+            callbacks: self.callbacks.clone(),
+            is_coalesced: self.is_coalesced,
+        }
+    }
+}
+
+impl<R> Default for ThreadCallbacks<R> {
+    fn default() -> Self {
+        Self {
+            callbacks: Default::default(),
+            is_coalesced: false,
+        }
+    }
+}
 
-lazy_static! {
-    static ref CALLBACKS: RwLock<HashMap<u32, HWBPCallbackList>> = RwLock::new(HashMap::new());
+/// The outcome of `CallbackRegistry::dispatch`, which the backend has to carry out.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Dispatch {
+    dr6: u64,
+    actions: [Option<HitAction>; 4],
+    is_step: bool,
+    is_stepping: bool,
 }
 
-pub fn get(thread_id: u32, index: Index) -> Option<HWBPCallback> {
-    if let Ok(callbacks) = CALLBACKS.read() {
-        callbacks
+impl Dispatch {
+    /// Gets `DR6` with the detection bits of the dispatched breakpoints cleared.
+    pub fn dr6(&self) -> u64 {
+        self.dr6
+    }
+
+    /// Gets the action returned for a breakpoint, if its callback was run.
+    pub fn get_action(&self, index: Index) -> Option<HitAction> {
+        self.actions[index as usize]
+    }
+
+    /// Gets whether anything was dispatched,
+    /// either a breakpoint hit or a single step the thread asked for.
+    pub fn is_dispatched(&self) -> bool {
+        self.is_step || self.actions.iter().any(Option::is_some)
+    }
+
+    /// Gets whether the trap has been fully handled.
+    ///
+    /// Otherwise it should be passed on to the next handler,
+    /// either because a callback asked for it
+    /// or because `DR6` reports a cause that is not ours,
+    /// such as single-stepping by another tool.
+    pub fn is_handled(&self) -> bool {
+        self.is_dispatched() && self.dr6 & DR6_CAUSES == 0 && !self.is_passed_on()
+    }
+
+    /// Gets whether a callback asked to pass the trap on to the next handler.
+    pub fn is_passed_on(&self) -> bool {
+        self.actions.contains(&Some(HitAction::PassOn))
+    }
+
+    /// Gets whether a callback asked to break into a debugger.
+    pub fn is_break(&self) -> bool {
+        self.actions.contains(&Some(HitAction::Break))
+    }
+
+    /// Gets `dr7` with the breakpoints disabled that the actions ask to.
+    pub fn apply_to_dr7(&self, dr7: u64) -> u64 {
+        let mut dr7 = DR7::from_bits(dr7);
+        for (index, action) in Index::ALL.into_iter().zip(self.actions) {
+            if let Some(HitAction::Disable | HitAction::Remove) = action {
+                match index {
+                    Index::First => dr7.set_bp_local_0(false),
+                    Index::Second => dr7.set_bp_local_1(false),
+                    Index::Third => dr7.set_bp_local_2(false),
+                    Index::Fourth => dr7.set_bp_local_3(false),
+                }
+            }
+        }
+        dr7.into_bits()
+    }
+
+    /// Gets `eflags` to resume the thread with.
+    ///
+    /// Sets the resume flag so that the breakpoint does not hit again,
+    /// and sets or clears the trap flag depending on whether the thread keeps stepping.
+    pub fn apply_to_flags(&self, eflags: u64) -> u64 {
+        let mut eflags = eflags | RESUME_FLAG;
+        if self.is_stepping {
+            eflags |= TRAP_FLAG;
+        } else if self.is_step {
+            eflags &= !TRAP_FLAG;
+        }
+        eflags
+    }
+}
+
+/// Callbacks of hardware breakpoints, per thread.
+///
+/// Filled in by `Context` when it is applied to a thread,
+/// and used by the backend's handler to find the callback of a hit.
+pub struct CallbackRegistry<R> {
+    callbacks: RwLock<HashMap<u32, ThreadCallbacks<R>>>,
+    /// Callbacks for threads that inherit their breakpoints when created.
+    inherited: RwLock<ThreadCallbacks<R>>,
+    /// What threads are single-stepping for, on behalf of breakpoints or in traces.
+    stepping: [Stepping<R>; MAX_STEPPING],
+    /// The owner tags hardware breakpoints are reserved for, in every thread.
+    owners: RwLock<[Option<&'static str>; 4]>,
+    /// The debug registers last applied to threads, for audits.
+    applied: RwLock<HashMap<u32, Applied>>,
+    /// The callbacks removed while the lock was taken.
+    deferred: [DeferredRemoval; MAX_DEFERRED],
+    memory: Option<MemoryReader>,
+}
+
+impl<R> CallbackRegistry<R> {
+    pub fn new() -> Self {
+        Self {
+            callbacks: RwLock::new(HashMap::new()),
+            inherited: RwLock::new(Default::default()),
+            stepping: std::array::from_fn(|_| Stepping::new()),
+            owners: RwLock::new([None; 4]),
+            applied: RwLock::new(HashMap::new()),
+            deferred: std::array::from_fn(|_| DeferredRemoval::new()),
+            memory: None,
+        }
+    }
+
+    /// Creates a registry whose callbacks can read memory of the target,
+    /// see `HitContext::read_memory`.
+    pub fn with_memory_reader(
+        reader: impl Fn(u64, &mut [u8]) -> io::Result<()> + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            memory: Some(Box::new(reader)),
+            ..Self::new()
+        }
+    }
+
+    pub(crate) fn memory(&self) -> Option<&MemoryReader> {
+        self.memory.as_ref()
+    }
+
+    /// Reads the callbacks, from the handler too.
+    ///
+    /// It waits for other threads to release the lock without blocking,
+    /// and gives up if the calling thread holds it, having been interrupted meanwhile.
+    fn read(&self) -> Option<RwLockReadGuard<'_, HashMap<u32, ThreadCallbacks<R>>>> {
+        loop {
+            match self.callbacks.try_read() {
+                Ok(callbacks) => return Some(callbacks),
+                Err(TryLockError::WouldBlock) if !IS_WRITING.get() => std::thread::yield_now(),
+                Err(_) => return None,
+            }
+        }
+    }
+
+    pub(crate) fn get(&self, thread_id: u32, index: Index) -> Option<Hook<R>> {
+        self.read()?
             .get(&thread_id)
-            .and_then(|callback_list| callback_list.get(index as usize))
-            .and_then(|x| *x)
-    } else {
-        None
+            .and_then(|thread| thread.callbacks.get(index as usize))
+            .and_then(|x| x.clone())
+            .filter(|x| !self.is_removed(thread_id, index, x))
+    }
+
+    pub(crate) fn get_owner(&self, index: Index) -> Option<&'static str> {
+        self.owners.read().ok()?[index as usize]
+    }
+
+    pub(crate) fn set_owner(&self, index: Index, owner: Option<&'static str>) {
+        if let Ok(mut owners) = self.owners.write() {
+            owners[index as usize] = owner;
+        }
+    }
+
+    pub(crate) fn set_applied(&self, thread_id: u32, applied: Applied) {
+        if let Ok(mut lock) = self.applied.write() {
+            lock.insert(thread_id, applied);
+        }
+    }
+
+    pub(crate) fn get_applied_for(&self, thread_id: u32) -> Option<Applied> {
+        self.applied.read().ok()?.get(&thread_id).copied()
+    }
+
+    pub(crate) fn get_applied(&self) -> Vec<(u32, Applied)> {
+        match self.applied.read() {
+            Ok(applied) => applied.iter().map(|(id, x)| (*id, *x)).collect(),
+            Err(_) => Vec::new(),
+        }
+    }
+
+    pub(crate) fn forget_applied(&self, thread_id: u32) {
+        if let Ok(mut lock) = self.applied.write() {
+            lock.remove(&thread_id);
+        }
+    }
+
+    pub(crate) fn is_coalesced(&self, thread_id: u32) -> bool {
+        self.read()
+            .is_some_and(|callbacks| callbacks.get(&thread_id).is_some_and(|x| x.is_coalesced))
+    }
+
+    pub(crate) fn get_write_lock(&self) -> CallbacksWriteGuard<'_, R> {
+        // Flagged beforehand, for the handler may interrupt the thread as soon as it locks.
+        IS_WRITING.set(true);
+        let mut lock = CallbacksWriteGuard {
+            lock: ManuallyDrop::new(
+                self.callbacks
+                    .write()
+                    .expect("Failed to acquire write lock for callbacks"),
+            ),
+        };
+        self.remove_deferred(&mut lock);
+        lock
+    }
+
+    pub(crate) fn get_thread(&self, thread_id: u32) -> Option<ThreadCallbacks<R>> {
+        self.callbacks.read().ok()?.get(&thread_id).cloned()
+    }
+
+    /// Gets the write lock unless another thread holds the lock.
+    pub(crate) fn try_get_write_lock(&self) -> Option<CallbacksWriteGuard<'_, R>> {
+        IS_WRITING.set(true);
+        let Ok(lock) = self.callbacks.try_write() else {
+            IS_WRITING.set(false);
+            return None;
+        };
+
+        let mut lock = CallbacksWriteGuard {
+            lock: ManuallyDrop::new(lock),
+        };
+        self.remove_deferred(&mut lock);
+        Some(lock)
+    }
+
+    /// Removes the callback of a hardware breakpoint of a thread, from the handler.
+    ///
+    /// The thread may have been interrupted while holding the lock,
+    /// or another thread may hold it, so the removal is deferred until the lock is free.
+    /// If too many removals wait already, the callback stays,
+    /// though the backend disables its breakpoint all the same.
+    pub(crate) fn remove(&self, thread_id: u32, index: Index) {
+        if let Ok(mut lock) = self.callbacks.try_write() {
+            if let Some(thread) = lock.get_mut(&thread_id) {
+                thread.callbacks[index as usize] = None;
+            }
+            return;
+        }
+
+        let Some(hook) = self.get(thread_id, index) else {
+            return;
+        };
+        let deferred = self.deferred.iter().find(|x| {
+            x.key
+                .compare_exchange(
+                    0,
+                    DeferredRemoval::FILLING,
+                    Ordering::AcqRel,
+                    Ordering::Acquire,
+                )
+                .is_ok()
+        });
+        if let Some(deferred) = deferred {
+            deferred
+                .filter
+                .store(DeferredRemoval::filter(&hook), Ordering::Release);
+            deferred
+                .key
+                .store(DeferredRemoval::key(thread_id, index), Ordering::Release);
+        }
+    }
+
+    /// Gets whether the removal of a callback is deferred.
+    fn is_removed(&self, thread_id: u32, index: Index, hook: &Hook<R>) -> bool {
+        let key = DeferredRemoval::key(thread_id, index);
+        self.deferred.iter().any(|x| {
+            x.key.load(Ordering::Acquire) == key
+                && x.filter.load(Ordering::Acquire) == DeferredRemoval::filter(hook)
+        })
+    }
+
+    /// Carries out the deferred removals, with the lock taken for writing.
+    fn remove_deferred(&self, callbacks: &mut HashMap<u32, ThreadCallbacks<R>>) {
+        for deferred in &self.deferred {
+            let key = deferred.key.load(Ordering::Acquire);
+            if key == 0 || key == DeferredRemoval::FILLING {
+                continue;
+            }
+
+            let (thread_id, index) = ((key - 1) >> 2, (key - 1) & 0b11);
+            let filter = deferred.filter.load(Ordering::Acquire);
+            if let Some(thread) = callbacks.get_mut(&(thread_id as u32)) {
+                let hook = &mut thread.callbacks[index as usize];
+                if hook.as_ref().map(DeferredRemoval::filter) == Some(filter) {
+                    *hook = None;
+                }
+            }
+            deferred.key.store(0, Ordering::Release);
+        }
+    }
+
+    /// Gets what a thread is single-stepping for, if anything.
+    fn find_stepping(&self, thread_id: u32) -> Option<&Stepping<R>> {
+        self.stepping
+            .iter()
+            .find(|x| x.thread_id.load(Ordering::Acquire) == thread_id)
+    }
+
+    /// Gets what a thread is single-stepping for, taking room for it if need be.
+    fn claim_stepping(&self, thread_id: u32) -> Option<&Stepping<R>> {
+        self.find_stepping(thread_id).or_else(|| {
+            self.stepping.iter().find(|x| {
+                x.thread_id
+                    .compare_exchange(0, thread_id, Ordering::AcqRel, Ordering::Acquire)
+                    .is_ok()
+            })
+        })
+    }
+
+    /// Sets the breakpoints a thread is single-stepping on behalf of.
+    ///
+    /// If too many threads are stepping already, the thread does not step.
+    pub(crate) fn set_stepping(&self, thread_id: u32, stepping: [bool; 4]) {
+        let indices = Index::ALL
+            .into_iter()
+            .filter(|x| stepping[*x as usize])
+            .fold(0, |indices, x| indices | 1 << x as u8);
+        let entry = match indices {
+            0 => self.find_stepping(thread_id),
+            _ => self.claim_stepping(thread_id),
+        };
+        if let Some(entry) = entry {
+            entry.indices.store(indices, Ordering::Release);
+            entry.release_if_done();
+        }
+    }
+
+    pub(crate) fn get_stepping(&self, thread_id: u32) -> [bool; 4] {
+        let indices = self
+            .find_stepping(thread_id)
+            .map_or(0, |x| x.indices.load(Ordering::Acquire));
+        Index::ALL.map(|x| indices & 1 << x as u8 != 0)
+    }
+
+    /// Sets the trace a thread is in.
+    ///
+    /// If too many threads are stepping already, the trace ends.
+    pub(crate) fn set_trace(&self, thread_id: u32, trace: Trace<R>) {
+        if let Some(entry) = self.claim_stepping(thread_id) {
+            if let Ok(mut lock) = entry.trace.try_lock() {
+                *lock = Some(trace);
+            }
+            entry.release_if_done();
+        }
+    }
+
+    pub(crate) fn take_trace(&self, thread_id: u32) -> Option<Trace<R>> {
+        let entry = self.find_stepping(thread_id)?;
+        let trace = entry.trace.try_lock().ok()?.take();
+        entry.release_if_done();
+        trace
+    }
+
+    /// Gets whether a thread is single-stepping on behalf of a breakpoint,
+    /// or is in a trace.
+    ///
+    /// Backends should dispatch single-step traps of such threads,
+    /// and pass on the others.
+    pub fn is_stepping(&self, thread_id: u32) -> bool {
+        self.find_stepping(thread_id)
+            .is_some_and(|x| x.indices.load(Ordering::Acquire) != 0 || x.has_trace())
+    }
+
+    pub(crate) fn set_inherited(&self, callbacks: ThreadCallbacks<R>) {
+        *self
+            .inherited
+            .write()
+            .expect("Failed to acquire write lock for callbacks") = callbacks;
+    }
+
+    /// Gives a newly created thread the callbacks of inherited breakpoints,
+    /// unless it has callbacks already.
+    ///
+    /// Backends should call this method when a thread inherits
+    /// the debug registers set by `Backend::set_inherited_debug_registers`.
+    pub fn inherit(&self, thread_id: u32) {
+        if let Ok(inherited) = self.inherited.read() {
+            self.get_write_lock()
+                .entry(thread_id)
+                .or_insert_with(|| inherited.clone());
+        }
+    }
+
+    /// Gives a newly created thread the callbacks of inherited breakpoints, like `inherit`,
+    /// but from the handler: it neither blocks nor allocates.
+    ///
+    /// # Return value
+    /// Whether the thread has callbacks, false if a lock is taken
+    /// or if there is no room left, see `reserve`.
+    #[cfg(target_os = "linux")]
+    pub(crate) fn try_inherit(&self, thread_id: u32) -> bool {
+        let Ok(inherited) = self.inherited.try_read() else {
+            return false;
+        };
+        let Some(mut lock) = self.try_get_write_lock() else {
+            return false;
+        };
+        if !lock.contains_key(&thread_id) {
+            if lock.len() == lock.capacity() {
+                return false;
+            }
+            lock.insert(thread_id, inherited.clone());
+        }
+
+        true
+    }
+
+    /// Makes room for threads whose callbacks are set from the handler, see `try_inherit`.
+    #[cfg(target_os = "linux")]
+    pub(crate) fn reserve(&self, additional: usize) {
+        self.get_write_lock().reserve(additional);
+    }
+
+    /// Runs the callbacks of the hardware breakpoints reported by `dr6`,
+    /// and of the ones the thread is single-stepping for.
+    ///
+    /// Backends should call this method from their handler,
+    /// with the debug registers and instruction pointer of the thread at the time of the hit,
+    /// and then carry out the returned actions.
+    pub fn dispatch(
+        &self,
+        thread_id: u32,
+        dr6: u64,
+        debug_registers: &DebugRegisters,
+        instruction_pointer: u64,
+        registers: &mut R,
+    ) -> Dispatch {
+        let mut dr6 = DR6::from_bits(dr6);
+        let (actions, is_step) = handler::dispatch(
+            self,
+            thread_id,
+            &mut dr6,
+            debug_registers,
+            instruction_pointer,
+            registers,
+        );
+        let dispatch = Dispatch {
+            dr6: dr6.into_bits(),
+            actions,
+            is_step,
+            is_stepping: self.is_stepping(thread_id),
+        };
+
+        // Breakpoints the backend disables on our behalf are not drifting.
+        // The thread may have been interrupted while holding the lock.
+        if let Ok(mut applied) = self.applied.try_write() {
+            if let Some(applied) = applied.get_mut(&thread_id) {
+                applied.registers.dr7 = dispatch.apply_to_dr7(applied.registers.dr7);
+            }
+        }
+
+        dispatch
     }
 }
 
-pub fn get_write_lock() -> RwLockWriteGuard<'static, HashMap<u32, HWBPCallbackList>> {
-    CALLBACKS
-        .write()
-        .expect("Failed to acquire write lock for callbacks")
+impl<R> Default for CallbackRegistry<R> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{backend::FakeBackend, Backend, Context, Size};
+
+    /// Applies an enabled hardware breakpoint whose callback returns an action
+    /// to the current thread.
+    fn watch(backend: &FakeBackend, action: HitAction) -> (u32, Index, DebugRegisters) {
+        let mut context = Context::current_with(backend).unwrap();
+        let index = context
+            .unused()
+            .unwrap()
+            .watch_memory_write(0x1000 as *const u8, Size::FourBytes, move |_| action)
+            .with_enabled(true)
+            .build_and_set()
+            .unwrap()
+            .get_index();
+        context.apply_for_current_thread().unwrap();
+
+        let thread_id = backend.current_thread_id();
+        let registers = backend.get_debug_registers(thread_id).unwrap();
+        (thread_id, index, registers)
+    }
+
+    /// Dispatches a hit of a hardware breakpoint.
+    fn hit(
+        backend: &FakeBackend,
+        thread_id: u32,
+        index: Index,
+        registers: &DebugRegisters,
+    ) -> Dispatch {
+        let dr6 = 1 << index as u64;
+        backend
+            .callbacks()
+            .dispatch(thread_id, dr6, registers, 0x4000, &mut ())
+    }
+
+    #[test]
+    fn dispatch_continue() {
+        let backend = FakeBackend::new(&[1]);
+        let (thread_id, index, registers) = watch(&backend, HitAction::Continue);
+
+        let dispatch = hit(&backend, thread_id, index, &registers);
+        assert_eq!(dispatch.get_action(index), Some(HitAction::Continue));
+        assert!(dispatch.is_handled());
+        assert_eq!(dispatch.apply_to_dr7(registers.dr7), registers.dr7);
+        assert_eq!(dispatch.apply_to_flags(0) & TRAP_FLAG, 0);
+    }
+
+    #[test]
+    fn dispatch_disable() {
+        let backend = FakeBackend::new(&[1]);
+        let (thread_id, index, registers) = watch(&backend, HitAction::Disable);
+
+        let dispatch = hit(&backend, thread_id, index, &registers);
+        assert_eq!(dispatch.get_action(index), Some(HitAction::Disable));
+        let disabled = DebugRegisters {
+            dr7: dispatch.apply_to_dr7(registers.dr7),
+            ..registers
+        };
+        assert!(!disabled.is_enabled(index));
+        assert!(backend.callbacks().get(thread_id, index).is_some());
+    }
+
+    #[test]
+    fn dispatch_remove() {
+        let backend = FakeBackend::new(&[1]);
+        let (thread_id, index, registers) = watch(&backend, HitAction::Remove);
+
+        let dispatch = hit(&backend, thread_id, index, &registers);
+        assert_eq!(dispatch.get_action(index), Some(HitAction::Remove));
+        let disabled = DebugRegisters {
+            dr7: dispatch.apply_to_dr7(registers.dr7),
+            ..registers
+        };
+        assert!(!disabled.is_enabled(index));
+        assert!(backend.callbacks().get(thread_id, index).is_none());
+    }
+
+    #[test]
+    fn dispatch_remove_deferred() {
+        let backend = FakeBackend::new(&[1]);
+        let (thread_id, index, registers) = watch(&backend, HitAction::Remove);
+        let registry = backend.callbacks();
+
+        // The handler cannot take the lock, so the removal waits for it.
+        let lock = registry.callbacks.read().unwrap();
+        let dispatch = hit(&backend, thread_id, index, &registers);
+        assert_eq!(dispatch.get_action(index), Some(HitAction::Remove));
+        assert!(lock[&thread_id].callbacks[index as usize].is_some());
+        assert!(registry.get(thread_id, index).is_none());
+        drop(lock);
+
+        drop(registry.get_write_lock());
+        assert!(registry.get_thread(thread_id).unwrap().callbacks[index as usize].is_none());
+        assert!(registry
+            .deferred
+            .iter()
+            .all(|x| x.key.load(Ordering::Acquire) == 0));
+    }
+
+    #[test]
+    fn dispatch_single_step() {
+        let backend = FakeBackend::new(&[1]);
+        let (thread_id, index, registers) = watch(&backend, HitAction::SingleStep);
+        let registry = backend.callbacks();
+
+        let dispatch = hit(&backend, thread_id, index, &registers);
+        assert_eq!(dispatch.get_action(index), Some(HitAction::SingleStep));
+        assert!(registry.is_stepping(thread_id));
+        assert_ne!(dispatch.apply_to_flags(0) & TRAP_FLAG, 0);
+
+        // The step runs the callback again, which asks for another one.
+        let step = DR6::new().with_is_single_step(true).into_bits();
+        let dispatch = registry.dispatch(thread_id, step, &registers, 0x4004, &mut ());
+        assert!(dispatch.is_handled());
+        assert_eq!(dispatch.get_action(index), Some(HitAction::SingleStep));
+        assert!(registry.is_stepping(thread_id));
+    }
+
+    #[test]
+    fn dispatch_pass_on_foreign() {
+        let backend = FakeBackend::new(&[1]);
+        let (thread_id, index, registers) = watch(&backend, HitAction::Continue);
+
+        // A hit of a breakpoint that is not ours is passed on.
+        let other = Index::ALL.into_iter().find(|x| *x != index).unwrap();
+        let dispatch = hit(&backend, thread_id, other, &registers);
+        assert!(!dispatch.is_dispatched());
+        assert!(!dispatch.is_handled());
+    }
 }
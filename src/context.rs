@@ -1,110 +1,321 @@
-use windows::Win32::{
-    Foundation::{CloseHandle, HANDLE},
-    System::{
-        Diagnostics::Debug::{GetThreadContext, SetThreadContext, CONTEXT_DEBUG_REGISTERS_AMD64},
-        Threading::{
-            GetCurrentThread, GetCurrentThreadId, GetThreadId, OpenThread, THREAD_GET_CONTEXT,
-            THREAD_SET_CONTEXT,
-        },
-    },
-};
+use std::{sync::Arc, time::Duration};
 
 use crate::{
-    callbacks, threads,
-    windows::{AlignedContext, CONTEXT},
+    audit::{audit, Applied},
+    callbacks::ThreadCallbacks,
+    hwbp::{into_callback, HitFilter, Hook},
+    range::plan_range,
     x86::DR7,
-    ContextError, HWBPBuilder, HWBPCallback, HWBPSlot, Index, HWBP,
+    Auditor, Backend, BuilderError, Condition, ContextError, DebugRegisters, Drift, HWBPBuilder,
+    HWBPSlot, HitAction, HitContext, Index, NativeBackend, Ownership, PageWatch, RangeWatch,
+    SoftwareBreakpoint, WatchStrategy, HWBP,
 };
 
 pub type Result<T> = std::result::Result<T, ContextError>;
 
-/// Represents an X86/AMD64 Windows thread context,
+/// What `Context::apply_for_all_threads_best_effort` did for every thread.
+#[derive(Debug, Default)]
+pub struct ApplyReport {
+    /// The threads the context was applied to.
+    pub applied: Vec<u32>,
+    /// The threads that exited before the context could be applied to them.
+    pub skipped: Vec<u32>,
+    /// The threads the context could not be applied to, and why.
+    pub failed: Vec<(u32, ContextError)>,
+}
+
+impl ApplyReport {
+    /// Gets whether the context was applied to every thread that still exists.
+    pub fn is_complete(&self) -> bool {
+        self.failed.is_empty()
+    }
+}
+
+/// The breakpoints and callbacks of a thread before applying a context to it.
+struct Snapshot<R> {
+    thread_id: u32,
+    registers: DebugRegisters,
+    callbacks: Option<ThreadCallbacks<R>>,
+    applied: Option<Applied>,
+}
+
+/// Represents an X86/AMD64 thread context,
 /// but only the hardware breakpoints.
-#[derive(Debug, Clone, Copy)]
-pub struct Context {
-    hwbps: [HWBP; 4],
+///
+/// The thread is accessed through the backend `B`,
+/// which is the current platform by default.
+#[derive(Debug, Clone)]
+pub struct Context<B: Backend = NativeBackend> {
+    backend: B,
+    thread_id: u32,
+    hwbps: [HWBP<B::Registers>; 4],
+    /// The breakpoints someone else enabled in the thread, see `Ownership::Foreign`.
+    foreign: [bool; 4],
+    is_inherited: bool,
+    is_coalesced: bool,
+    is_verified: bool,
+    strategy: WatchStrategy,
 }
 
 impl Context {
     /// Gets context for the current thread.
     pub fn current() -> Result<Self> {
-        let handle = unsafe { GetCurrentThread() };
-        let thread_id = unsafe { GetCurrentThreadId() };
-        Self::for_handle(handle, Some(thread_id))
+        Self::current_with(NativeBackend::default())
     }
 
     /// Gets context for a specific thread by id.
     pub fn for_thread(thread_id: u32) -> Result<Self> {
-        let handle =
-            unsafe { OpenThread(THREAD_GET_CONTEXT | THREAD_SET_CONTEXT, false, thread_id) }
-                .map_err(ContextError::OpenThreadFailed)?;
+        Self::for_thread_with(NativeBackend::default(), thread_id)
+    }
+}
 
-        let result = Self::for_handle(handle, Some(thread_id));
+impl<B: Backend> Context<B> {
+    /// Gets context for the current thread through a specific backend.
+    pub fn current_with(backend: B) -> Result<Self> {
+        let thread_id = backend.current_thread_id();
+        Self::for_thread_with(backend, thread_id)
+    }
 
-        _ = unsafe { CloseHandle(handle) };
+    /// Gets context for a specific thread by id through a specific backend.
+    ///
+    /// Breakpoints enabled by someone else are foreign, see `get_ownership`,
+    /// and show up as disabled.
+    pub fn for_thread_with(backend: B, thread_id: u32) -> Result<Self> {
+        let registers = backend.get_debug_registers(thread_id)?;
+
+        let callbacks = backend.callbacks();
+        let dr7 = DR7::from_bits(registers.dr7);
+        let [dr0, dr1, dr2, dr3] = registers.address;
+        let mut hwbps: [HWBP<B::Registers>; 4] = [
+            HWBP::from_context(Index::First, &dr7, dr0, callbacks, thread_id),
+            HWBP::from_context(Index::Second, &dr7, dr1, callbacks, thread_id),
+            HWBP::from_context(Index::Third, &dr7, dr2, callbacks, thread_id),
+            HWBP::from_context(Index::Fourth, &dr7, dr3, callbacks, thread_id),
+        ];
 
-        result
+        let foreign = Self::get_foreign(&backend, thread_id, &registers);
+        for (hwbp, _) in hwbps.iter_mut().zip(foreign).filter(|(_, x)| *x) {
+            hwbp.disable();
+        }
+
+        let is_coalesced = callbacks.is_coalesced(thread_id);
+
+        Ok(Self {
+            backend,
+            thread_id,
+            hwbps,
+            foreign,
+            is_inherited: false,
+            is_coalesced,
+            is_verified: false,
+            strategy: WatchStrategy::default(),
+        })
     }
 
-    /// Gets context for a specific thread by handle.
-    fn for_handle(handle: HANDLE, thread_id: Option<u32>) -> Result<Self> {
-        let mut actx = AlignedContext(CONTEXT {
-            ContextFlags: CONTEXT_DEBUG_REGISTERS_AMD64,
-            ..Default::default()
-        });
-        unsafe { GetThreadContext(handle, &mut actx.0) }.map_err(ContextError::GetContextFailed)?;
+    /// Gets the backend the context was obtained through.
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
 
-        let thread_id = thread_id.unwrap_or(unsafe { GetThreadId(handle) });
+    /// Gets whether threads created after applying the context inherit it.
+    pub fn is_inherited(&self) -> bool {
+        self.is_inherited
+    }
 
-        let ctx = &mut actx.0;
-        let dr7 = DR7::from_bits(ctx.Dr7);
-        let hwbps: [HWBP; 4] = [
-            HWBP::from_context(Index::First, &dr7, ctx.Dr0, thread_id),
-            HWBP::from_context(Index::Second, &dr7, ctx.Dr1, thread_id),
-            HWBP::from_context(Index::Third, &dr7, ctx.Dr2, thread_id),
-            HWBP::from_context(Index::Fourth, &dr7, ctx.Dr3, thread_id),
-        ];
+    /// Sets whether threads created after applying the context inherit it,
+    /// breakpoints and callbacks alike.
+    ///
+    /// The inherited breakpoints are process-wide, so applying another
+    /// inherited context replaces them, and applying one without
+    /// enabled breakpoints stops the inheritance.
+    ///
+    /// On Linux (`PerfBackend`), only the threads created by the threads
+    /// the context was applied to inherit it.
+    pub fn set_inherited(&mut self, is_inherited: bool) {
+        self.is_inherited = is_inherited;
+    }
+
+    /// Gets whether hardware breakpoints hit together get a single callback.
+    pub fn is_coalesced(&self) -> bool {
+        self.is_coalesced
+    }
+
+    /// Sets whether hardware breakpoints hit together,
+    /// by the same instruction, get a single callback.
+    ///
+    /// Only the callback of the breakpoint with the lowest index is called,
+    /// see `HitContext::is_hit` for the others,
+    /// and the action it returns applies to all of them.
+    /// Otherwise, every callback is called in the order of the indices.
+    pub fn set_coalesced(&mut self, is_coalesced: bool) {
+        self.is_coalesced = is_coalesced;
+    }
+
+    /// Gets whether applying the context reads the debug registers back.
+    pub fn is_verified(&self) -> bool {
+        self.is_verified
+    }
+
+    /// Sets whether applying the context reads the debug registers back,
+    /// failing with `ContextError::VerificationFailed` if they do not match,
+    /// as when a hypervisor ignores or resets them.
+    ///
+    /// On Linux (`PerfBackend`), the debug registers cannot be read from user mode,
+    /// so what is read back is what the backend armed, which always matches.
+    pub fn set_verified(&mut self, is_verified: bool) {
+        self.is_verified = is_verified;
+    }
+
+    /// Gets how watches are armed by default.
+    pub fn get_strategy(&self) -> WatchStrategy {
+        self.strategy
+    }
 
-        Ok(Self { hwbps })
+    /// Sets how watches are armed by default,
+    /// by `watch_range` and by the builders from `builder`.
+    pub fn set_strategy(&mut self, strategy: WatchStrategy) {
+        self.strategy = strategy;
     }
 }
 
-impl Context {
-    /// Gets a builder for an unused hardware breakpoint.
+impl<B: Backend> Context<B> {
+    /// Gets who a hardware breakpoint belongs to, as of when the context was obtained.
+    ///
+    /// Breakpoints enabled in the thread, locally or globally, without a callback of ours
+    /// are foreign. They are never handed out, and applying the context leaves them as they are.
+    pub fn get_ownership(&self, index: Index) -> Ownership {
+        if self.foreign[index as usize] {
+            Ownership::Foreign
+        } else if self.hwbps[index as usize].is_enabled() {
+            Ownership::Ours
+        } else {
+            Ownership::Free
+        }
+    }
+
+    /// Gets the owner tag a hardware breakpoint is reserved for, if any.
+    pub fn get_owner(&self, index: Index) -> Option<&'static str> {
+        self.backend.callbacks().get_owner(index)
+    }
+
+    /// Reserves a hardware breakpoint for an owner tag, in every thread,
+    /// so that only `unused_for` with the same tag hands it out.
+    ///
+    /// Fails if it is foreign or reserved for another tag.
+    pub fn reserve(&self, index: Index, owner: &'static str) -> Result<()> {
+        if self.foreign[index as usize] {
+            return Err(ContextError::ForeignSlot {
+                thread_id: self.thread_id,
+                index,
+            });
+        }
+
+        match self.get_owner(index) {
+            Some(other) if other != owner => Err(ContextError::SlotReserved {
+                index,
+                owner: other,
+            }),
+            _ => {
+                self.backend.callbacks().set_owner(index, Some(owner));
+                Ok(())
+            }
+        }
+    }
+
+    /// Releases a hardware breakpoint reserved by `reserve`.
+    pub fn release(&self, index: Index) {
+        self.backend.callbacks().set_owner(index, None);
+    }
+
+    /// Gets whether a hardware breakpoint is free for an owner tag,
+    /// or for anyone if `None`: neither enabled, foreign nor reserved for another tag.
+    pub(crate) fn is_available(&self, index: Index, owner: Option<&str>) -> bool {
+        self.get_ownership(index) == Ownership::Free
+            && self.get_owner(index).is_none_or(|x| Some(x) == owner)
+    }
+
+    /// Gets the first available hardware breakpoint, see `is_available`,
+    /// preferring the ones reserved for the owner tag.
+    pub(crate) fn find_unused(&self, owner: Option<&str>) -> Option<Index> {
+        let reserved = Index::ALL
+            .into_iter()
+            .filter(|x| owner.is_some() && self.get_owner(*x) == owner);
+        reserved
+            .chain(Index::ALL)
+            .find(|x| self.is_available(*x, owner))
+    }
+
+    /// Gets which breakpoints of a thread are foreign, see `get_ownership`.
+    pub(crate) fn get_foreign(
+        backend: &B,
+        thread_id: u32,
+        registers: &DebugRegisters,
+    ) -> [bool; 4] {
+        Index::ALL.map(|index| {
+            registers.is_enabled(index) && backend.callbacks().get(thread_id, index).is_none()
+        })
+    }
+}
+
+impl<B: Backend> Context<B> {
+    /// Gets a builder for an unused hardware breakpoint,
+    /// one that is neither enabled, foreign nor reserved, see `get_ownership`.
     ///
     /// Returns `None` if there are no unused hardware breakpoints.
     #[allow(clippy::manual_map)]
-    pub fn unused(&mut self) -> Option<HWBPBuilder> {
-        if let Some(hwbp) = self.hwbps.iter().find(|hwbp| !hwbp.is_enabled()) {
-            Some(HWBPBuilder::new(self, hwbp.get_index()))
+    pub fn unused(&mut self) -> Option<HWBPBuilder<'_, B>> {
+        if let Some(index) = self.find_unused(None) {
+            Some(HWBPBuilder::new(self, Some(index)))
         } else {
             None
         }
     }
 
+    /// Gets a builder for an unused hardware breakpoint reserved for an owner tag,
+    /// or else one that is not reserved at all, see `reserve`.
+    ///
+    /// Returns `None` if there are no such hardware breakpoints.
+    pub fn unused_for(&mut self, owner: &'static str) -> Option<HWBPBuilder<'_, B>> {
+        let index = self.find_unused(Some(owner))?;
+        Some(HWBPBuilder::new(self, Some(index)).with_owner(owner))
+    }
+
+    /// Gets a builder for an unused hardware breakpoint, if there is one,
+    /// which can also arm the watch by protecting pages or patching code,
+    /// see `HWBPBuilder::build_and_watch`.
+    pub fn builder(&mut self) -> HWBPBuilder<'_, B> {
+        let index = self.find_unused(None);
+        HWBPBuilder::new(self, index)
+    }
+
+    /// Gets a hardware breakpoint by index.
+    pub fn get(&self, index: Index) -> HWBP<B::Registers> {
+        self.hwbps[index as usize].clone()
+    }
+
     /// Gets the first hardware breakpoint.
-    pub fn first(&self) -> HWBP {
-        self.hwbps[0]
+    pub fn first(&self) -> HWBP<B::Registers> {
+        self.hwbps[0].clone()
     }
 
     /// Gets the second hardware breakpoint.
-    pub fn second(&self) -> HWBP {
-        self.hwbps[1]
+    pub fn second(&self) -> HWBP<B::Registers> {
+        self.hwbps[1].clone()
     }
 
     /// Gets the third hardware breakpoint.
-    pub fn third(&self) -> HWBP {
-        self.hwbps[2]
+    pub fn third(&self) -> HWBP<B::Registers> {
+        self.hwbps[2].clone()
     }
 
     /// Gets the fourth hardware breakpoint.
-    pub fn fourth(&self) -> HWBP {
-        self.hwbps[3]
+    pub fn fourth(&self) -> HWBP<B::Registers> {
+        self.hwbps[3].clone()
     }
 
     /// Sets a hardware breakpoint.
-    pub fn set(&mut self, hwbp: &HWBP) {
-        self.hwbps[hwbp.get_index() as usize] = *hwbp;
+    pub fn set(&mut self, hwbp: &HWBP<B::Registers>) {
+        self.hwbps[hwbp.get_index() as usize] = hwbp.clone();
     }
 
     /// Disables all hardware breakpoints.
@@ -114,79 +325,408 @@ impl Context {
         }
     }
 
+    /// Watches `len` bytes at `address` for a condition,
+    /// with as many unused hardware breakpoints as it takes, see `plan_range`.
+    ///
+    /// The hardware breakpoints are set and enabled,
+    /// but like any other only take effect once applied.
+    /// If there are not enough of them, the strategy of the context
+    /// decides whether to protect pages instead, which takes effect immediately.
+    pub fn watch_range<A: Into<HitAction>>(
+        &mut self,
+        address: u64,
+        len: u64,
+        condition: Condition,
+        callback: impl Fn(&mut HitContext<'_, B::Registers>) -> A + Send + Sync + 'static,
+    ) -> std::result::Result<RangeWatch<B::Registers>, BuilderError> {
+        if condition == Condition::Execute {
+            return Err(BuilderError::UnsupportedCondition(condition));
+        }
+        if len == 0 {
+            return Err(BuilderError::EmptyRange);
+        }
+
+        let hook = Hook {
+            callback: into_callback(callback),
+            filter: Arc::new(HitFilter::default()),
+            arm: None,
+        };
+
+        let plan = plan_range(address, len).ok_or(BuilderError::RangeOverflow { address, len })?;
+        let unused = Index::ALL
+            .into_iter()
+            .filter(|x| self.is_available(*x, None))
+            .collect::<Vec<_>>();
+        let is_paging = match self.strategy {
+            WatchStrategy::Hardware => false,
+            WatchStrategy::Paging => true,
+            WatchStrategy::Software => return Err(BuilderError::UnsupportedCondition(condition)),
+            WatchStrategy::Fallback => plan.len() > unused.len(),
+        };
+        if is_paging {
+            let page = self.watch_pages(address, len, condition, hook)?;
+            return Ok(RangeWatch::with_pages(page));
+        }
+        if plan.len() > unused.len() {
+            return Err(BuilderError::NotEnoughSlots {
+                needed: plan.len(),
+                free: unused.len(),
+            });
+        }
+
+        let slots = plan
+            .into_iter()
+            .map(|(address, size)| HWBPSlot {
+                is_enabled: true,
+                address,
+                condition,
+                size,
+            })
+            .collect::<Vec<_>>();
+        for slot in &slots {
+            self.validate_slot(slot)?;
+        }
+
+        let hwbps = slots
+            .into_iter()
+            .zip(unused)
+            .map(|(slot, index)| self.build_and_set_hwbp(index, slot, hook.clone()))
+            .collect();
+
+        Ok(RangeWatch::new(address, len, hwbps))
+    }
+
+    /// Sets the hardware breakpoints of a range.
+    pub fn set_range(&mut self, range: &RangeWatch<B::Registers>) {
+        for hwbp in range.get_hwbps() {
+            self.set(hwbp);
+        }
+    }
+
+    /// Checks that a slot can be set, see `HWBPBuilder::with_validation`.
+    pub(crate) fn validate_slot(&self, slot: &HWBPSlot) -> std::result::Result<(), BuilderError> {
+        slot.validate_for(&self.backend)
+    }
+
+    /// Watches memory by protecting its pages, see `PageWatcher`.
+    pub(crate) fn watch_pages(
+        &self,
+        address: u64,
+        len: u64,
+        condition: Condition,
+        hook: Hook<B::Registers>,
+    ) -> std::result::Result<PageWatch<B::Registers>, BuilderError> {
+        if !matches!(condition, Condition::Write | Condition::ReadWrite) {
+            return Err(BuilderError::UnsupportedCondition(condition));
+        }
+
+        let watcher = self
+            .backend
+            .page_watcher()
+            .ok_or(BuilderError::PagingUnsupported)?;
+        watcher
+            .watch(address, len, condition, hook)
+            .map_err(BuilderError::ProtectionFailed)
+    }
+
+    /// Sets an execute breakpoint by patching code, see `SoftwareBreakpoints`.
+    pub(crate) fn set_software_breakpoint(
+        &self,
+        address: u64,
+        hook: Hook<B::Registers>,
+    ) -> std::result::Result<SoftwareBreakpoint<B::Registers>, BuilderError> {
+        let breakpoints = self
+            .backend
+            .software_breakpoints()
+            .ok_or(BuilderError::SoftwareUnsupported)?;
+        breakpoints
+            .insert(address, hook)
+            .map_err(BuilderError::PatchFailed)
+    }
+
     pub(crate) fn build_and_set_hwbp(
         &mut self,
         index: Index,
         slot: HWBPSlot,
-        callback: HWBPCallback,
-    ) -> HWBP {
+        hook: Hook<B::Registers>,
+    ) -> HWBP<B::Registers> {
         let idx = index as usize;
-        self.hwbps[idx].set(slot, callback);
-        self.hwbps[idx]
+        self.hwbps[idx].set(slot, hook);
+        self.hwbps[idx].clone()
     }
 }
 
-impl Context {
+impl<B: Backend> Context<B> {
     /// Applies the context (breakpoints only) to all existing threads.
+    ///
+    /// All or nothing: if it fails for a thread, the threads it was applied to so far
+    /// are rolled back to their previous breakpoints and callbacks.
+    /// Threads that exit in the meantime are skipped.
     pub fn apply_for_all_threads(&self) -> Result<()> {
-        threads::enumerate(|id| {
-            self.apply_for_thread(id).unwrap();
-            Ok(())
-        })
-        .map_err(|x| match x {
-            threads::EnumerateError::WindowsError(e) => ContextError::EnumeratingThreadsFailed(e),
-            threads::EnumerateError::UserError(e) => e,
-        })?;
+        let mut snapshots = Vec::new();
+        for thread_id in self.backend.enumerate_threads()? {
+            let snapshot = match self.snapshot(thread_id) {
+                Ok(snapshot) => snapshot,
+                Err(_) if Self::has_exited(&self.backend, thread_id) => continue,
+                Err(error) => {
+                    self.rollback(snapshots);
+                    return Err(ContextError::ThreadFailed {
+                        thread_id,
+                        error: Box::new(error),
+                    });
+                }
+            };
+
+            // The registers may have been partially written, so it is rolled back too.
+            snapshots.push(snapshot);
+            match self.apply_registers(thread_id) {
+                Ok(()) => {}
+                Err(_) if Self::has_exited(&self.backend, thread_id) => {}
+                Err(error) => {
+                    self.rollback(snapshots);
+                    return Err(ContextError::ThreadFailed {
+                        thread_id,
+                        error: Box::new(error),
+                    });
+                }
+            }
+        }
+
+        if let Err(error) = self.apply_inherited() {
+            self.rollback(snapshots);
+            return Err(error);
+        }
 
         Ok(())
     }
 
+    /// Applies the context (breakpoints only) to all existing threads,
+    /// as many as it can.
+    ///
+    /// # Return value
+    /// The threads it was applied to, the ones that exited in the meantime,
+    /// and the ones it failed for, which are left as they are.
+    /// Only fails if the threads cannot be enumerated, or inherited.
+    pub fn apply_for_all_threads_best_effort(&self) -> Result<ApplyReport> {
+        let mut report = ApplyReport::default();
+        for thread_id in self.backend.enumerate_threads()? {
+            match self.apply_registers(thread_id) {
+                Ok(()) => report.applied.push(thread_id),
+                Err(_) if Self::has_exited(&self.backend, thread_id) => {
+                    report.skipped.push(thread_id)
+                }
+                Err(error) => report.failed.push((thread_id, error)),
+            }
+        }
+
+        self.apply_inherited()?;
+        Ok(report)
+    }
+
     /// Applies the context (breakpoints only) to the current thread.
     pub fn apply_for_current_thread(&self) -> Result<()> {
-        let handle = unsafe { GetCurrentThread() };
-        let id = unsafe { GetCurrentThreadId() };
-
-        self.apply_for_handle(handle, Some(id))
+        self.apply_for_thread(self.backend.current_thread_id())
     }
 
     /// Applies the context (breakpoints only) to a specific thread by id.
+    ///
+    /// Foreign breakpoints of the thread are left as they are, see `get_ownership`,
+    /// and enabling a breakpoint in their place fails.
     pub fn apply_for_thread(&self, thread_id: u32) -> Result<()> {
-        let handle =
-            unsafe { OpenThread(THREAD_GET_CONTEXT | THREAD_SET_CONTEXT, false, thread_id) }
-                .map_err(ContextError::GetContextFailed)?;
+        self.apply_inherited()?;
+        self.apply_registers(thread_id)
+    }
 
-        self.apply_for_handle(handle, Some(thread_id))?;
+    /// Gets the debug registers describing the breakpoints of the context.
+    fn get_registers(&self) -> DebugRegisters {
+        let mut registers = DebugRegisters::default();
 
-        _ = unsafe { CloseHandle(handle) };
-        Ok(())
+        let mut dr7 = DR7::from_bits(registers.dr7);
+        for (hwbp, drn) in self.hwbps.iter().zip(registers.address.iter_mut()) {
+            hwbp.apply_to_context(drn, &mut dr7);
+        }
+        registers.dr7 = dr7.into_bits();
+        registers
     }
 
-    /// Applies the context (breakpoints only) to a specific thread by handle.
-    fn apply_for_handle(&self, handle: HANDLE, thread_id: Option<u32>) -> Result<()> {
-        let mut actx = AlignedContext(CONTEXT {
-            ContextFlags: CONTEXT_DEBUG_REGISTERS_AMD64,
-            ..Default::default()
-        });
+    /// Gets the callbacks of the context, but for the foreign breakpoints,
+    /// whose hits are left to their owners.
+    fn get_callbacks(&self, foreign: [bool; 4]) -> ThreadCallbacks<B::Registers> {
+        ThreadCallbacks {
+            callbacks: Index::ALL.map(|index| match foreign[index as usize] {
+                true => None,
+                false => self.hwbps[index as usize].get_hook(),
+            }),
+            is_coalesced: self.is_coalesced,
+        }
+    }
 
-        let thread_id = thread_id.unwrap_or(unsafe { GetThreadId(handle) });
+    /// Makes threads created from now on inherit the context, if it is inherited.
+    fn apply_inherited(&self) -> Result<()> {
+        if self.is_inherited {
+            let registers = self.get_registers();
+            let callbacks = self.get_callbacks([false; 4]);
+            self.backend.callbacks().set_inherited(callbacks);
+            self.backend.set_inherited_debug_registers(&registers)?;
+        }
 
-        let ctx = &mut actx.0;
-        let mut dr7 = DR7::from_bits(ctx.Dr7);
-        self.hwbps[0].apply_to_context(&mut ctx.Dr0, &mut dr7);
-        self.hwbps[1].apply_to_context(&mut ctx.Dr1, &mut dr7);
-        self.hwbps[2].apply_to_context(&mut ctx.Dr2, &mut dr7);
-        self.hwbps[3].apply_to_context(&mut ctx.Dr3, &mut dr7);
-        ctx.Dr7 = dr7.into_bits();
+        Ok(())
+    }
 
-        let mut write_lock = callbacks::get_write_lock();
-        let value = write_lock.entry(thread_id).or_default();
-        value[0] = self.hwbps[0].get_callback();
-        value[1] = self.hwbps[1].get_callback();
-        value[2] = self.hwbps[2].get_callback();
-        value[3] = self.hwbps[3].get_callback();
+    /// Applies the breakpoints and callbacks of the context to a thread.
+    fn apply_registers(&self, thread_id: u32) -> Result<()> {
+        let mut registers = self.get_registers();
+        let current = self.backend.get_debug_registers(thread_id)?;
+        let foreign = Self::get_foreign(&self.backend, thread_id, &current);
+        for index in Index::ALL.into_iter().filter(|x| foreign[*x as usize]) {
+            if self.hwbps[index as usize].is_enabled() {
+                return Err(ContextError::ForeignSlot { thread_id, index });
+            }
+            registers.copy_slot(&current, index);
+        }
 
-        unsafe { SetThreadContext(handle, ctx) }.map_err(ContextError::SetContextFailed)?;
+        Self::swap(
+            &self.backend,
+            thread_id,
+            &registers,
+            Some(self.get_callbacks(foreign)),
+        )?;
+
+        let applied = Applied {
+            registers,
+            is_ours: foreign.map(|x| !x),
+        };
+        self.backend.callbacks().set_applied(thread_id, applied);
+
+        if self.is_verified {
+            let actual = self.backend.get_debug_registers(thread_id)?;
+            let mismatches = applied.diff(&actual);
+            if !mismatches.is_empty() {
+                return Err(ContextError::VerificationFailed {
+                    thread_id,
+                    mismatches,
+                });
+            }
+        }
 
         Ok(())
     }
+
+    /// Swaps in the callbacks and debug registers of a thread, or removes its callbacks,
+    /// with the thread suspended so that its handler sees either all or none of them.
+    ///
+    /// Hooks are armed beforehand, see `Hook::arm`.
+    /// The calling thread is never suspended.
+    /// Another thread may be suspended while holding the lock of the callbacks,
+    /// in which case it is resumed until it releases it, instead of waiting on it forever.
+    /// Nothing is allocated or freed meanwhile, in case it holds the lock of the heap too.
+    pub(crate) fn swap(
+        backend: &B,
+        thread_id: u32,
+        registers: &DebugRegisters,
+        callbacks: Option<ThreadCallbacks<B::Registers>>,
+    ) -> Result<()> {
+        let registry = backend.callbacks();
+        // Callbacks keeping a copy of the watched memory take it now.
+        for hook in callbacks.iter().flat_map(|x| x.callbacks.iter().flatten()) {
+            hook.arm(registry.memory());
+        }
+
+        if thread_id == backend.current_thread_id() {
+            let mut lock = registry.get_write_lock();
+            match callbacks {
+                Some(callbacks) => lock.insert(thread_id, callbacks),
+                None => lock.remove(&thread_id),
+            };
+            drop(lock);
+            return backend.set_debug_registers(thread_id, registers);
+        }
+
+        // Make room beforehand, so that swapping the callbacks does not allocate.
+        if callbacks.is_some() {
+            registry.get_write_lock().entry(thread_id).or_default();
+        }
+
+        let mut lock = loop {
+            backend.suspend_thread(thread_id)?;
+            if let Some(lock) = registry.try_get_write_lock() {
+                break lock;
+            }
+
+            backend.resume_thread(thread_id)?;
+            std::thread::yield_now();
+        };
+        let previous = match callbacks {
+            Some(callbacks) => lock.insert(thread_id, callbacks),
+            None => lock.remove(&thread_id),
+        };
+        drop(lock);
+
+        let result = backend.set_debug_registers(thread_id, registers);
+        let resumed = backend.resume_thread(thread_id);
+        drop(previous);
+        result.and(resumed)
+    }
+
+    /// Takes what rolling back a thread takes, before applying the context to it.
+    fn snapshot(&self, thread_id: u32) -> Result<Snapshot<B::Registers>> {
+        let callbacks = self.backend.callbacks();
+        Ok(Snapshot {
+            thread_id,
+            registers: self.backend.get_debug_registers(thread_id)?,
+            callbacks: callbacks.get_thread(thread_id),
+            applied: callbacks.get_applied_for(thread_id),
+        })
+    }
+
+    /// Rolls threads back to their snapshots, the last one first.
+    ///
+    /// This is best effort, threads that cannot be rolled back are left as they are.
+    fn rollback(&self, snapshots: Vec<Snapshot<B::Registers>>) {
+        let callbacks = self.backend.callbacks();
+        for snapshot in snapshots.into_iter().rev() {
+            _ = Self::swap(
+                &self.backend,
+                snapshot.thread_id,
+                &snapshot.registers,
+                snapshot.callbacks,
+            );
+            match snapshot.applied {
+                Some(applied) => callbacks.set_applied(snapshot.thread_id, applied),
+                None => callbacks.forget_applied(snapshot.thread_id),
+            }
+        }
+    }
+
+    /// Gets whether a thread exited, which is why applying something to it failed.
+    pub(crate) fn has_exited(backend: &B, thread_id: u32) -> bool {
+        backend
+            .enumerate_threads()
+            .is_ok_and(|threads| !threads.contains(&thread_id))
+    }
+
+    /// Reads back the debug registers of every thread a context was applied to,
+    /// and reports the ones that drifted away from what was last applied to them,
+    /// as when a hypervisor or a security product resets them.
+    ///
+    /// Foreign breakpoints are not audited, see `get_ownership`,
+    /// and neither are threads that inherited their breakpoints.
+    pub fn audit(&self) -> Vec<Drift> {
+        audit(&self.backend)
+    }
+
+    /// Audits the debug registers every `interval` on a background thread,
+    /// calling `callback` for every thread that drifted, see `audit`.
+    ///
+    /// The audits go on until the returned `Auditor` is dropped.
+    pub fn audit_in_background(
+        &self,
+        interval: Duration,
+        callback: impl Fn(&Drift) + Send + 'static,
+    ) -> Auditor
+    where
+        B: Clone + Send + 'static,
+    {
+        Auditor::spawn(self.backend.clone(), interval, callback)
+    }
 }
@@ -1,16 +1,46 @@
-use crate::windows::Error as WindowsError;
 use thiserror::Error;
 
+use crate::{BreakpointId, Condition, Index, Size, SlotMismatch};
+
+#[cfg(windows)]
+pub use crate::windows::Error as OsError;
+
+#[cfg(target_os = "linux")]
+pub use crate::linux::Error as OsError;
+
 #[derive(Error, Debug)]
 pub enum ContextError {
     #[error("Failed to open thread: {0}")]
-    OpenThreadFailed(WindowsError),
+    OpenThreadFailed(OsError),
     #[error("Failed to get context: {0}")]
-    GetContextFailed(WindowsError),
+    GetContextFailed(OsError),
     #[error("Failed to set context: {0}")]
-    SetContextFailed(WindowsError),
+    SetContextFailed(OsError),
     #[error("Error enumerating threads: {0}")]
-    EnumeratingThreadsFailed(WindowsError),
+    EnumeratingThreadsFailed(OsError),
+    #[error("Timed out suspending thread {thread_id}")]
+    SuspendTimedOut { thread_id: u32 },
+    #[error("Backend error: {0}")]
+    BackendError(Box<dyn std::error::Error + Send + Sync>),
+    #[error("Hardware breakpoint {index:?} of thread {thread_id} belongs to someone else")]
+    ForeignSlot { thread_id: u32, index: Index },
+    #[error("Hardware breakpoint {index:?} is reserved for {owner:?}")]
+    SlotReserved { index: Index, owner: &'static str },
+    #[error("Debug registers of thread {thread_id} do not match what was applied: {mismatches:?}")]
+    VerificationFailed {
+        thread_id: u32,
+        mismatches: Vec<SlotMismatch>,
+    },
+    #[error("No unused hardware breakpoint in thread {thread_id}")]
+    NoUnusedSlot { thread_id: u32 },
+    #[error("No breakpoint with id {0:?}")]
+    UnknownBreakpoint(BreakpointId),
+    #[error("Failed to apply to thread {thread_id}: {error}")]
+    ThreadFailed {
+        thread_id: u32,
+        #[source]
+        error: Box<ContextError>,
+    },
 }
 
 #[derive(Error, Debug)]
@@ -23,4 +53,34 @@ pub enum BuilderError {
     SizeNotSet,
     #[error("Callback is not set")]
     CallbackNotSet,
+    #[error("Range is empty")]
+    EmptyRange,
+    #[error("Range of {len} bytes at {address:#x} goes past the end of the address space")]
+    RangeOverflow { address: u64, len: u64 },
+    #[error("Range needs {needed} hardware breakpoints, but only {free} are free")]
+    NotEnoughSlots { needed: usize, free: usize },
+    #[error("Condition {0:?} is not supported")]
+    UnsupportedCondition(Condition),
+    #[error("Execute breakpoints must be one byte, not {0:?}")]
+    InvalidExecuteSize(Size),
+    #[error("Address {address:#x} is not aligned to {size:?}")]
+    Misaligned { address: u64, size: Size },
+    #[error("Address {0:#x} is not canonical")]
+    NonCanonicalAddress(u64),
+    #[error("Address {0:#x} is in kernel space")]
+    KernelAddress(u64),
+    #[error("Address {0:#x} is not mapped")]
+    UnmappedAddress(u64),
+    #[error("No unused hardware breakpoint")]
+    NoUnusedSlot,
+    #[error("Page watches are not supported by this backend")]
+    PagingUnsupported,
+    #[error("Failed to protect pages: {0}")]
+    ProtectionFailed(std::io::Error),
+    #[error("Software breakpoints are not supported by this backend")]
+    SoftwareUnsupported,
+    #[error("Failed to patch code: {0}")]
+    PatchFailed(std::io::Error),
+    #[error("Failed to apply: {0}")]
+    ApplyFailed(ContextError),
 }
@@ -0,0 +1,240 @@
+use std::{fmt, marker::PhantomData};
+
+use crate::{
+    hwbp::Hook, x86::DR7, Backend, Context, ContextError, DebugRegisters, Index, NativeBackend,
+    HWBP,
+};
+
+type Result<T> = std::result::Result<T, ContextError>;
+
+/// What the hardware breakpoint of a guard held in a thread before the guard was applied to it.
+struct Previous<R> {
+    thread_id: u32,
+    registers: DebugRegisters,
+    hook: Option<Hook<R>>,
+}
+
+/// A hardware breakpoint that, when dropped, puts back whatever its hardware breakpoint held
+/// before in every thread it was applied to, see `HWBPBuilder::build_guarded`.
+///
+/// Unlike a context, applying a guard only changes its own hardware breakpoint.
+/// Like a context, it fails for threads where it is foreign, or where it is reserved
+/// for another owner tag than the one it was handed out for, unless taking them over
+/// is allowed with `set_takeover`, since they are put back afterwards.
+/// Threads where another breakpoint took it over in the meantime are left as they are.
+///
+/// Built by `HWBPBuilder::watch_guarded`, it borrows the watched variable,
+/// so that it cannot outlive it.
+pub struct HWBPGuard<'a, B: Backend = NativeBackend> {
+    backend: B,
+    hwbp: HWBP<B::Registers>,
+    hook: Hook<B::Registers>,
+    previous: Vec<Previous<B::Registers>>,
+    /// The owner tag the hardware breakpoint was handed out for, see `Context::unused_for`.
+    owner: Option<&'static str>,
+    is_takeover: bool,
+    variable: PhantomData<&'a ()>,
+}
+
+impl<B: Backend> fmt::Debug for HWBPGuard<'_, B> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HWBPGuard")
+            .field("hwbp", &self.hwbp)
+            .field("threads", &self.get_threads())
+            .finish_non_exhaustive()
+    }
+}
+
+impl<B: Backend> HWBPGuard<'_, B> {
+    pub(crate) fn new(
+        backend: B,
+        hwbp: HWBP<B::Registers>,
+        hook: Hook<B::Registers>,
+        owner: Option<&'static str>,
+    ) -> Self {
+        Self {
+            backend,
+            hwbp,
+            hook,
+            previous: Vec::new(),
+            owner,
+            is_takeover: false,
+            variable: PhantomData,
+        }
+    }
+
+    /// Gets the hardware breakpoint of the guard.
+    pub fn get_hwbp(&self) -> &HWBP<B::Registers> {
+        &self.hwbp
+    }
+
+    /// Gets the index of the hardware breakpoint.
+    pub fn get_index(&self) -> Index {
+        self.hwbp.get_index()
+    }
+
+    /// Gets how many times the hardware breakpoint was hit, in every thread.
+    pub fn get_hit_count(&self) -> u64 {
+        self.hwbp.get_hit_count()
+    }
+
+    /// Gets whether applying the guard takes over its hardware breakpoint
+    /// where it is foreign or reserved for another owner tag.
+    pub fn is_takeover(&self) -> bool {
+        self.is_takeover
+    }
+
+    /// Sets whether applying the guard takes over its hardware breakpoint
+    /// where it is foreign or reserved for another owner tag,
+    /// instead of failing with `ContextError::ForeignSlot` or `ContextError::SlotReserved`.
+    pub fn set_takeover(&mut self, is_takeover: bool) {
+        self.is_takeover = is_takeover;
+    }
+
+    /// Gets the threads the guard was applied to, which it restores when dropped.
+    pub fn get_threads(&self) -> Vec<u32> {
+        self.previous.iter().map(|x| x.thread_id).collect()
+    }
+
+    /// Applies the hardware breakpoint to all existing threads.
+    ///
+    /// All or nothing: if it fails for a thread, the threads it was applied to
+    /// by this call are restored. Threads that exit in the meantime are skipped.
+    pub fn apply_for_all_threads(&mut self) -> Result<()> {
+        let len = self.previous.len();
+        for thread_id in self.backend.enumerate_threads()? {
+            match self.apply_for_thread(thread_id) {
+                Ok(()) => {}
+                Err(_) if Context::has_exited(&self.backend, thread_id) => {}
+                Err(error) => {
+                    let applied = self.previous.split_off(len);
+                    for previous in applied.iter().rev() {
+                        _ = self.restore_thread(previous);
+                    }
+                    return Err(ContextError::ThreadFailed {
+                        thread_id,
+                        error: Box::new(error),
+                    });
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Applies the hardware breakpoint to the current thread.
+    pub fn apply_for_current_thread(&mut self) -> Result<()> {
+        self.apply_for_thread(self.backend.current_thread_id())
+    }
+
+    /// Applies the hardware breakpoint to a specific thread by id.
+    pub fn apply_for_thread(&mut self, thread_id: u32) -> Result<()> {
+        let index = self.hwbp.get_index();
+        let callbacks = self.backend.callbacks();
+
+        let current = self.backend.get_debug_registers(thread_id)?;
+        if !self.is_takeover {
+            if Context::get_foreign(&self.backend, thread_id, &current)[index as usize] {
+                return Err(ContextError::ForeignSlot { thread_id, index });
+            }
+            if let Some(owner) = callbacks
+                .get_owner(index)
+                .filter(|x| Some(*x) != self.owner)
+            {
+                return Err(ContextError::SlotReserved { index, owner });
+            }
+        }
+
+        let mut ours = DebugRegisters::default();
+        let mut dr7 = DR7::from_bits(ours.dr7);
+        self.hwbp
+            .apply_to_context(&mut ours.address[index as usize], &mut dr7);
+        ours.dr7 = dr7.into_bits();
+        let mut registers = current;
+        registers.copy_slot(&ours, index);
+
+        let mut thread = callbacks.get_thread(thread_id).unwrap_or_default();
+        let hook = thread.callbacks[index as usize].replace(self.hook.clone());
+        Context::swap(&self.backend, thread_id, &registers, Some(thread))?;
+        if let Some(mut applied) = callbacks.get_applied_for(thread_id) {
+            applied.registers.copy_slot(&registers, index);
+            applied.is_ours[index as usize] = true;
+            callbacks.set_applied(thread_id, applied);
+        }
+
+        // Applying again keeps what the thread held before the first time.
+        if !self.previous.iter().any(|x| x.thread_id == thread_id) {
+            self.previous.push(Previous {
+                thread_id,
+                registers: current,
+                hook,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Restores every thread the guard was applied to, the last one first,
+    /// which dropping it does too, but ignoring errors.
+    ///
+    /// Threads that exited are skipped. If it fails for a thread, it goes on with the others.
+    pub fn restore(mut self) -> Result<()> {
+        self.restore_all()
+    }
+
+    fn restore_all(&mut self) -> Result<()> {
+        let mut result = Ok(());
+        for previous in std::mem::take(&mut self.previous).iter().rev() {
+            match self.restore_thread(previous) {
+                Ok(()) => {}
+                Err(_) if Context::has_exited(&self.backend, previous.thread_id) => {}
+                Err(error) => {
+                    if result.is_ok() {
+                        result = Err(ContextError::ThreadFailed {
+                            thread_id: previous.thread_id,
+                            error: Box::new(error),
+                        });
+                    }
+                }
+            }
+        }
+
+        result
+    }
+
+    /// Puts back what the hardware breakpoint held in a thread,
+    /// unless another breakpoint took it over since.
+    fn restore_thread(&self, previous: &Previous<B::Registers>) -> Result<()> {
+        let index = self.hwbp.get_index();
+        let callbacks = self.backend.callbacks();
+
+        let mut registers = self.backend.get_debug_registers(previous.thread_id)?;
+        let mut thread = callbacks.get_thread(previous.thread_id).unwrap_or_default();
+        // Our callback is gone if it removed itself, leaving the hardware breakpoint disabled.
+        let is_ours = match &thread.callbacks[index as usize] {
+            Some(hook) => hook.is_same(&self.hook),
+            None => !registers.is_enabled(index),
+        };
+        if !is_ours {
+            return Ok(());
+        }
+
+        registers.copy_slot(&previous.registers, index);
+        thread.callbacks[index as usize] = previous.hook.clone();
+        Context::swap(&self.backend, previous.thread_id, &registers, Some(thread))?;
+        if let Some(mut applied) = callbacks.get_applied_for(previous.thread_id) {
+            applied.registers.copy_slot(&registers, index);
+            applied.is_ours[index as usize] =
+                previous.hook.is_some() || !registers.is_enabled(index);
+            callbacks.set_applied(previous.thread_id, applied);
+        }
+
+        Ok(())
+    }
+}
+
+impl<B: Backend> Drop for HWBPGuard<'_, B> {
+    fn drop(&mut self) {
+        _ = self.restore_all();
+    }
+}
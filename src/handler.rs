@@ -1,80 +1,172 @@
-use std::sync::Mutex;
-
-use windows::Win32::{
-    Foundation::EXCEPTION_SINGLE_STEP,
-    System::{
-        Diagnostics::Debug::{
-            AddVectoredExceptionHandler, RemoveVectoredExceptionHandler,
-            EXCEPTION_CONTINUE_EXECUTION, EXCEPTION_CONTINUE_SEARCH, EXCEPTION_POINTERS,
-        },
-        Threading::GetCurrentThreadId,
-    },
-};
-
 use crate::{
-    callbacks,
     x86::{DR6, DR7},
-    Index,
+    CallbackRegistry, DebugRegisters, HWBPSlot, HitAction, HitContext, HitFlags, Index,
 };
 
-static HANDLER_HANDLE: Mutex<Option<usize>> = Mutex::new(None);
+/// Runs the callbacks of the hardware breakpoints reported by `dr6`
+/// and clears their detection bits.
+///
+/// A single-step trap of a thread that asked for it first steps its trace,
+/// then runs the callbacks of the breakpoints it stepped for,
+/// then the ones of breakpoints hit by the same instruction,
+/// each in the order of their index.
+/// Breakpoints without a callback are not ours, so their bits are left set.
+/// Breakpoints sharing a callback, like the slots of a range,
+/// run it once for all of them.
+/// Hits rejected by the filter of a breakpoint skip its callback,
+/// in coalesced mode falling through to the next breakpoint hit.
+///
+/// # Return value
+/// The action of every breakpoint whose callback was run,
+/// and whether the trap was a single step the thread asked for.
+pub fn dispatch<R>(
+    callbacks: &CallbackRegistry<R>,
+    tid: u32,
+    dr6: &mut DR6,
+    registers: &DebugRegisters,
+    ip: u64,
+    cr: &mut R,
+) -> ([Option<HitAction>; 4], bool) {
+    let dr7 = DR7::from_bits(registers.dr7);
+    let detected = [
+        dr7.bp_local_0() && dr6.bp_detected_0(),
+        dr7.bp_local_1() && dr6.bp_detected_1(),
+        dr7.bp_local_2() && dr6.bp_detected_2(),
+        dr7.bp_local_3() && dr6.bp_detected_3(),
+    ];
+    let hits =
+        Index::ALL.map(|index| detected[index as usize] && callbacks.get(tid, index).is_some());
 
-pub fn init() {
-    let mut lock = HANDLER_HANDLE.lock().unwrap();
-    if lock.is_some() {
-        return;
+    let has_detected =
+        dr6.bp_detected_0() || dr6.bp_detected_1() || dr6.bp_detected_2() || dr6.bp_detected_3();
+    let mut flags = HitFlags::from_dr6(dr6);
+    let stepping = callbacks.get_stepping(tid);
+    let is_step = callbacks.is_stepping(tid) && (flags.single_step || !has_detected);
+    if is_step {
+        callbacks.set_stepping(tid, [false; 4]);
+        dr6.set_is_single_step(false);
     }
 
-    let handler = unsafe { AddVectoredExceptionHandler(1, Some(exception_handler)) };
-    *lock = Some(handler as usize);
-}
-
-pub fn free() {
-    let mut lock = HANDLER_HANDLE.lock().unwrap();
+    for (index, hit) in Index::ALL.into_iter().zip(hits) {
+        if hit {
+            match index {
+                Index::First => dr6.set_bp_detected_0(false),
+                Index::Second => dr6.set_bp_detected_1(false),
+                Index::Third => dr6.set_bp_detected_2(false),
+                Index::Fourth => dr6.set_bp_detected_3(false),
+            }
+        }
+    }
 
-    if let Some(handler) = lock.take() {
-        unsafe { RemoveVectoredExceptionHandler(handler as _) };
+    let trace = if is_step {
+        callbacks.take_trace(tid)
+    } else {
+        None
+    };
+    if let Some(mut trace) = trace {
+        let index = trace.get_index();
+        let slot = HWBPSlot::from_dr7(registers.address[index as usize], &dr7, index);
+        let mut hit = HitContext::new(
+            index,
+            &slot,
+            tid,
+            ip,
+            HitFlags {
+                single_step: true,
+                ..flags
+            },
+            Index::ALL.map(|x| x == index),
+            cr,
+            callbacks.memory(),
+        );
+        let goes_on = trace.step(&mut hit);
+        match hit.take_trace() {
+            Some(mut next) => {
+                next.set_index(index);
+                callbacks.set_trace(tid, next);
+            }
+            None if goes_on => callbacks.set_trace(tid, trace),
+            None => {}
+        }
     }
-}
 
-pub unsafe extern "system" fn exception_handler(ex: *mut EXCEPTION_POINTERS) -> i32 {
-    if let Some(ex) = ex.as_mut() {
-        let cr = ex.ContextRecord;
-        let er = ex.ExceptionRecord;
-        if let (Some(cr), Some(er)) = (cr.as_mut(), er.as_ref()) {
-            if er.ExceptionCode == EXCEPTION_SINGLE_STEP {
-                let mut dr6 = DR6::from_bits(cr.Dr6);
-                let dr7 = DR7::from_bits(cr.Dr7);
-                let tid = GetCurrentThreadId();
+    let is_coalesced = callbacks.is_coalesced(tid);
+    let mut actions = [None; 4];
+    let mut run = |group: [bool; 4], flags: HitFlags, is_step: bool| {
+        let mut is_done = [false; 4];
+        for index in Index::ALL {
+            if !group[index as usize] || is_done[index as usize] {
+                continue;
+            }
 
-                if dr7.bp_local_0() && dr6.bp_detected_0() {
-                    if let Some(callback) = callbacks::get(tid, Index::First) {
-                        callback(cr);
-                    }
-                    dr6.set_bp_detected_0(false);
-                } else if dr7.bp_local_1() && dr6.bp_detected_1() {
-                    if let Some(callback) = callbacks::get(tid, Index::Second) {
-                        callback(cr);
-                    }
-                    dr6.set_bp_detected_1(false);
-                } else if dr7.bp_local_2() && dr6.bp_detected_2() {
-                    if let Some(callback) = callbacks::get(tid, Index::Third) {
-                        callback(cr);
+            let Some(hook) = callbacks.get(tid, index) else {
+                continue;
+            };
+
+            // Breakpoints sharing a hook, like the slots of a range,
+            // are one logical breakpoint hit once.
+            let shared = Index::ALL.map(|x| {
+                group[x as usize] && callbacks.get(tid, x).is_some_and(|x| x.is_same(&hook))
+            });
+            for (is_done, is_shared) in is_done.iter_mut().zip(shared) {
+                *is_done |= is_shared;
+            }
+
+            let slot = HWBPSlot::from_dr7(registers.address[index as usize], &dr7, index);
+            let mut hit =
+                HitContext::new(index, &slot, tid, ip, flags, group, cr, callbacks.memory());
+
+            // Steps the callback asked for are not hits, so they are not filtered.
+            // A filtered hit is still ours, it just continues.
+            if !is_step && !hook.filter.accept(&hit) {
+                for (action, is_shared) in actions.iter_mut().zip(shared) {
+                    if is_shared {
+                        action.get_or_insert(HitAction::Continue);
                     }
-                    dr6.set_bp_detected_2(false);
-                } else if dr7.bp_local_3() && dr6.bp_detected_3() {
-                    if let Some(callback) = callbacks::get(tid, Index::Fourth) {
-                        callback(cr);
+                }
+                continue;
+            }
+
+            let action = (hook.callback)(&mut hit);
+            if let Some(mut trace) = hit.take_trace() {
+                trace.set_index(index);
+                callbacks.set_trace(tid, trace);
+            }
+
+            // A coalesced callback decides for every breakpoint of the group.
+            let targets = if is_coalesced { group } else { shared };
+            for target in Index::ALL.into_iter().filter(|x| targets[*x as usize]) {
+                actions[target as usize] = Some(action);
+                match action {
+                    HitAction::Remove => callbacks.remove(tid, target),
+                    HitAction::SingleStep => {
+                        let mut stepping = callbacks.get_stepping(tid);
+                        stepping[target as usize] = true;
+                        callbacks.set_stepping(tid, stepping);
                     }
-                    dr6.set_bp_detected_3(false);
+                    _ => {}
                 }
+            }
 
-                cr.Dr6 = dr6.into_bits();
-                cr.EFlags |= 1 << 16;
-                return EXCEPTION_CONTINUE_EXECUTION;
+            if is_coalesced {
+                break;
             }
         }
+    };
+
+    if is_step {
+        run(
+            stepping,
+            HitFlags {
+                single_step: true,
+                ..flags
+            },
+            true,
+        );
+        // The single step has been dispatched.
+        flags.single_step = false;
     }
+    run(hits, flags, false);
 
-    EXCEPTION_CONTINUE_SEARCH
+    (actions, is_step)
 }
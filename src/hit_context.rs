@@ -0,0 +1,221 @@
+use std::{fmt, io, sync::Arc};
+
+use crate::{
+    types::{Condition, Index, Size},
+    x86::DR6,
+    HWBPSlot, Trace, CONTEXT,
+};
+
+/// Reads memory of the target of a backend into a buffer.
+pub type MemoryReader = Box<dyn Fn(u64, &mut [u8]) -> io::Result<()> + Send + Sync>;
+
+/// A predicate on a hit of a hardware breakpoint.
+pub type HitPredicate<R = CONTEXT> = Arc<dyn Fn(&HitContext<'_, R>) -> bool + Send + Sync>;
+
+/// The flags of `DR6` that tell why a debug exception was raised.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct HitFlags {
+    /// BS: the exception was raised by single-stepping (`EFLAGS.TF`).
+    pub single_step: bool,
+    /// BD: the next instruction accesses a debug register
+    /// while general detect (`DR7.GD`) is enabled.
+    pub debug_register_access: bool,
+    /// BT: the exception was raised by a task switch.
+    pub task_switch: bool,
+    /// RTM: the exception was raised inside a transactional region.
+    pub rtm: bool,
+}
+
+impl HitFlags {
+    pub(crate) fn from_dr6(dr6: &DR6) -> Self {
+        Self {
+            single_step: dr6.is_single_step(),
+            debug_register_access: dr6.dra_detected(),
+            task_switch: dr6.task_switch(),
+            // Unlike the others, this flag is cleared when it applies.
+            rtm: !dr6.rtm(),
+        }
+    }
+}
+
+/// Everything known about a hit of a hardware breakpoint,
+/// passed to its callback.
+///
+/// `R` is the type of registers the backend passes to callbacks,
+/// by default the thread context of the current platform.
+pub struct HitContext<'a, R = CONTEXT> {
+    index: Index,
+    address: u64,
+    condition: Condition,
+    size: Size,
+    thread_id: u32,
+    instruction_pointer: u64,
+    flags: HitFlags,
+    hits: [bool; 4],
+    registers: &'a mut R,
+    memory: Option<&'a MemoryReader>,
+    trace: Option<Trace<R>>,
+}
+
+impl<R: fmt::Debug> fmt::Debug for HitContext<'_, R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HitContext")
+            .field("index", &self.index)
+            .field("address", &self.address)
+            .field("condition", &self.condition)
+            .field("size", &self.size)
+            .field("thread_id", &self.thread_id)
+            .field("instruction_pointer", &self.instruction_pointer)
+            .field("flags", &self.flags)
+            .field("hits", &self.hits)
+            .field("registers", &self.registers)
+            .field("trace", &self.trace)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<'a, R> HitContext<'a, R> {
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn new(
+        index: Index,
+        slot: &HWBPSlot,
+        thread_id: u32,
+        instruction_pointer: u64,
+        flags: HitFlags,
+        hits: [bool; 4],
+        registers: &'a mut R,
+        memory: Option<&'a MemoryReader>,
+    ) -> Self {
+        Self {
+            index,
+            address: slot.address,
+            condition: slot.condition,
+            size: slot.size,
+            thread_id,
+            instruction_pointer,
+            flags,
+            hits,
+            registers,
+            memory,
+            trace: None,
+        }
+    }
+
+    /// Gets the index of the hardware breakpoint that was hit.
+    pub fn get_index(&self) -> Index {
+        self.index
+    }
+
+    /// Gets the address watched by the hardware breakpoint.
+    pub fn get_address(&self) -> u64 {
+        self.address
+    }
+
+    /// Gets the condition of the hardware breakpoint.
+    pub fn get_condition(&self) -> Condition {
+        self.condition
+    }
+
+    /// Gets the size of the hardware breakpoint.
+    pub fn get_size(&self) -> Size {
+        self.size
+    }
+
+    /// Gets the id of the thread that hit the hardware breakpoint.
+    pub fn get_thread_id(&self) -> u32 {
+        self.thread_id
+    }
+
+    /// Gets the instruction pointer at the time of the hit.
+    ///
+    /// For execute breakpoints this is the address of the instruction
+    /// about to run, for data breakpoints the one after the access.
+    pub fn get_instruction_pointer(&self) -> u64 {
+        self.instruction_pointer
+    }
+
+    /// Gets the decoded `DR6` flags of the hit.
+    pub fn get_flags(&self) -> HitFlags {
+        self.flags
+    }
+
+    /// Gets whether a hardware breakpoint was hit together with this one,
+    /// by the same instruction or single step.
+    ///
+    /// This is true for the index of this breakpoint.
+    pub fn is_hit(&self, index: Index) -> bool {
+        self.hits[index as usize]
+    }
+
+    /// Gets the registers of the thread that hit the hardware breakpoint.
+    pub fn registers(&self) -> &R {
+        self.registers
+    }
+
+    /// Gets the registers of the thread that hit the hardware breakpoint.
+    ///
+    /// Changes are written back to the thread when the callback returns.
+    pub fn registers_mut(&mut self) -> &mut R {
+        self.registers
+    }
+
+    /// Reads memory of the process that hit the hardware breakpoint.
+    ///
+    /// Fails for unmapped memory, or if the backend cannot read memory.
+    pub fn read_memory(&self, address: u64, buffer: &mut [u8]) -> io::Result<()> {
+        match self.memory {
+            Some(memory) => memory(address, buffer),
+            None => Err(io::ErrorKind::Unsupported.into()),
+        }
+    }
+
+    /// Reads the memory watched by the hardware breakpoint,
+    /// as many bytes as its size.
+    pub fn read_value(&self) -> io::Result<u64> {
+        let mut value = [0; 8];
+        self.read_memory(self.address, &mut value[..self.size.into_bytes()])?;
+        Ok(u64::from_le_bytes(value))
+    }
+
+    /// Starts single-stepping the thread once the callback returns,
+    /// replacing the trace it is in, if any.
+    pub fn start_trace(&mut self, trace: Trace<R>) {
+        self.trace = Some(trace);
+    }
+
+    pub(crate) fn take_trace(&mut self) -> Option<Trace<R>> {
+        self.trace.take()
+    }
+}
+
+/// What to do after the callback of a hardware breakpoint returns.
+///
+/// Callbacks returning `()` continue execution.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum HitAction {
+    /// Continues execution.
+    #[default]
+    Continue,
+    /// Disables the hardware breakpoint in the thread that hit it,
+    /// and continues execution.
+    Disable,
+    /// Disables the hardware breakpoint in the thread that hit it,
+    /// removes its callback, and continues execution.
+    Remove,
+    /// Executes the next instruction and calls the callback again,
+    /// with `HitFlags::single_step` set.
+    SingleStep,
+    /// Leaves the exception to the next handler,
+    /// `EXCEPTION_CONTINUE_SEARCH` on Windows or the previous `SIGTRAP` handler on Linux.
+    PassOn,
+    /// Breaks into an attached debugger, then continues execution.
+    ///
+    /// Without a debugger this usually terminates the process, like `DebugBreak` does.
+    Break,
+}
+
+impl From<()> for HitAction {
+    fn from(_: ()) -> Self {
+        Self::Continue
+    }
+}
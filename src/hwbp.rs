@@ -1,30 +1,178 @@
-use crate::{callbacks, types::Index, windows::CONTEXT, x86::DR7, HWBPSlot};
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use crate::{
+    types::Index, x86::DR7, CallbackRegistry, HWBPSlot, HitAction, HitContext, HitPredicate,
+    MemoryReader, CONTEXT,
+};
 
 /// A callback that is called when the hardware breakpoint is hit.
-pub type HWBPCallback = fn(&mut CONTEXT);
+///
+/// `R` is the type of registers the backend passes to callbacks,
+/// by default the thread context of the current platform.
+///
+/// Callbacks are shared between every `HWBP` and thread they were set for,
+/// and may be called from any of those threads.
+pub type HWBPCallback<R = CONTEXT> = Arc<dyn Fn(&mut HitContext<'_, R>) -> HitAction + Send + Sync>;
+
+/// Wraps a closure returning anything that converts into `HitAction`.
+pub(crate) fn into_callback<R, A: Into<HitAction>>(
+    callback: impl Fn(&mut HitContext<'_, R>) -> A + Send + Sync + 'static,
+) -> HWBPCallback<R> {
+    Arc::new(move |hit| callback(hit).into())
+}
+
+/// Refreshes what a callback keeps of the watched memory, when its breakpoint is armed,
+/// reading it through the backend if it can, see `CallbackRegistry::memory`.
+pub(crate) type ArmCallback = Arc<dyn Fn(Option<&MemoryReader>) + Send + Sync>;
+
+/// Orders the hits of all hardware breakpoints.
+static HIT_SEQUENCE: AtomicU64 = AtomicU64::new(0);
+
+/// Decides which hits of a hardware breakpoint run its callback,
+/// and counts them.
+pub(crate) struct HitFilter<R> {
+    hits: AtomicU64,
+    last_hit: AtomicU64,
+    pub(crate) ignore_count: u64,
+    pub(crate) interval: u64,
+    pub(crate) predicate: Option<HitPredicate<R>>,
+}
+
+impl<R> HitFilter<R> {
+    /// Counts a hit, and gets whether it runs the callback.
+    pub(crate) fn accept(&self, hit: &HitContext<'_, R>) -> bool {
+        let count = self.hits.fetch_add(1, Ordering::Relaxed) + 1;
+        self.last_hit.store(
+            HIT_SEQUENCE.fetch_add(1, Ordering::Relaxed) + 1,
+            Ordering::Relaxed,
+        );
+        if count <= self.ignore_count {
+            return false;
+        }
+
+        if !(count - self.ignore_count).is_multiple_of(self.interval.max(1)) {
+            return false;
+        }
+
+        self.predicate.as_ref().is_none_or(|x| x(hit))
+    }
+
+    /// Gets how many times the breakpoint was hit.
+    pub(crate) fn get_hit_count(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    /// Gets when the breakpoint was last hit, compared to the others,
+    /// or 0 if it never was.
+    pub(crate) fn get_last_hit(&self) -> u64 {
+        self.last_hit.load(Ordering::Relaxed)
+    }
+}
+
+impl<R> Default for HitFilter<R> {
+    fn default() -> Self {
+        Self {
+            hits: AtomicU64::new(0),
+            last_hit: AtomicU64::new(0),
+            ignore_count: 0,
+            interval: 1,
+            predicate: None,
+        }
+    }
+}
+
+/// The callback of a hardware breakpoint together with its filter,
+/// shared by every thread it is applied to.
+pub(crate) struct Hook<R> {
+    pub(crate) callback: HWBPCallback<R>,
+    pub(crate) filter: Arc<HitFilter<R>>,
+    pub(crate) arm: Option<ArmCallback>,
+}
+
+impl<R> Hook<R> {
+    /// Gets whether both hooks belong to the same logical breakpoint.
+    pub(crate) fn is_same(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.filter, &other.filter)
+    }
+
+    /// Lets the callback know that its breakpoint is being armed.
+    pub(crate) fn arm(&self, memory: Option<&MemoryReader>) {
+        if let Some(arm) = &self.arm {
+            arm(memory);
+        }
+    }
+}
+
+impl<R> Clone for Hook<R> {
+    fn clone(&self) -> Self {
+        Self {
+            callback: self.callback.clone(),
+            filter: self.filter.clone(),
+            arm: self.arm.clone(),
+        }
+    }
+}
 
 /// Represents a hardware breakpoint bound to a specific index.
-#[derive(Clone, Copy, Debug)]
-pub struct HWBP {
+///
+/// Cloning a hardware breakpoint is cheap,
+/// the clones share the callback and the hit count.
+pub struct HWBP<R = CONTEXT> {
     idx: Index,
     slot: HWBPSlot,
-    callback: Option<HWBPCallback>,
+    hook: Option<Hook<R>>,
+}
+
+impl<R> Clone for HWBP<R> {
+    fn clone(&self) -> Self {
+        Self {
+            idx: self.idx,
+            slot: self.slot,
+            hook: self.hook.clone(),
+        }
+    }
+}
+
+impl<R> fmt::Debug for HWBP<R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HWBP")
+            .field("idx", &self.idx)
+            .field("slot", &self.slot)
+            .field(
+                "callback",
+                &self.hook.as_ref().map(|x| Arc::as_ptr(&x.callback)),
+            )
+            .field("hit_count", &self.get_hit_count())
+            .finish()
+    }
 }
 
-impl HWBP {
-    pub(crate) fn set(&mut self, slot: HWBPSlot, callback: HWBPCallback) {
+impl<R> HWBP<R> {
+    pub(crate) fn set(&mut self, slot: HWBPSlot, hook: Hook<R>) {
         self.slot = slot;
-        self.callback = Some(callback);
+        self.hook = Some(hook);
     }
 
-    pub(crate) fn from_context(idx: Index, dr7: &DR7, drn: u64, thread_id: u32) -> Self {
+    pub(crate) fn get_hook(&self) -> Option<Hook<R>> {
+        self.hook.clone()
+    }
+
+    pub(crate) fn from_context(
+        idx: Index,
+        dr7: &DR7,
+        drn: u64,
+        callbacks: &CallbackRegistry<R>,
+        thread_id: u32,
+    ) -> Self {
         let slot = HWBPSlot::from_dr7(drn, dr7, idx);
-        let callback = callbacks::get(thread_id, idx);
-        Self {
-            idx,
-            slot,
-            callback,
-        }
+        let hook = callbacks.get(thread_id, idx);
+        Self { idx, slot, hook }
     }
 
     pub(crate) fn apply_to_context(&self, drn: &mut u64, dr7: &mut DR7) {
@@ -32,15 +180,30 @@ impl HWBP {
     }
 }
 
-impl HWBP {
+impl<R> HWBP<R> {
     /// Gets the index of the hardware breakpoint.
     pub fn get_index(&self) -> Index {
         self.idx
     }
 
     /// Gets the callback of the hardware breakpoint.
-    pub fn get_callback(&self) -> Option<HWBPCallback> {
-        self.callback
+    pub fn get_callback(&self) -> Option<HWBPCallback<R>> {
+        self.hook.as_ref().map(|x| x.callback.clone())
+    }
+
+    /// Gets how many times the hardware breakpoint was hit,
+    /// in every thread it was applied to,
+    /// including the hits filtered out before running the callback.
+    pub fn get_hit_count(&self) -> u64 {
+        self.hook.as_ref().map_or(0, |x| x.filter.get_hit_count())
+    }
+
+    /// Resets the hit count of the hardware breakpoint,
+    /// so that the hits to ignore and the interval start over.
+    pub fn reset_hit_count(&self) {
+        if let Some(hook) = &self.hook {
+            hook.filter.hits.store(0, Ordering::Relaxed);
+        }
     }
 
     /// Gets whether the hardware breakpoint is enabled.
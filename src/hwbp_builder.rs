@@ -1,21 +1,58 @@
-use crate::{BuilderError, Condition, Context, HWBPCallback, HWBPSlot, Index, Size, HWBP};
+use std::{fmt, sync::Arc};
+
+use crate::{
+    hwbp::{into_callback, ArmCallback, HitFilter, Hook},
+    watch::{change_callback, snapshot_callback},
+    ArmedWatch, Backend, BuilderError, Condition, Context, HWBPCallback, HWBPGuard, HWBPSlot,
+    HitAction, HitContext, Index, NativeBackend, Scope, ScopedBreakpoint, Size, ValueChange, Watch,
+    WatchStrategy, Watchable, HWBP,
+};
 
 pub type Result<T> = std::result::Result<T, BuilderError>;
 
 /// A builder for hardware breakpoints.
-#[derive(Debug)]
-pub struct HWBPBuilder<'a> {
-    context: &'a mut Context,
-    index: Index,
+pub struct HWBPBuilder<'a, B: Backend = NativeBackend> {
+    context: &'a mut Context<B>,
+    index: Option<Index>,
     is_enabled: bool,
     address: Option<u64>,
     condition: Option<Condition>,
     size: Option<Size>,
-    callback: Option<HWBPCallback>,
+    callback: Option<HWBPCallback<B::Registers>>,
+    /// Takes the shadow copy of `watch_variable_change` when the watch is armed.
+    arm: Option<ArmCallback>,
+    filter: HitFilter<B::Registers>,
+    is_validated: bool,
+    strategy: WatchStrategy,
+    /// The owner tag the hardware breakpoint was handed out for, see `Context::unused_for`.
+    owner: Option<&'static str>,
+}
+
+impl<B: Backend> fmt::Debug for HWBPBuilder<'_, B>
+where
+    Context<B>: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HWBPBuilder")
+            .field("context", &self.context)
+            .field("index", &self.index)
+            .field("is_enabled", &self.is_enabled)
+            .field("address", &self.address)
+            .field("condition", &self.condition)
+            .field("size", &self.size)
+            .field("callback", &self.callback.as_ref().map(Arc::as_ptr))
+            .field("ignore_count", &self.filter.ignore_count)
+            .field("interval", &self.filter.interval)
+            .field("is_validated", &self.is_validated)
+            .field("strategy", &self.strategy)
+            .field("owner", &self.owner)
+            .finish_non_exhaustive()
+    }
 }
 
-impl<'a> HWBPBuilder<'a> {
-    pub(crate) fn new(context: &'a mut Context, index: Index) -> Self {
+impl<'a, B: Backend> HWBPBuilder<'a, B> {
+    pub(crate) fn new(context: &'a mut Context<B>, index: Option<Index>) -> Self {
+        let strategy = context.get_strategy();
         Self {
             context,
             index,
@@ -24,11 +61,97 @@ impl<'a> HWBPBuilder<'a> {
             condition: None,
             size: None,
             callback: None,
+            arm: None,
+            filter: HitFilter::default(),
+            is_validated: true,
+            strategy,
+            owner: None,
         }
     }
 
+    pub(crate) fn with_owner(mut self, owner: &'static str) -> Self {
+        self.owner = Some(owner);
+        self
+    }
+
     /// Builds and sets the hardware breakpoint.
-    pub fn build_and_set(self) -> Result<HWBP> {
+    ///
+    /// Unless validation is turned off with `with_validation`,
+    /// this fails for breakpoints the CPU cannot watch as described,
+    /// see `BuilderError`.
+    pub fn build_and_set(mut self) -> Result<HWBP<B::Registers>> {
+        let index = match self.index {
+            Some(index) => index,
+            None => return Err(BuilderError::NoUnusedSlot),
+        };
+
+        let (slot, hook) = self.build()?;
+        Ok(self.context.build_and_set_hwbp(index, slot, hook))
+    }
+
+    /// Builds the breakpoint for every thread of a scope, see `ScopedBreakpoint`,
+    /// and arms it right away if it is enabled.
+    ///
+    /// Every thread gets a hardware breakpoint of its own,
+    /// whichever one the builder was obtained for.
+    pub fn build_scoped(mut self, scope: Scope) -> Result<ScopedBreakpoint<B>>
+    where
+        B: Clone,
+    {
+        let (slot, hook) = self.build()?;
+        ScopedBreakpoint::new(self.context.backend().clone(), scope, slot, hook)
+            .map_err(BuilderError::ApplyFailed)
+    }
+
+    /// Builds the hardware breakpoint like `build_and_set`, but into a guard
+    /// instead of the context, see `HWBPGuard`.
+    ///
+    /// The guard still has to be applied to the threads,
+    /// and puts back what they held before when dropped.
+    pub fn build_guarded(mut self) -> Result<HWBPGuard<'static, B>>
+    where
+        B: Clone,
+    {
+        let index = match self.index {
+            Some(index) => index,
+            None => return Err(BuilderError::NoUnusedSlot),
+        };
+
+        let (slot, hook) = self.build()?;
+        let mut hwbp = self.context.get(index);
+        hwbp.set(slot, hook.clone());
+        Ok(HWBPGuard::new(
+            self.context.backend().clone(),
+            hwbp,
+            hook,
+            self.owner,
+        ))
+    }
+
+    /// Builds a guard watching a variable for a condition, like `watch` and `build_guarded`.
+    ///
+    /// The guard borrows the variable, so a watch on the stack
+    /// is restored before the stack frame goes away.
+    pub fn watch_guarded<'v, T: Watchable, A: Into<HitAction>>(
+        mut self,
+        variable: &'v T,
+        condition: Condition,
+        callback: impl Fn(&mut HitContext<'_, B::Registers>, &T) -> A + Send + Sync + 'static,
+    ) -> Result<HWBPGuard<'v, B>>
+    where
+        B: Clone,
+    {
+        self.address = Some(variable as *const T as u64);
+        self.condition = Some(condition);
+        self.size = Some(T::SIZE);
+        self.callback = Some(snapshot_callback(callback));
+        self.arm = None;
+
+        self.build_guarded()
+    }
+
+    /// Checks the breakpoint, see `build_and_set`, and gets its slot and hook.
+    fn build(&mut self) -> Result<(HWBPSlot, Hook<B::Registers>)> {
         let address = match self.address {
             Some(addr) => addr,
             None => return Err(BuilderError::AddressNotSet),
@@ -39,7 +162,7 @@ impl<'a> HWBPBuilder<'a> {
             None => return Err(BuilderError::ConditionNotSet),
         };
 
-        let callback = match self.callback {
+        let callback = match self.callback.take() {
             Some(callback) => callback,
             None => return Err(BuilderError::CallbackNotSet),
         };
@@ -61,77 +184,229 @@ impl<'a> HWBPBuilder<'a> {
                 is_enabled: self.is_enabled,
                 address,
                 condition,
-                size: Size::OneByte,
+                size: self.size.unwrap_or(Size::OneByte),
             }
         };
 
-        Ok(self.context.build_and_set_hwbp(self.index, slot, callback))
+        if self.is_validated {
+            self.context.validate_slot(&slot)?;
+        }
+
+        let hook = Hook {
+            callback,
+            filter: Arc::new(std::mem::take(&mut self.filter)),
+            arm: self.arm.take(),
+        };
+
+        Ok((slot, hook))
+    }
+
+    /// Builds and arms the watch according to the strategy, see `with_strategy`:
+    /// either sets a hardware breakpoint like `build_and_set`,
+    /// or protects the pages of the watched memory or patches an `int3` into the code,
+    /// which takes effect immediately.
+    pub fn build_and_watch(self) -> Result<ArmedWatch<B::Registers>> {
+        let strategy = match (self.strategy, self.index, self.condition) {
+            (WatchStrategy::Fallback, Some(_), _) => WatchStrategy::Hardware,
+            (WatchStrategy::Fallback, None, Some(Condition::Execute)) => WatchStrategy::Software,
+            (WatchStrategy::Fallback, None, _) => WatchStrategy::Paging,
+            (strategy, _, _) => strategy,
+        };
+        if strategy == WatchStrategy::Hardware {
+            return self.build_and_set().map(ArmedWatch::Hardware);
+        }
+
+        let address = match self.address {
+            Some(addr) => addr,
+            None => return Err(BuilderError::AddressNotSet),
+        };
+
+        let condition = match self.condition {
+            Some(condition) => condition,
+            None => return Err(BuilderError::ConditionNotSet),
+        };
+
+        // An `int3` patches a single byte.
+        let size = match (strategy, self.size) {
+            (WatchStrategy::Software, _) => Size::OneByte,
+            (_, Some(size)) => size,
+            (_, None) => return Err(BuilderError::SizeNotSet),
+        };
+
+        if strategy == WatchStrategy::Software && condition != Condition::Execute {
+            return Err(BuilderError::UnsupportedCondition(condition));
+        }
+
+        // The same address and condition checks as a hardware breakpoint.
+        if self.is_validated {
+            self.context.validate_slot(&HWBPSlot {
+                is_enabled: self.is_enabled,
+                address,
+                condition,
+                size,
+            })?;
+        }
+
+        let callback = match self.callback {
+            Some(callback) => callback,
+            None => return Err(BuilderError::CallbackNotSet),
+        };
+
+        let hook = Hook {
+            callback,
+            filter: Arc::new(self.filter),
+            arm: self.arm,
+        };
+        // Pages and code are armed right away.
+        hook.arm(self.context.backend().callbacks().memory());
+
+        if strategy == WatchStrategy::Software {
+            let breakpoint = self.context.set_software_breakpoint(address, hook)?;
+            if !self.is_enabled {
+                breakpoint.disable().map_err(BuilderError::PatchFailed)?;
+            }
+
+            return Ok(ArmedWatch::Software(breakpoint));
+        }
+
+        let len = size.into_bytes() as u64;
+        let page = self.context.watch_pages(address, len, condition, hook)?;
+        if !self.is_enabled {
+            page.disable().map_err(BuilderError::ProtectionFailed)?;
+        }
+
+        Ok(ArmedWatch::Paging(page))
+    }
+
+    /// Builds a hardware breakpoint watching a variable for a condition,
+    /// with a callback that gets a snapshot of the variable after the access.
+    ///
+    /// Unlike `watch_variable`, the size of the breakpoint comes from the type,
+    /// so types that a breakpoint cannot cover do not compile.
+    /// The returned `Watch` borrows the variable, and is applied to the threads
+    /// instead of the context, like a guard, see `build_guarded`.
+    pub fn watch<'v, T: Watchable, A: Into<HitAction>>(
+        self,
+        variable: &'v T,
+        condition: Condition,
+        callback: impl Fn(&mut HitContext<'_, B::Registers>, &T) -> A + Send + Sync + 'static,
+    ) -> Result<Watch<'v, T, B>>
+    where
+        B: Clone,
+    {
+        let guard = self.watch_guarded(variable, condition, callback)?;
+        Ok(Watch::new(guard, variable))
     }
 }
 
-impl HWBPBuilder<'_> {
+impl<B: Backend> HWBPBuilder<'_, B> {
     /// Watch a memory address for a specific condition.
-    pub fn watch_memory(
+    pub fn watch_memory<A: Into<HitAction>>(
         mut self,
         addr: *const u8,
         condition: Condition,
         size: Size,
-        callback: HWBPCallback,
+        callback: impl Fn(&mut HitContext<'_, B::Registers>) -> A + Send + Sync + 'static,
     ) -> Self {
         self.address = Some(addr as u64);
         self.condition = Some(condition);
         self.size = Some(size);
-        self.callback = Some(callback);
+        self.callback = Some(into_callback(callback));
+        self.arm = None;
         self
     }
 
     /// Watch a memory address for write access.
-    pub fn watch_memory_write(self, addr: *const u8, size: Size, callback: HWBPCallback) -> Self {
+    pub fn watch_memory_write<A: Into<HitAction>>(
+        self,
+        addr: *const u8,
+        size: Size,
+        callback: impl Fn(&mut HitContext<'_, B::Registers>) -> A + Send + Sync + 'static,
+    ) -> Self {
         self.watch_memory(addr, Condition::Write, size, callback)
     }
 
     /// Watch a memory address for read and write access.
-    pub fn watch_memory_read_write(
+    pub fn watch_memory_read_write<A: Into<HitAction>>(
         self,
         addr: *const u8,
         size: Size,
-        callback: HWBPCallback,
+        callback: impl Fn(&mut HitContext<'_, B::Registers>) -> A + Send + Sync + 'static,
     ) -> Self {
         self.watch_memory(addr, Condition::ReadWrite, size, callback)
     }
 
     /// Watch a memory address for execution.
-    pub fn watch_memory_execute(self, addr: *const u8, callback: HWBPCallback) -> Self {
+    pub fn watch_memory_execute<A: Into<HitAction>>(
+        self,
+        addr: *const u8,
+        callback: impl Fn(&mut HitContext<'_, B::Registers>) -> A + Send + Sync + 'static,
+    ) -> Self {
         self.watch_memory(addr, Condition::Execute, Size::OneByte, callback)
     }
 
     /// Watch a variable for a specific condition.
-    pub fn watch_variable<T>(
+    pub fn watch_variable<T, A: Into<HitAction>>(
         self,
         variable: &T,
         condition: Condition,
-        callback: HWBPCallback,
+        callback: impl Fn(&mut HitContext<'_, B::Registers>) -> A + Send + Sync + 'static,
     ) -> Option<Self> {
         let size = Size::from_bytes(std::mem::size_of::<T>())?;
         Some(self.watch_memory(variable as *const T as *const u8, condition, size, callback))
     }
 
     /// Watch a variable for write access.
-    pub fn watch_variable_write<T>(self, variable: &T, callback: HWBPCallback) -> Option<Self> {
+    pub fn watch_variable_write<T, A: Into<HitAction>>(
+        self,
+        variable: &T,
+        callback: impl Fn(&mut HitContext<'_, B::Registers>) -> A + Send + Sync + 'static,
+    ) -> Option<Self> {
         self.watch_variable(variable, Condition::Write, callback)
     }
 
     /// Watch a variable for read and write access.
-    pub fn watch_variable_read_write<T>(
+    pub fn watch_variable_read_write<T, A: Into<HitAction>>(
         self,
         variable: &T,
-        callback: HWBPCallback,
+        callback: impl Fn(&mut HitContext<'_, B::Registers>) -> A + Send + Sync + 'static,
     ) -> Option<Self> {
         self.watch_variable(variable, Condition::ReadWrite, callback)
     }
+
+    /// Watch a variable for writes, passing its old and new values to the callback.
+    ///
+    /// Writes trap after they happen, so the old value comes from a copy
+    /// taken when the watch is armed, as it is applied to a thread,
+    /// and refreshed on every hit.
+    /// If `only_changes` is set, writes of the same value do not call the callback.
+    ///
+    /// The callback is not called when the new value cannot be read,
+    /// see `HitContext::read_memory`.
+    pub fn watch_variable_change<T, A: Into<HitAction>>(
+        mut self,
+        variable: &T,
+        only_changes: bool,
+        callback: impl Fn(&mut HitContext<'_, B::Registers>, ValueChange<T>) -> A
+            + Send
+            + Sync
+            + 'static,
+    ) -> Option<Self>
+    where
+        T: Copy + PartialEq + Send + 'static,
+    {
+        let size = Size::from_bytes(std::mem::size_of::<T>())?;
+        self.address = Some(variable as *const T as u64);
+        self.condition = Some(Condition::Write);
+        self.size = Some(size);
+        let (callback, arm) = change_callback(variable, only_changes, callback);
+        self.callback = Some(callback);
+        self.arm = Some(arm);
+        Some(self)
+    }
 }
 
-impl HWBPBuilder<'_> {
+impl<B: Backend> HWBPBuilder<'_, B> {
     /// Sets whether the hardware breakpoint is enabled.
     pub fn set_enabled(&mut self, is_enabled: bool) {
         self.is_enabled = is_enabled;
@@ -153,12 +428,52 @@ impl HWBPBuilder<'_> {
     }
 
     /// Sets the callback of the hardware breakpoint.
-    pub fn set_callback(&mut self, callback: HWBPCallback) {
-        self.callback = Some(callback);
+    pub fn set_callback<A: Into<HitAction>>(
+        &mut self,
+        callback: impl Fn(&mut HitContext<'_, B::Registers>) -> A + Send + Sync + 'static,
+    ) {
+        self.callback = Some(into_callback(callback));
+        self.arm = None;
+    }
+
+    /// Sets how `build_and_watch` arms the watch,
+    /// by default the strategy of the context.
+    pub fn set_strategy(&mut self, strategy: WatchStrategy) {
+        self.strategy = strategy;
+    }
+
+    /// Sets whether `build_and_set` and `build_and_watch` check the breakpoint.
+    ///
+    /// Turning this off programs the debug registers with whatever is set,
+    /// and leaves the rest to the CPU, which for example rounds misaligned addresses down.
+    pub fn set_validation(&mut self, is_validated: bool) {
+        self.is_validated = is_validated;
+    }
+
+    /// Sets how many hits to ignore before running the callback.
+    pub fn set_ignore_count(&mut self, ignore_count: u64) {
+        self.filter.ignore_count = ignore_count;
+    }
+
+    /// Sets the callback to run only every `interval`th hit,
+    /// counting from the first hit that is not ignored.
+    pub fn set_interval(&mut self, interval: u64) {
+        self.filter.interval = interval;
+    }
+
+    /// Sets a predicate that has to hold for the callback to run.
+    ///
+    /// It is evaluated after the ignore count and the interval,
+    /// and may read the registers and the watched memory of the hit.
+    pub fn set_predicate(
+        &mut self,
+        predicate: impl Fn(&HitContext<'_, B::Registers>) -> bool + Send + Sync + 'static,
+    ) {
+        self.filter.predicate = Some(Arc::new(predicate));
     }
 }
 
-impl HWBPBuilder<'_> {
+impl<B: Backend> HWBPBuilder<'_, B> {
     /// Sets whether the hardware breakpoint is enabled.
     pub fn with_enabled(mut self, is_enabled: bool) -> Self {
         self.is_enabled = is_enabled;
@@ -184,8 +499,53 @@ impl HWBPBuilder<'_> {
     }
 
     /// Sets the callback of the hardware breakpoint.
-    pub fn with_callback(mut self, callback: HWBPCallback) -> Self {
-        self.callback = Some(callback);
+    pub fn with_callback<A: Into<HitAction>>(
+        mut self,
+        callback: impl Fn(&mut HitContext<'_, B::Registers>) -> A + Send + Sync + 'static,
+    ) -> Self {
+        self.callback = Some(into_callback(callback));
+        self.arm = None;
+        self
+    }
+
+    /// Sets how `build_and_watch` arms the watch,
+    /// by default the strategy of the context.
+    pub fn with_strategy(mut self, strategy: WatchStrategy) -> Self {
+        self.strategy = strategy;
+        self
+    }
+
+    /// Sets whether `build_and_set` and `build_and_watch` check the breakpoint.
+    ///
+    /// Turning this off programs the debug registers with whatever is set,
+    /// and leaves the rest to the CPU, which for example rounds misaligned addresses down.
+    pub fn with_validation(mut self, is_validated: bool) -> Self {
+        self.is_validated = is_validated;
+        self
+    }
+
+    /// Sets how many hits to ignore before running the callback.
+    pub fn with_ignore_count(mut self, ignore_count: u64) -> Self {
+        self.filter.ignore_count = ignore_count;
+        self
+    }
+
+    /// Sets the callback to run only every `interval`th hit,
+    /// counting from the first hit that is not ignored.
+    pub fn with_interval(mut self, interval: u64) -> Self {
+        self.filter.interval = interval;
+        self
+    }
+
+    /// Sets a predicate that has to hold for the callback to run.
+    ///
+    /// It is evaluated after the ignore count and the interval,
+    /// and may read the registers and the watched memory of the hit.
+    pub fn with_predicate(
+        mut self,
+        predicate: impl Fn(&HitContext<'_, B::Registers>) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.filter.predicate = Some(Arc::new(predicate));
         self
     }
 }
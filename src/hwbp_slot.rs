@@ -1,8 +1,40 @@
 use crate::{
     types::{Condition, Index, Size},
     x86::DR7,
+    Backend, BuilderError,
 };
 
+/// Gets how many bits user-space addresses have:
+/// 47 with 4-level paging, 56 with 5-level paging (LA57).
+///
+/// The addresses above are in kernel space if canonical,
+/// that is if their upper bits are copies of the highest bit of user space.
+#[cfg(target_arch = "x86_64")]
+fn get_user_space_bits() -> u32 {
+    // The kernel only reports LA57 if it enabled 5-level paging.
+    #[cfg(target_os = "linux")]
+    {
+        static BITS: std::sync::OnceLock<u32> = std::sync::OnceLock::new();
+        *BITS.get_or_init(|| {
+            let is_la57 = std::fs::read_to_string("/proc/cpuinfo").is_ok_and(|cpuinfo| {
+                cpuinfo
+                    .lines()
+                    .filter(|x| x.starts_with("flags"))
+                    .any(|x| x.split_whitespace().any(|x| x == "la57"))
+            });
+            if is_la57 {
+                56
+            } else {
+                47
+            }
+        })
+    }
+
+    // Windows keeps user space below 47 bits, whatever the paging.
+    #[cfg(not(target_os = "linux"))]
+    47
+}
+
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub(crate) struct HWBPSlot {
     pub(crate) is_enabled: bool,
@@ -41,6 +73,61 @@ impl HWBPSlot {
         }
     }
 
+    /// Checks that the CPU watches exactly what the slot describes, from user mode.
+    pub(crate) fn validate(&self) -> Result<(), BuilderError> {
+        match self.condition {
+            Condition::IoReadWrite => {
+                return Err(BuilderError::UnsupportedCondition(self.condition))
+            }
+            Condition::Execute if self.size != Size::OneByte => {
+                return Err(BuilderError::InvalidExecuteSize(self.size))
+            }
+            _ => {}
+        }
+
+        // The CPU ignores the low bits of the address instead.
+        if !self.address.is_multiple_of(self.size.into_bytes() as u64) {
+            return Err(BuilderError::Misaligned {
+                address: self.address,
+                size: self.size,
+            });
+        }
+
+        #[cfg(target_arch = "x86_64")]
+        {
+            let bits = get_user_space_bits();
+            let shift = 63 - bits;
+            if ((self.address as i64) << shift >> shift) as u64 != self.address {
+                return Err(BuilderError::NonCanonicalAddress(self.address));
+            }
+            if self.address >> bits != 0 {
+                return Err(BuilderError::KernelAddress(self.address));
+            }
+        }
+        #[cfg(not(target_arch = "x86_64"))]
+        if self.address > u32::MAX as u64 {
+            return Err(BuilderError::NonCanonicalAddress(self.address));
+        }
+
+        Ok(())
+    }
+
+    /// Checks the slot like `validate`, and that its address is mapped,
+    /// if the backend can read memory.
+    pub(crate) fn validate_for<B: Backend>(&self, backend: &B) -> Result<(), BuilderError> {
+        self.validate()?;
+
+        // Backends that cannot read memory cannot tell.
+        let mut buffer = [0; 8];
+        let buffer = &mut buffer[..self.size.into_bytes()];
+        if let Some(memory) = backend.callbacks().memory() {
+            memory(self.address, buffer)
+                .map_err(|_| BuilderError::UnmappedAddress(self.address))?;
+        }
+
+        Ok(())
+    }
+
     pub(crate) fn apply_to_dr7(&self, index: &Index, drn: &mut u64, dr7: &mut DR7) {
         *drn = self.address;
         match index {
@@ -67,3 +154,32 @@ impl HWBPSlot {
         }
     }
 }
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests {
+    use super::*;
+
+    fn validate(address: u64) -> Result<(), BuilderError> {
+        HWBPSlot {
+            is_enabled: true,
+            address,
+            condition: Condition::Write,
+            size: Size::FourBytes,
+        }
+        .validate()
+    }
+
+    #[test]
+    fn validate_addresses() {
+        assert!(validate(0x1000).is_ok());
+        assert!(validate(0x0000_7FFF_FFFF_F000).is_ok());
+        assert!(matches!(
+            validate(0x8000_0000_0000_0000),
+            Err(BuilderError::NonCanonicalAddress(_))
+        ));
+        assert!(matches!(
+            validate(0xFFFF_FFFF_FFFF_F000),
+            Err(BuilderError::KernelAddress(_))
+        ));
+    }
+}
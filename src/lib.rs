@@ -1,55 +1,88 @@
+mod audit;
+mod backend;
+mod callbacks;
 mod context;
 mod error;
+mod guard;
+mod hit_context;
 mod hwbp;
 mod hwbp_builder;
 mod hwbp_slot;
+mod manager;
+mod multiplexer;
+mod paging;
+mod range;
+mod scope;
+mod software;
+mod trace;
 mod types;
-pub use context::Context;
+mod watch;
+pub use audit::{Auditor, Drift, SlotMismatch, SlotRegisters};
+pub use backend::{Backend, DebugRegisters, NativeBackend};
+pub use callbacks::{CallbackRegistry, Dispatch};
+pub use context::{ApplyReport, Context};
 pub use error::{BuilderError, ContextError};
+pub use guard::HWBPGuard;
+pub use hit_context::{HitAction, HitContext, HitFlags, HitPredicate, MemoryReader};
 pub use hwbp::{HWBPCallback, HWBP};
 pub use hwbp_builder::HWBPBuilder;
 pub(crate) use hwbp_slot::HWBPSlot;
+pub use manager::{BreakpointId, BreakpointInfo, BreakpointManager};
+pub use multiplexer::{MultiplexPolicy, Multiplexer, VirtualBreakpoint, VirtualId};
+pub use paging::{ArmedWatch, PageWatch, PageWatcher, WatchStrategy};
+pub use range::{plan_range, RangeWatch};
+pub use scope::{Scope, ScopedBreakpoint};
+pub use software::{SoftwareBreakpoint, SoftwareBreakpoints};
+pub use trace::{Trace, TraceCallback};
 pub use types::*;
+pub use watch::{ValueChange, Watch, Watchable};
 
+#[cfg(windows)]
 pub mod windows;
+#[cfg(windows)]
+pub(crate) use windows::CONTEXT;
+#[cfg(windows)]
 use windows::EXCEPTION_POINTERS;
 
-mod callbacks;
+#[cfg(target_os = "linux")]
+pub mod linux;
+#[cfg(target_os = "linux")]
+pub(crate) use linux::{siginfo_t, CONTEXT};
+
+#[cfg(windows)]
+pub use backend::WindowsBackend;
+#[cfg(target_os = "linux")]
+pub use backend::{PerfBackend, Session, SessionEvent};
+
 mod handler;
-mod threads;
 mod x86;
 
 /// Initializes the library.
 ///
-/// This method initializes the exception handler.
+/// This method initializes the exception handler on Windows,
+/// or the `SIGTRAP` handler on Linux.
 ///
 /// If you don't wish this crate to register its own exception handler,
 /// and you have your own handler, you should not call this method,
-/// and instead call `dispatch_exception`.
+/// and instead call `dispatch_exception` (or `dispatch_signal` on Linux).
 pub fn init() {
-    handler::init();
+    NativeBackend::default().install_handler();
 }
 
 /// Frees the library.
 ///
 /// This method unregisters the exception handler.
 pub fn free() {
-    handler::free();
+    NativeBackend::default().uninstall_handler();
 }
 
 /// Frees the library and clears all hardware breakpoints.
 pub fn free_and_clear() -> Result<(), ContextError> {
-    threads::enumerate(|id| {
+    for id in NativeBackend::default().enumerate_threads()? {
         let mut ctx = Context::for_thread(id)?;
         ctx.disable_all();
         ctx.apply_for_thread(id)?;
-
-        Ok(())
-    })
-    .map_err(|x| match x {
-        threads::EnumerateError::WindowsError(e) => ContextError::EnumeratingThreadsFailed(e),
-        threads::EnumerateError::UserError(e) => e,
-    })?;
+    }
 
     free();
     Ok(())
@@ -62,6 +95,21 @@ pub fn free_and_clear() -> Result<(), ContextError> {
 ///
 /// # Return value
 /// Either EXCEPTION_CONTINUE_EXECUTION or EXCEPTION_CONTINUE_SEARCH.
+#[cfg(windows)]
 pub fn dispatch_exception(ex: &mut EXCEPTION_POINTERS) -> i32 {
-    unsafe { handler::exception_handler(ex) }
+    unsafe { backend::exception_handler(ex) }
+}
+
+/// Dispatches a `SIGTRAP` signal, or a `SIGSEGV` for page watches.
+///
+/// Breakpoint traps of other `int3`s than ours are left to you as well.
+///
+/// You should call this method from your signal handlers.
+/// If you don't have one, you should call `init`.
+///
+/// # Return value
+/// Whether the signal was caused by a hardware breakpoint or a page watch and has been handled.
+#[cfg(target_os = "linux")]
+pub fn dispatch_signal(info: &siginfo_t, context: &mut CONTEXT) -> bool {
+    unsafe { backend::signal_dispatch(info, context) }
 }
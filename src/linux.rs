@@ -0,0 +1,2 @@
+pub use libc::{siginfo_t, ucontext_t as CONTEXT, user_regs_struct};
+pub use std::io::Error;
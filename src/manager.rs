@@ -0,0 +1,325 @@
+use std::{collections::BTreeMap, fmt};
+
+use crate::{
+    Backend, BuilderError, Condition, Context, ContextError, HWBPBuilder, HitAction, HitContext,
+    Index, NativeBackend, Scope, ScopedBreakpoint, Size,
+};
+
+type Result<T> = std::result::Result<T, ContextError>;
+
+/// The id of a breakpoint in a `BreakpointManager`, which stays the same until it is removed.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct BreakpointId(u64);
+
+/// The state of a breakpoint in a `BreakpointManager`, see `BreakpointManager::list`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct BreakpointInfo {
+    pub id: BreakpointId,
+    pub scope: Scope,
+    pub address: u64,
+    pub condition: Condition,
+    pub size: Size,
+    pub is_enabled: bool,
+    pub hit_count: u64,
+    /// The threads it is armed in, and their hardware breakpoint.
+    pub indices: BTreeMap<u32, Index>,
+}
+
+/// Owns breakpoints and tells them apart by id,
+/// so that they can be enabled, disabled, changed or removed by id alone.
+///
+/// The breakpoints are scoped breakpoints, see `ScopedBreakpoint`,
+/// so every change applies to the threads they are armed in right away.
+/// Dropping the manager disables all of them, ignoring errors.
+pub struct BreakpointManager<B: Backend = NativeBackend> {
+    backend: B,
+    breakpoints: BTreeMap<BreakpointId, ScopedBreakpoint<B>>,
+    next_id: u64,
+}
+
+impl<B: Backend> fmt::Debug for BreakpointManager<B> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BreakpointManager")
+            .field("breakpoints", &self.breakpoints)
+            .finish_non_exhaustive()
+    }
+}
+
+impl BreakpointManager {
+    /// Creates a manager for the breakpoints of the current process.
+    pub fn new() -> Self {
+        Self::new_with(NativeBackend::default())
+    }
+}
+
+impl Default for BreakpointManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<B: Backend + Clone> BreakpointManager<B> {
+    /// Creates a manager for the breakpoints of the threads of a specific backend.
+    pub fn new_with(backend: B) -> Self {
+        Self {
+            backend,
+            breakpoints: BTreeMap::new(),
+            next_id: 0,
+        }
+    }
+
+    /// Adds an enabled breakpoint watching an address for a condition in every thread of a scope.
+    ///
+    /// Like with `HWBPBuilder`, the size of execute breakpoints is one byte.
+    /// For anything else the builder offers, see `add_with`.
+    pub fn add<A: Into<HitAction>>(
+        &mut self,
+        scope: Scope,
+        address: u64,
+        condition: Condition,
+        size: Size,
+        callback: impl Fn(&mut HitContext<'_, B::Registers>) -> A + Send + Sync + 'static,
+    ) -> std::result::Result<BreakpointId, BuilderError> {
+        self.add_with(scope, |builder| {
+            builder
+                .watch_memory(address as *const u8, condition, size, callback)
+                .with_enabled(true)
+        })
+    }
+
+    /// Adds a breakpoint for every thread of a scope, described by a builder,
+    /// see `HWBPBuilder::build_scoped`.
+    pub fn add_with(
+        &mut self,
+        scope: Scope,
+        build: impl FnOnce(HWBPBuilder<'_, B>) -> HWBPBuilder<'_, B>,
+    ) -> std::result::Result<BreakpointId, BuilderError> {
+        let mut context =
+            Context::current_with(self.backend.clone()).map_err(BuilderError::ApplyFailed)?;
+        let breakpoint = build(context.builder()).build_scoped(scope)?;
+        Ok(self.insert(breakpoint))
+    }
+
+    /// Takes over a breakpoint built by `HWBPBuilder::build_scoped`.
+    pub fn insert(&mut self, breakpoint: ScopedBreakpoint<B>) -> BreakpointId {
+        let id = BreakpointId(self.next_id);
+        self.next_id += 1;
+        self.breakpoints.insert(id, breakpoint);
+        id
+    }
+
+    /// Gets a breakpoint by id.
+    pub fn get(&self, id: BreakpointId) -> Option<&ScopedBreakpoint<B>> {
+        self.breakpoints.get(&id)
+    }
+
+    /// Enables a breakpoint, see `ScopedBreakpoint::enable`.
+    pub fn enable(&mut self, id: BreakpointId) -> Result<()> {
+        self.get_mut(id)?.enable()
+    }
+
+    /// Disables a breakpoint, see `ScopedBreakpoint::disable`.
+    pub fn disable(&mut self, id: BreakpointId) -> Result<()> {
+        self.get_mut(id)?.disable()
+    }
+
+    /// Changes what a breakpoint watches, see `ScopedBreakpoint::modify`.
+    pub fn modify(
+        &mut self,
+        id: BreakpointId,
+        address: u64,
+        condition: Condition,
+        size: Size,
+    ) -> std::result::Result<(), BuilderError> {
+        self.get_mut(id)
+            .map_err(BuilderError::ApplyFailed)?
+            .modify(address, condition, size)
+    }
+
+    /// Removes a breakpoint, disabling it in every thread it is armed in.
+    ///
+    /// If it cannot be disabled in a thread, it is kept.
+    pub fn remove(&mut self, id: BreakpointId) -> Result<()> {
+        self.get_mut(id)?.disable()?;
+        self.breakpoints.remove(&id);
+        Ok(())
+    }
+
+    /// Removes every breakpoint, see `remove`.
+    pub fn clear(&mut self) -> Result<()> {
+        let ids = self.breakpoints.keys().copied().collect::<Vec<_>>();
+        ids.into_iter().try_for_each(|id| self.remove(id))
+    }
+
+    /// Arms the enabled breakpoints in the threads created since,
+    /// see `ScopedBreakpoint::rescan`.
+    pub fn rescan(&mut self) -> Result<()> {
+        self.breakpoints
+            .values_mut()
+            .try_for_each(ScopedBreakpoint::rescan)
+    }
+
+    /// Lists the breakpoints and their state, in the order they were added.
+    pub fn list(&self) -> Vec<BreakpointInfo> {
+        self.breakpoints
+            .iter()
+            .map(|(id, breakpoint)| BreakpointInfo {
+                id: *id,
+                scope: breakpoint.get_scope().clone(),
+                address: breakpoint.get_address(),
+                condition: breakpoint.get_condition(),
+                size: breakpoint.get_size(),
+                is_enabled: breakpoint.is_enabled(),
+                hit_count: breakpoint.get_hit_count(),
+                indices: breakpoint.get_indices().clone(),
+            })
+            .collect()
+    }
+
+    fn get_mut(&mut self, id: BreakpointId) -> Result<&mut ScopedBreakpoint<B>> {
+        self.breakpoints
+            .get_mut(&id)
+            .ok_or(ContextError::UnknownBreakpoint(id))
+    }
+}
+
+impl<B: Backend> Drop for BreakpointManager<B> {
+    fn drop(&mut self) {
+        for breakpoint in self.breakpoints.values_mut() {
+            _ = breakpoint.disable();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::FakeBackend;
+
+    /// Adds a breakpoint watching 4 bytes at `address` in every thread.
+    fn add(manager: &mut BreakpointManager<&FakeBackend>, address: u64) -> BreakpointId {
+        manager
+            .add(
+                Scope::Process,
+                address,
+                Condition::Write,
+                Size::FourBytes,
+                |_| {},
+            )
+            .unwrap()
+    }
+
+    /// Gets the address a hardware breakpoint of a thread is enabled for, if it is.
+    fn get_address(backend: &FakeBackend, thread_id: u32, index: Index) -> Option<u64> {
+        let registers = backend.get_debug_registers(thread_id).unwrap();
+        registers
+            .is_enabled(index)
+            .then_some(registers.address[index as usize])
+    }
+
+    #[test]
+    fn by_id() {
+        let backend = FakeBackend::new(&[1, 2]);
+        let mut manager = BreakpointManager::new_with(&backend);
+        let first = add(&mut manager, 0x1000);
+        let second = add(&mut manager, 0x2000);
+        assert_ne!(first, second);
+        for thread_id in [1, 2] {
+            assert_eq!(get_address(&backend, thread_id, Index::First), Some(0x1000));
+            assert_eq!(
+                get_address(&backend, thread_id, Index::Second),
+                Some(0x2000)
+            );
+        }
+
+        // Every change only applies to the breakpoint with the id.
+        manager.disable(first).unwrap();
+        assert!(!manager.get(first).unwrap().is_enabled());
+        assert!(manager.get(second).unwrap().is_enabled());
+        assert_eq!(get_address(&backend, 1, Index::First), None);
+        assert_eq!(get_address(&backend, 1, Index::Second), Some(0x2000));
+
+        manager
+            .modify(second, 0x3000, Condition::ReadWrite, Size::FourBytes)
+            .unwrap();
+        assert_eq!(get_address(&backend, 2, Index::Second), Some(0x3000));
+
+        manager.enable(first).unwrap();
+        assert_eq!(get_address(&backend, 2, Index::First), Some(0x1000));
+
+        let list = manager.list();
+        assert_eq!(list.len(), 2);
+        assert_eq!((list[0].id, list[0].address), (first, 0x1000));
+        assert_eq!((list[1].id, list[1].address), (second, 0x3000));
+        assert_eq!(list[1].condition, Condition::ReadWrite);
+
+        manager.remove(first).unwrap();
+        assert!(manager.get(first).is_none());
+        assert_eq!(get_address(&backend, 1, Index::First), None);
+        assert_eq!(get_address(&backend, 1, Index::Second), Some(0x3000));
+
+        // Ids are not reused.
+        let third = add(&mut manager, 0x4000);
+        assert!(third != first && third != second);
+    }
+
+    #[test]
+    fn unknown_id() {
+        let backend = FakeBackend::new(&[1]);
+        let mut manager = BreakpointManager::new_with(&backend);
+        let id = add(&mut manager, 0x1000);
+        manager.remove(id).unwrap();
+
+        assert!(matches!(
+            manager.enable(id),
+            Err(ContextError::UnknownBreakpoint(x)) if x == id
+        ));
+        assert!(matches!(
+            manager.disable(id),
+            Err(ContextError::UnknownBreakpoint(_))
+        ));
+        assert!(matches!(
+            manager.remove(id),
+            Err(ContextError::UnknownBreakpoint(_))
+        ));
+        assert!(matches!(
+            manager.modify(id, 0x2000, Condition::Write, Size::FourBytes),
+            Err(BuilderError::ApplyFailed(ContextError::UnknownBreakpoint(
+                _
+            )))
+        ));
+    }
+
+    #[test]
+    fn remove_keeps_failing() {
+        let backend = FakeBackend::new(&[1, 2]);
+        let mut manager = BreakpointManager::new_with(&backend);
+        let id = add(&mut manager, 0x1000);
+
+        // It is kept until it can be disabled in every thread.
+        backend.set_failing(Some(2));
+        assert!(manager.remove(id).is_err());
+        assert_eq!(manager.get(id).unwrap().get_indices().len(), 1);
+        assert_eq!(get_address(&backend, 1, Index::First), None);
+
+        backend.set_failing(None);
+        manager.remove(id).unwrap();
+        assert!(manager.list().is_empty());
+        assert_eq!(get_address(&backend, 2, Index::First), None);
+    }
+
+    #[test]
+    fn drop_disables() {
+        let backend = FakeBackend::new(&[1, 2]);
+        let mut manager = BreakpointManager::new_with(&backend);
+        add(&mut manager, 0x1000);
+        add(&mut manager, 0x2000);
+
+        drop(manager);
+        for thread_id in [1, 2] {
+            for index in [Index::First, Index::Second] {
+                assert_eq!(get_address(&backend, thread_id, index), None);
+            }
+        }
+    }
+}
@@ -0,0 +1,382 @@
+use std::{fmt, sync::Arc};
+
+use crate::{
+    hwbp::{into_callback, HitFilter, Hook},
+    Backend, BuilderError, Condition, Context, HWBPSlot, HitAction, HitContext, Index,
+    NativeBackend, Size,
+};
+
+/// The id of a virtual breakpoint in a `Multiplexer`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct VirtualId(u64);
+
+/// How a `Multiplexer` picks the virtual breakpoints to arm.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum MultiplexPolicy {
+    /// Arms the ones with the highest priority.
+    #[default]
+    Priority,
+    /// Arms the next ones on every `Multiplexer::rearm`,
+    /// which is meant to be called periodically to slice time between them.
+    RoundRobin,
+    /// Arms the ones that were hit most recently,
+    /// then the ones with the highest priority.
+    MostRecentlyHit,
+}
+
+/// A breakpoint that a `Multiplexer` arms in a hardware breakpoint
+/// whenever its policy picks it.
+pub struct VirtualBreakpoint<R> {
+    slot: HWBPSlot,
+    priority: i32,
+    hook: Hook<R>,
+}
+
+impl<R> fmt::Debug for VirtualBreakpoint<R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("VirtualBreakpoint")
+            .field("slot", &self.slot)
+            .field("priority", &self.priority)
+            .field("callback", &Arc::as_ptr(&self.hook.callback))
+            .finish()
+    }
+}
+
+impl<R> VirtualBreakpoint<R> {
+    /// Creates a virtual breakpoint watching an address for a condition.
+    ///
+    /// Like with `HWBPBuilder`, the size of execute breakpoints is one byte.
+    pub fn new<A: Into<HitAction>>(
+        address: u64,
+        condition: Condition,
+        size: Size,
+        callback: impl Fn(&mut HitContext<'_, R>) -> A + Send + Sync + 'static,
+    ) -> Self {
+        let size = match condition {
+            Condition::Execute => Size::OneByte,
+            _ => size,
+        };
+
+        Self {
+            slot: HWBPSlot {
+                is_enabled: true,
+                address,
+                condition,
+                size,
+            },
+            priority: 0,
+            hook: Hook {
+                callback: into_callback(callback),
+                filter: Arc::new(HitFilter::default()),
+                arm: None,
+            },
+        }
+    }
+
+    /// Sets the priority of the virtual breakpoint, 0 by default.
+    pub fn with_priority(mut self, priority: i32) -> Self {
+        self.priority = priority;
+        self
+    }
+}
+
+/// Multiplexes any number of virtual breakpoints onto the four hardware breakpoints
+/// of a `Context`, but the foreign and reserved ones, see `Context::get_ownership`.
+///
+/// Every virtual breakpoint keeps its callback and hit count while it is not armed.
+/// `rearm` picks the ones to arm according to the policy,
+/// which then, like any other breakpoint, take effect once the context is applied.
+pub struct Multiplexer<B: Backend = NativeBackend> {
+    context: Context<B>,
+    policy: MultiplexPolicy,
+    breakpoints: Vec<(VirtualId, VirtualBreakpoint<B::Registers>)>,
+    live: [Option<VirtualId>; 4],
+    next_id: u64,
+    cursor: usize,
+}
+
+impl<B: Backend> fmt::Debug for Multiplexer<B>
+where
+    Context<B>: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Multiplexer")
+            .field("context", &self.context)
+            .field("policy", &self.policy)
+            .field("breakpoints", &self.breakpoints)
+            .field("live", &self.live)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<B: Backend> Multiplexer<B> {
+    /// Creates a multiplexer taking over every available hardware breakpoint of the context.
+    pub fn new(context: Context<B>, policy: MultiplexPolicy) -> Self {
+        Self {
+            context,
+            policy,
+            breakpoints: Vec::new(),
+            live: [None; 4],
+            next_id: 0,
+            cursor: 0,
+        }
+    }
+
+    /// Gets the context the virtual breakpoints are armed in.
+    pub fn context(&self) -> &Context<B> {
+        &self.context
+    }
+
+    /// Gets the policy picking the virtual breakpoints to arm.
+    pub fn get_policy(&self) -> MultiplexPolicy {
+        self.policy
+    }
+
+    /// Sets the policy picking the virtual breakpoints to arm.
+    pub fn set_policy(&mut self, policy: MultiplexPolicy) {
+        self.policy = policy;
+    }
+
+    /// Adds a virtual breakpoint, armed by the next `rearm` if the policy picks it.
+    ///
+    /// Fails if the breakpoint does not pass the checks of `HWBPBuilder::build_and_set`.
+    pub fn add(
+        &mut self,
+        breakpoint: VirtualBreakpoint<B::Registers>,
+    ) -> Result<VirtualId, BuilderError> {
+        self.context.validate_slot(&breakpoint.slot)?;
+
+        let id = VirtualId(self.next_id);
+        self.next_id += 1;
+        self.breakpoints.push((id, breakpoint));
+        Ok(id)
+    }
+
+    /// Removes a virtual breakpoint, disarmed by the next `rearm`.
+    ///
+    /// Returns whether it existed.
+    pub fn remove(&mut self, id: VirtualId) -> bool {
+        let len = self.breakpoints.len();
+        self.breakpoints.retain(|(x, _)| *x != id);
+        self.breakpoints.len() != len
+    }
+
+    /// Gets how many times a virtual breakpoint was hit,
+    /// counting every time it was armed.
+    pub fn get_hit_count(&self, id: VirtualId) -> Option<u64> {
+        self.get(id).map(|x| x.hook.filter.get_hit_count())
+    }
+
+    /// Gets whether a virtual breakpoint is armed in a hardware breakpoint,
+    /// as of the last `rearm`.
+    pub fn is_live(&self, id: VirtualId) -> bool {
+        self.live.contains(&Some(id))
+    }
+
+    /// Gets the virtual breakpoint armed in every hardware breakpoint,
+    /// as of the last `rearm`.
+    pub fn get_live(&self) -> [Option<VirtualId>; 4] {
+        self.live
+    }
+
+    /// Picks the virtual breakpoints to arm according to the policy,
+    /// and sets them to the context.
+    ///
+    /// Breakpoints that stay armed keep their hardware breakpoint.
+    /// The context still has to be applied to the threads.
+    pub fn rearm(&mut self) {
+        // Foreign and reserved hardware breakpoints are not ours to take.
+        let available = Index::ALL.map(|index| {
+            self.live[index as usize].is_some() || self.context.is_available(index, None)
+        });
+        let picked = self.pick(available.iter().filter(|x| **x).count());
+
+        // Keep the ones that stay where they are, and fill the rest in order.
+        let mut live = self.live.map(|x| x.filter(|x| picked.contains(x)));
+        for id in picked {
+            if !live.contains(&Some(id)) {
+                let free = live
+                    .iter_mut()
+                    .zip(available)
+                    .find(|(x, is_available)| x.is_none() && *is_available);
+                if let Some((free, _)) = free {
+                    *free = Some(id);
+                }
+            }
+        }
+
+        for (index, id) in Index::ALL.into_iter().zip(live) {
+            if !available[index as usize] {
+                continue;
+            }
+
+            match id.and_then(|id| self.get(id)) {
+                Some(breakpoint) => {
+                    self.context.build_and_set_hwbp(
+                        index,
+                        breakpoint.slot,
+                        breakpoint.hook.clone(),
+                    );
+                }
+                None => {
+                    let mut hwbp = self.context.get(index);
+                    hwbp.disable();
+                    self.context.set(&hwbp);
+                }
+            }
+        }
+
+        self.live = live;
+    }
+
+    fn get(&self, id: VirtualId) -> Option<&VirtualBreakpoint<B::Registers>> {
+        self.breakpoints
+            .iter()
+            .find(|(x, _)| *x == id)
+            .map(|(_, breakpoint)| breakpoint)
+    }
+
+    /// Gets the ids of up to `count` virtual breakpoints to arm.
+    fn pick(&mut self, count: usize) -> Vec<VirtualId> {
+        let mut ids = self.breakpoints.iter().collect::<Vec<_>>();
+        match self.policy {
+            MultiplexPolicy::Priority => {
+                ids.sort_by_key(|(id, x)| (std::cmp::Reverse(x.priority), *id));
+            }
+            MultiplexPolicy::MostRecentlyHit => {
+                ids.sort_by_key(|(id, x)| {
+                    (
+                        std::cmp::Reverse(x.hook.filter.get_last_hit()),
+                        std::cmp::Reverse(x.priority),
+                        *id,
+                    )
+                });
+            }
+            MultiplexPolicy::RoundRobin => {
+                let len = ids.len().max(1);
+                self.cursor %= len;
+                ids.rotate_left(self.cursor);
+                self.cursor += count;
+            }
+        }
+
+        ids.into_iter().take(count).map(|(id, _)| *id).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::FakeBackend;
+
+    /// Creates a multiplexer with a virtual breakpoint for every priority, in order.
+    fn multiplexer<'a>(
+        backend: &'a FakeBackend,
+        policy: MultiplexPolicy,
+        priorities: &[i32],
+    ) -> (Multiplexer<&'a FakeBackend>, Vec<VirtualId>) {
+        let context = Context::current_with(backend).unwrap();
+        let mut multiplexer = Multiplexer::new(context, policy);
+        let ids = priorities
+            .iter()
+            .enumerate()
+            .map(|(n, priority)| {
+                let address = 0x1000 + n as u64 * 4;
+                let breakpoint =
+                    VirtualBreakpoint::new(address, Condition::Write, Size::FourBytes, |_| {})
+                        .with_priority(*priority);
+                multiplexer.add(breakpoint).unwrap()
+            })
+            .collect();
+        (multiplexer, ids)
+    }
+
+    #[test]
+    fn priority() {
+        let backend = FakeBackend::new(&[1]);
+        let (mut multiplexer, ids) =
+            multiplexer(&backend, MultiplexPolicy::Priority, &[0, 5, 1, 5, 2, 0]);
+
+        multiplexer.rearm();
+        let live = [ids[1], ids[3], ids[4], ids[2]].map(Some);
+        assert_eq!(multiplexer.get_live(), live);
+        assert!(!multiplexer.is_live(ids[0]) && !multiplexer.is_live(ids[5]));
+
+        // The ones that stay armed keep their hardware breakpoint.
+        assert!(multiplexer.remove(ids[3]));
+        multiplexer.rearm();
+        let live = [ids[1], ids[0], ids[4], ids[2]].map(Some);
+        assert_eq!(multiplexer.get_live(), live);
+
+        multiplexer.context.apply_for_current_thread().unwrap();
+        let registers = backend.get_debug_registers(1).unwrap();
+        for (index, id) in Index::ALL.into_iter().zip(live) {
+            let address = multiplexer.get(id.unwrap()).unwrap().slot.address;
+            assert!(registers.is_enabled(index));
+            assert_eq!(registers.address[index as usize], address);
+        }
+    }
+
+    #[test]
+    fn round_robin() {
+        let backend = FakeBackend::new(&[1]);
+        let (mut multiplexer, ids) = multiplexer(&backend, MultiplexPolicy::RoundRobin, &[0; 6]);
+
+        multiplexer.rearm();
+        assert_eq!(multiplexer.get_live(), [0, 1, 2, 3].map(|x| Some(ids[x])));
+
+        // The next ones wrap around, and the ones armed again keep their place.
+        multiplexer.rearm();
+        assert_eq!(multiplexer.get_live(), [0, 1, 4, 5].map(|x| Some(ids[x])));
+
+        multiplexer.rearm();
+        assert_eq!(multiplexer.get_live(), [2, 3, 4, 5].map(|x| Some(ids[x])));
+    }
+
+    #[test]
+    fn most_recently_hit() {
+        let backend = FakeBackend::new(&[1]);
+        let (mut multiplexer, ids) =
+            multiplexer(&backend, MultiplexPolicy::MostRecentlyHit, &[0, 0, 0, 0, 5]);
+
+        multiplexer.rearm();
+        assert_eq!(multiplexer.get_live(), [4, 0, 1, 2].map(|x| Some(ids[x])));
+        multiplexer.context.apply_for_current_thread().unwrap();
+
+        let registers = backend.get_debug_registers(1).unwrap();
+        let dr6 = 1 << Index::Fourth as u64;
+        backend
+            .callbacks()
+            .dispatch(1, dr6, &registers, 0x4000, &mut ());
+        assert_eq!(multiplexer.get_hit_count(ids[2]), Some(1));
+
+        // The one that was hit goes ahead of the ones with a higher priority.
+        let added = [0x2000, 0x2004, 0x2008].map(|address| {
+            let breakpoint =
+                VirtualBreakpoint::new(address, Condition::Write, Size::FourBytes, |_| {});
+            multiplexer.add(breakpoint.with_priority(9)).unwrap()
+        });
+        multiplexer.rearm();
+        assert_eq!(
+            multiplexer.get_live(),
+            [added[0], added[1], added[2], ids[2]].map(Some)
+        );
+    }
+
+    #[test]
+    fn skips_reserved() {
+        let backend = FakeBackend::new(&[1]);
+        let (mut multiplexer, ids) = multiplexer(&backend, MultiplexPolicy::Priority, &[0; 4]);
+        multiplexer
+            .context()
+            .reserve(Index::Second, "other")
+            .unwrap();
+
+        multiplexer.rearm();
+        assert_eq!(
+            multiplexer.get_live(),
+            [Some(ids[0]), None, Some(ids[1]), Some(ids[2])]
+        );
+        assert!(!multiplexer.context().get(Index::Second).is_enabled());
+    }
+}
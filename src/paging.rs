@@ -0,0 +1,765 @@
+use std::{
+    cell::Cell,
+    fmt, io,
+    mem::ManuallyDrop,
+    ops::{Deref, DerefMut},
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicU8, AtomicUsize, Ordering},
+        Arc, RwLock, RwLockReadGuard, RwLockWriteGuard, TryLockError,
+    },
+};
+
+use crate::{
+    hwbp::Hook, Condition, HWBPSlot, HitAction, HitContext, HitFlags, Index, MemoryReader, Size,
+    SoftwareBreakpoint, CONTEXT, HWBP,
+};
+
+/// The size of the pages protected by page watches.
+pub(crate) const PAGE_SIZE: u64 = 0x1000;
+
+/// How a watch is armed.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum WatchStrategy {
+    /// With debug registers only.
+    #[default]
+    Hardware,
+    /// With page protection only, see `PageWatch`.
+    Paging,
+    /// With `int3` patches for execute breakpoints, see `SoftwareBreakpoint`.
+    Software,
+    /// With debug registers if there are enough unused ones,
+    /// otherwise with `int3` patches for execute breakpoints,
+    /// and with page protection for the others.
+    Fallback,
+}
+
+/// Changes the protection of a page so that it faults on accesses a condition watches,
+/// or restores it for `None`.
+pub(crate) type SetProtection = fn(page: u64, condition: Option<Condition>) -> io::Result<()>;
+
+struct Entry<R> {
+    id: u64,
+    address: u64,
+    len: u64,
+    condition: Condition,
+    hook: Hook<R>,
+    is_enabled: Arc<AtomicBool>,
+}
+
+impl<R> Clone for Entry<R> {
+    fn clone(&self) -> Self {
+        Self {
+            id: self.id,
+            address: self.address,
+            len: self.len,
+            condition: self.condition,
+            hook: self.hook.clone(),
+            is_enabled: self.is_enabled.clone(),
+        }
+    }
+}
+
+impl<R> Entry<R> {
+    fn overlaps(&self, page: u64) -> bool {
+        self.address < page + PAGE_SIZE && page < self.address + self.len
+    }
+}
+
+/// How many pages can be protected at once.
+const MAX_PAGES: usize = 64;
+/// How many threads can single-step over faults at once.
+const MAX_PENDING: usize = 64;
+/// How many pages a single instruction can fault on, as when it straddles pages.
+const MAX_FAULTS: usize = 4;
+/// How many watches a single instruction can hit, the others are missed.
+const MAX_HITS: usize = 8;
+
+/// The faults a thread is single-stepping over, with their pages unprotected.
+///
+/// Faults are handled in signal handlers, which must not allocate,
+/// so this is preallocated and only touched by the thread that claimed it.
+struct Pending {
+    /// The thread that claimed it, 0 if none.
+    thread_id: AtomicU32,
+    pages: [AtomicU64; MAX_FAULTS],
+    page_count: AtomicUsize,
+    /// The id of every watch hit, and the address it was hit at.
+    hits: [(AtomicU64, AtomicU64); MAX_HITS],
+    hit_count: AtomicUsize,
+}
+
+impl Pending {
+    fn new() -> Self {
+        Self {
+            thread_id: AtomicU32::new(0),
+            pages: Default::default(),
+            page_count: AtomicUsize::new(0),
+            hits: Default::default(),
+            hit_count: AtomicUsize::new(0),
+        }
+    }
+
+    fn push_page(&self, page: u64) -> bool {
+        let count = self.page_count.load(Ordering::Relaxed);
+        if count == MAX_FAULTS {
+            return false;
+        }
+
+        self.pages[count].store(page, Ordering::Relaxed);
+        self.page_count.store(count + 1, Ordering::Relaxed);
+        true
+    }
+
+    fn push_hit(&self, id: u64, address: u64) {
+        let count = self.hit_count.load(Ordering::Relaxed);
+        if count < MAX_HITS {
+            self.hits[count].0.store(id, Ordering::Relaxed);
+            self.hits[count].1.store(address, Ordering::Relaxed);
+            self.hit_count.store(count + 1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// A protected page, and the accesses its protection catches.
+struct Protected {
+    /// The page, 0 for none.
+    page: AtomicU64,
+    condition: AtomicU8,
+}
+
+impl Protected {
+    fn new() -> Self {
+        Self {
+            page: AtomicU64::new(0),
+            condition: AtomicU8::new(0),
+        }
+    }
+}
+
+thread_local! {
+    /// Whether the thread holds the entries for writing,
+    /// so that its fault handler does not wait for itself.
+    static IS_WRITING: Cell<bool> = const { Cell::new(false) };
+}
+
+/// The entries locked for writing, see `Inner::write`.
+struct EntriesWriteGuard<'a, R> {
+    lock: ManuallyDrop<RwLockWriteGuard<'a, Vec<Entry<R>>>>,
+}
+
+impl<R> Deref for EntriesWriteGuard<'_, R> {
+    type Target = Vec<Entry<R>>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.lock
+    }
+}
+
+impl<R> DerefMut for EntriesWriteGuard<'_, R> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.lock
+    }
+}
+
+impl<R> Drop for EntriesWriteGuard<'_, R> {
+    fn drop(&mut self) {
+        // Unlocked first, so that the handler never finds the lock taken with the flag cleared.
+        unsafe { ManuallyDrop::drop(&mut self.lock) };
+        IS_WRITING.set(false);
+    }
+}
+
+/// Page-aligned, so that it never shares a page with watched memory,
+/// which the fault handlers could not touch before unprotecting it.
+/// The buffer of the entries may still be on a watched page.
+#[repr(align(4096))]
+struct Inner<R> {
+    set_protection: SetProtection,
+    entries: RwLock<Vec<Entry<R>>>,
+    protected: [Protected; MAX_PAGES],
+    pending: [Pending; MAX_PENDING],
+    /// The ids of the watches removed from the handler, plus one, 0 for none.
+    /// They are removed when the entries are next locked for writing.
+    removed: [AtomicU64; MAX_PAGES],
+    next_id: AtomicU64,
+}
+
+impl<R> Inner<R> {
+    /// Reads the entries, from the handler too.
+    ///
+    /// It waits for other threads to release the lock without blocking,
+    /// and gives up if the calling thread holds it, having faulted meanwhile.
+    fn read(&self) -> Option<RwLockReadGuard<'_, Vec<Entry<R>>>> {
+        loop {
+            match self.entries.try_read() {
+                Ok(entries) => return Some(entries),
+                Err(TryLockError::WouldBlock) if !IS_WRITING.get() => std::thread::yield_now(),
+                Err(_) => return None,
+            }
+        }
+    }
+
+    /// Locks the entries for writing, outside the handler,
+    /// and carries out the removals deferred by the handler.
+    fn write(&self) -> io::Result<EntriesWriteGuard<'_, R>> {
+        // Flagged beforehand, for the handler may interrupt the thread as soon as it locks.
+        IS_WRITING.set(true);
+        let Ok(lock) = self.entries.write() else {
+            IS_WRITING.set(false);
+            return Err(io::Error::other("poisoned"));
+        };
+
+        let mut entries = EntriesWriteGuard {
+            lock: ManuallyDrop::new(lock),
+        };
+        for removed in &self.removed {
+            let id = removed.swap(0, Ordering::AcqRel);
+            if id != 0 {
+                entries.retain(|x| x.id != id - 1);
+            }
+        }
+
+        Ok(entries)
+    }
+
+    /// Removes a watch once the entries are next locked for writing, from the handler.
+    ///
+    /// If too many removals wait already, it stays, disabled.
+    fn defer_remove(&self, id: u64) {
+        self.removed.iter().any(|x| {
+            x.compare_exchange(0, id + 1, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok()
+        });
+    }
+
+    /// Gets the condition the protection of a page has to catch, if any.
+    ///
+    /// If the calling thread holds the entries, the page keeps its protection.
+    fn get_condition(&self, page: u64) -> Option<Condition> {
+        let Some(entries) = self.read() else {
+            return self.get_protection(page);
+        };
+        entries
+            .iter()
+            .filter(|x| x.is_enabled.load(Ordering::Relaxed) && x.overlaps(page))
+            .map(|x| x.condition)
+            .max_by_key(|x| *x == Condition::ReadWrite)
+    }
+
+    /// Gets the condition a page is protected for, if it is.
+    fn get_protection(&self, page: u64) -> Option<Condition> {
+        self.find_protected(page)
+            .map(|x| Condition::from_bits(x.condition.load(Ordering::Acquire)))
+    }
+
+    fn find_protected(&self, page: u64) -> Option<&Protected> {
+        self.protected
+            .iter()
+            .find(|x| x.page.load(Ordering::Acquire) == page)
+    }
+
+    fn is_protected(&self, page: u64) -> bool {
+        self.find_protected(page).is_some()
+    }
+
+    /// Protects a page according to the watches overlapping it, or unprotects it.
+    ///
+    /// Pages are marked as protected before they are, and unprotected before they are not,
+    /// so that the fault handlers always recognize them.
+    fn protect_page(&self, page: u64) -> io::Result<()> {
+        let condition = self.get_condition(page);
+        if let Some(condition) = condition {
+            let protected = self.find_protected(page).or_else(|| {
+                self.protected.iter().find(|x| {
+                    x.page
+                        .compare_exchange(0, page, Ordering::AcqRel, Ordering::Acquire)
+                        .is_ok()
+                })
+            });
+            let Some(protected) = protected else {
+                return Err(io::Error::other("too many protected pages"));
+            };
+            protected
+                .condition
+                .store(condition.into_bits(), Ordering::Release);
+        }
+
+        (self.set_protection)(page, condition)?;
+        if condition.is_none() {
+            for protected in &self.protected {
+                _ = protected
+                    .page
+                    .compare_exchange(page, 0, Ordering::AcqRel, Ordering::Acquire);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn protect(&self, address: u64, len: u64) -> io::Result<()> {
+        let start = address & !(PAGE_SIZE - 1);
+        for page in (start..address + len).step_by(PAGE_SIZE as usize) {
+            self.protect_page(page)?;
+        }
+
+        Ok(())
+    }
+
+    fn remove(&self, id: u64) {
+        let entry = {
+            let Ok(mut entries) = self.write() else {
+                return;
+            };
+            let Some(position) = entries.iter().position(|x| x.id == id) else {
+                return;
+            };
+            entries.remove(position)
+        };
+
+        _ = self.protect(entry.address, entry.len);
+    }
+
+    /// Gets the pending faults of a thread, claiming them if asked to.
+    fn get_pending(&self, thread_id: u32, is_claimed: bool) -> Option<&Pending> {
+        let pending = self
+            .pending
+            .iter()
+            .find(|x| x.thread_id.load(Ordering::Acquire) == thread_id);
+        if pending.is_some() || !is_claimed {
+            return pending;
+        }
+
+        self.pending.iter().find(|x| {
+            x.thread_id
+                .compare_exchange(0, thread_id, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok()
+        })
+    }
+}
+
+/// Watches memory of the current process by protecting its pages,
+/// for when debug registers are not enough.
+///
+/// Accesses to protected pages fault, which unprotects the page
+/// and single-steps the access before protecting it again.
+/// Only accesses inside a watched range call its callback, after they complete,
+/// like with hardware breakpoints.
+///
+/// This is much slower than debug registers, since every access to the pages faults,
+/// and other threads can access the page unnoticed while it is unprotected.
+/// Up to 64 pages can be protected at once.
+pub struct PageWatcher<R = CONTEXT> {
+    inner: Arc<Inner<R>>,
+}
+
+impl<R> fmt::Debug for PageWatcher<R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PageWatcher").finish_non_exhaustive()
+    }
+}
+
+impl<R> PageWatcher<R> {
+    pub(crate) fn new(set_protection: SetProtection) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                set_protection,
+                entries: RwLock::new(Vec::new()),
+                protected: std::array::from_fn(|_| Protected::new()),
+                pending: std::array::from_fn(|_| Pending::new()),
+                removed: std::array::from_fn(|_| AtomicU64::new(0)),
+                next_id: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    /// Watches `len` bytes at `address` for a condition.
+    pub(crate) fn watch(
+        &self,
+        address: u64,
+        len: u64,
+        condition: Condition,
+        hook: Hook<R>,
+    ) -> io::Result<PageWatch<R>> {
+        let entry = Entry {
+            id: self.inner.next_id.fetch_add(1, Ordering::Relaxed),
+            address,
+            len,
+            condition,
+            hook,
+            is_enabled: Arc::new(AtomicBool::new(true)),
+        };
+
+        self.inner.write()?.push(entry.clone());
+        if let Err(error) = self.inner.protect(address, len) {
+            self.inner.remove(entry.id);
+            return Err(error);
+        }
+
+        Ok(PageWatch {
+            watcher: self.inner.clone(),
+            entry,
+        })
+    }
+
+    /// Handles an access fault of the current thread, without allocating.
+    ///
+    /// # Return value
+    /// Whether the page is watched, in which case it was unprotected
+    /// and the thread has to single-step the access.
+    pub(crate) fn on_fault(&self, thread_id: u32, address: u64, is_write: bool) -> bool {
+        let page = address & !(PAGE_SIZE - 1);
+        if !self.inner.is_protected(page) {
+            return false;
+        }
+
+        // Unprotect it first, the allocator or the locks below may be on it.
+        if (self.inner.set_protection)(page, None).is_err() {
+            return false;
+        }
+
+        let Some(pending) = self.inner.get_pending(thread_id, true) else {
+            _ = (self.inner.set_protection)(page, self.inner.get_protection(page));
+            return false;
+        };
+        if !pending.push_page(page) {
+            return false;
+        }
+
+        if let Some(entries) = self.inner.read() {
+            entries
+                .iter()
+                .filter(|x| x.is_enabled.load(Ordering::Relaxed))
+                .filter(|x| (x.address..x.address + x.len).contains(&address))
+                .filter(|x| is_write || x.condition == Condition::ReadWrite)
+                .for_each(|x| pending.push_hit(x.id, address));
+        }
+
+        true
+    }
+
+    /// Gets whether the current thread is single-stepping over a fault.
+    pub(crate) fn is_pending(&self, thread_id: u32) -> bool {
+        self.inner.get_pending(thread_id, false).is_some()
+    }
+
+    /// Handles the single step over a fault of the current thread,
+    /// protecting its pages again and calling the callbacks of the watches it hit.
+    ///
+    /// # Return value
+    /// Whether the thread was single-stepping over a fault,
+    /// and whether a callback asked to break.
+    pub(crate) fn on_step(
+        &self,
+        thread_id: u32,
+        ip: u64,
+        registers: &mut R,
+        memory: Option<&MemoryReader>,
+    ) -> Option<bool> {
+        let pending = self.inner.get_pending(thread_id, false)?;
+        let pages = pending.page_count.swap(0, Ordering::Relaxed);
+        let hits = pending.hit_count.swap(0, Ordering::Relaxed);
+        let pages: [u64; MAX_FAULTS] = std::array::from_fn(|i| match i < pages {
+            true => pending.pages[i].load(Ordering::Relaxed),
+            false => 0,
+        });
+        let hits: [(u64, u64); MAX_HITS] = std::array::from_fn(|i| match i < hits {
+            true => (
+                pending.hits[i].0.load(Ordering::Relaxed),
+                pending.hits[i].1.load(Ordering::Relaxed),
+            ),
+            false => (u64::MAX, 0),
+        });
+        pending.thread_id.store(0, Ordering::Release);
+
+        for page in pages.into_iter().filter(|x| *x != 0) {
+            _ = self.inner.protect_page(page);
+        }
+
+        let mut is_break = false;
+        for (id, address) in hits.into_iter().filter(|(id, _)| *id != u64::MAX) {
+            // Cloning it only takes references, it is not allocated.
+            let entry = self
+                .inner
+                .read()
+                .and_then(|entries| entries.iter().find(|x| x.id == id).cloned());
+            let Some(entry) = entry else {
+                continue;
+            };
+
+            let slot = HWBPSlot {
+                is_enabled: true,
+                address,
+                condition: entry.condition,
+                size: Size::OneByte,
+            };
+            let mut hit = HitContext::new(
+                Index::First,
+                &slot,
+                thread_id,
+                ip,
+                HitFlags::default(),
+                [true, false, false, false],
+                registers,
+                memory,
+            );
+            if !entry.hook.filter.accept(&hit) {
+                continue;
+            }
+
+            // Disabled, the watch no longer counts for the protection of its pages,
+            // and removing it waits for the lock, which the handler must not.
+            match (entry.hook.callback)(&mut hit) {
+                HitAction::Disable => {
+                    entry.is_enabled.store(false, Ordering::Relaxed);
+                    _ = self.inner.protect(entry.address, entry.len);
+                }
+                HitAction::Remove => {
+                    entry.is_enabled.store(false, Ordering::Relaxed);
+                    self.inner.defer_remove(entry.id);
+                    _ = self.inner.protect(entry.address, entry.len);
+                }
+                HitAction::Break => is_break = true,
+                _ => {}
+            }
+        }
+
+        Some(is_break)
+    }
+}
+
+/// A watch armed by protecting pages, see `PageWatcher`.
+///
+/// Unlike hardware breakpoints, it applies to every thread at once,
+/// and enabling or disabling it takes effect immediately.
+/// Callbacks get the accessed address as the address of the hit,
+/// and `Index::First` as its index.
+pub struct PageWatch<R = CONTEXT> {
+    watcher: Arc<Inner<R>>,
+    entry: Entry<R>,
+}
+
+impl<R> Clone for PageWatch<R> {
+    fn clone(&self) -> Self {
+        Self {
+            watcher: self.watcher.clone(),
+            entry: self.entry.clone(),
+        }
+    }
+}
+
+impl<R> fmt::Debug for PageWatch<R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PageWatch")
+            .field("address", &self.entry.address)
+            .field("len", &self.entry.len)
+            .field("condition", &self.entry.condition)
+            .field("is_enabled", &self.is_enabled())
+            .field("hit_count", &self.get_hit_count())
+            .finish()
+    }
+}
+
+impl<R> PageWatch<R> {
+    /// Gets the address of the watched memory.
+    pub fn get_address(&self) -> u64 {
+        self.entry.address
+    }
+
+    /// Gets the length of the watched memory in bytes.
+    pub fn get_len(&self) -> u64 {
+        self.entry.len
+    }
+
+    /// Gets how many times the watched memory was accessed.
+    pub fn get_hit_count(&self) -> u64 {
+        self.entry.hook.filter.get_hit_count()
+    }
+
+    /// Gets whether the watch is enabled.
+    pub fn is_enabled(&self) -> bool {
+        self.entry.is_enabled.load(Ordering::Relaxed)
+    }
+
+    /// Enables the watch.
+    pub fn enable(&self) -> io::Result<()> {
+        self.entry.is_enabled.store(true, Ordering::Relaxed);
+        self.watcher.protect(self.entry.address, self.entry.len)
+    }
+
+    /// Disables the watch, restoring the protection of its pages
+    /// unless other watches need it.
+    pub fn disable(&self) -> io::Result<()> {
+        self.entry.is_enabled.store(false, Ordering::Relaxed);
+        self.watcher.protect(self.entry.address, self.entry.len)
+    }
+
+    /// Removes the watch for good.
+    pub fn remove(self) {
+        self.watcher.remove(self.entry.id);
+    }
+}
+
+/// A watch armed by `HWBPBuilder::build_and_watch`,
+/// in a hardware breakpoint, by protecting pages or by patching code.
+pub enum ArmedWatch<R = CONTEXT> {
+    Hardware(HWBP<R>),
+    Paging(PageWatch<R>),
+    Software(SoftwareBreakpoint<R>),
+}
+
+impl<R> fmt::Debug for ArmedWatch<R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Hardware(hwbp) => f.debug_tuple("Hardware").field(hwbp).finish(),
+            Self::Paging(page) => f.debug_tuple("Paging").field(page).finish(),
+            Self::Software(breakpoint) => f.debug_tuple("Software").field(breakpoint).finish(),
+        }
+    }
+}
+
+impl<R> Clone for ArmedWatch<R> {
+    fn clone(&self) -> Self {
+        match self {
+            Self::Hardware(hwbp) => Self::Hardware(hwbp.clone()),
+            Self::Paging(page) => Self::Paging(page.clone()),
+            Self::Software(breakpoint) => Self::Software(breakpoint.clone()),
+        }
+    }
+}
+
+impl<R> ArmedWatch<R> {
+    /// Gets how many times the watched memory was hit.
+    pub fn get_hit_count(&self) -> u64 {
+        match self {
+            Self::Hardware(hwbp) => hwbp.get_hit_count(),
+            Self::Paging(page) => page.get_hit_count(),
+            Self::Software(breakpoint) => breakpoint.get_hit_count(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, collections::BTreeMap};
+
+    use super::*;
+    use crate::{backend::FakeBackend, Backend, Context};
+
+    thread_local! {
+        /// The protection of the fake pages of the test running on the thread.
+        static PROTECTION: RefCell<BTreeMap<u64, Condition>> = const { RefCell::new(BTreeMap::new()) };
+    }
+
+    fn set_protection(page: u64, condition: Option<Condition>) -> io::Result<()> {
+        PROTECTION.with_borrow_mut(|pages| match condition {
+            Some(condition) => pages.insert(page, condition),
+            None => pages.remove(&page),
+        });
+        Ok(())
+    }
+
+    fn get_protection(page: u64) -> Option<Condition> {
+        PROTECTION.with_borrow(|pages| pages.get(&page).copied())
+    }
+
+    /// Watches 4 bytes at `address` with every hardware breakpoint taken,
+    /// counting the hits in `hits`.
+    fn watch(
+        backend: &FakeBackend,
+        address: u64,
+        action: HitAction,
+        hits: &Arc<AtomicU64>,
+    ) -> ArmedWatch<()> {
+        let mut context = Context::current_with(backend).unwrap();
+        context.set_strategy(WatchStrategy::Fallback);
+        for index in Index::ALL {
+            context.reserve(index, "other").unwrap();
+        }
+
+        let hits = hits.clone();
+        context
+            .builder()
+            .watch_memory_write(address as *const u8, Size::FourBytes, move |_| {
+                hits.fetch_add(1, Ordering::Relaxed);
+                action
+            })
+            .with_enabled(true)
+            .build_and_watch()
+            .unwrap()
+    }
+
+    #[test]
+    fn fallback_to_pages() {
+        let backend = FakeBackend::new(&[1]).with_page_watcher(set_protection);
+        let hits = Arc::new(AtomicU64::new(0));
+        let watch = watch(&backend, 0x10_0010, HitAction::Continue, &hits);
+        assert!(matches!(watch, ArmedWatch::Paging(_)));
+        assert_eq!(get_protection(0x10_0000), Some(Condition::Write));
+
+        // A write to the watch unprotects the page, and its step calls the callback.
+        let watcher = backend.page_watcher().unwrap();
+        assert!(watcher.on_fault(1, 0x10_0012, true));
+        assert_eq!(get_protection(0x10_0000), None);
+        assert!(watcher.is_pending(1));
+        assert_eq!(watcher.on_step(1, 0x4000, &mut (), None), Some(false));
+        assert!(!watcher.is_pending(1));
+        assert_eq!(get_protection(0x10_0000), Some(Condition::Write));
+        assert_eq!(hits.load(Ordering::Relaxed), 1);
+        assert_eq!(watch.get_hit_count(), 1);
+
+        // Writes elsewhere on the page fault too, but do not call it.
+        assert!(watcher.on_fault(1, 0x10_0100, true));
+        assert_eq!(watcher.on_step(1, 0x4000, &mut (), None), Some(false));
+        assert_eq!(hits.load(Ordering::Relaxed), 1);
+
+        // Faults on other pages are passed on.
+        assert!(!watcher.on_fault(1, 0x20_0000, true));
+        assert_eq!(watcher.on_step(1, 0x4000, &mut (), None), None);
+    }
+
+    #[test]
+    fn remove_deferred() {
+        let backend = FakeBackend::new(&[1]).with_page_watcher(set_protection);
+        let hits = Arc::new(AtomicU64::new(0));
+        watch(&backend, 0x10_0010, HitAction::Remove, &hits);
+        let watcher = backend.page_watcher().unwrap();
+
+        // The handler only disables it, which unprotects the page right away.
+        assert!(watcher.on_fault(1, 0x10_0010, true));
+        assert_eq!(watcher.on_step(1, 0x4000, &mut (), None), Some(false));
+        assert_eq!(hits.load(Ordering::Relaxed), 1);
+        assert_eq!(get_protection(0x10_0000), None);
+        assert!(!watcher.on_fault(1, 0x10_0010, true));
+        assert_eq!(watcher.inner.entries.read().unwrap().len(), 1);
+
+        // It is removed once the entries are next locked for writing.
+        drop(watcher.inner.write().unwrap());
+        assert!(watcher.inner.entries.read().unwrap().is_empty());
+        assert!(watcher
+            .inner
+            .removed
+            .iter()
+            .all(|x| x.load(Ordering::Acquire) == 0));
+    }
+
+    #[test]
+    fn fault_while_writing() {
+        let backend = FakeBackend::new(&[1]).with_page_watcher(set_protection);
+        let hits = Arc::new(AtomicU64::new(0));
+        watch(&backend, 0x10_0010, HitAction::Continue, &hits);
+        let watcher = backend.page_watcher().unwrap();
+
+        // A fault of the thread holding the lock does not wait for it,
+        // and the page gets the protection it had back.
+        let entries = watcher.inner.write().unwrap();
+        assert!(watcher.on_fault(1, 0x10_0010, true));
+        assert_eq!(watcher.on_step(1, 0x4000, &mut (), None), Some(false));
+        assert_eq!(get_protection(0x10_0000), Some(Condition::Write));
+        assert_eq!(hits.load(Ordering::Relaxed), 0);
+        drop(entries);
+
+        assert!(watcher.on_fault(1, 0x10_0010, true));
+        assert_eq!(watcher.on_step(1, 0x4000, &mut (), None), Some(false));
+        assert_eq!(hits.load(Ordering::Relaxed), 1);
+    }
+}
@@ -0,0 +1,206 @@
+use std::fmt;
+
+use crate::{PageWatch, Size, CONTEXT, HWBP};
+
+/// Splits `len` bytes at `address` into the fewest naturally aligned
+/// 1, 2, 4 or 8-byte slots covering exactly those bytes.
+///
+/// # Return value
+/// The address and size of every slot, in the order of their addresses,
+/// or `None` if the range goes past the end of the address space.
+pub fn plan_range(address: u64, len: u64) -> Option<Vec<(u64, Size)>> {
+    const SIZES: &[Size] = &[
+        #[cfg(target_arch = "x86_64")]
+        Size::EightBytes,
+        Size::FourBytes,
+        Size::TwoBytes,
+        Size::OneByte,
+    ];
+
+    let mut slots = Vec::new();
+    let (mut address, end) = (address, address.checked_add(len)?);
+    while address < end {
+        let size = SIZES
+            .iter()
+            .copied()
+            .find(|size| {
+                let bytes = size.into_bytes() as u64;
+                address % bytes == 0 && address + bytes <= end
+            })
+            .unwrap_or(Size::OneByte);
+
+        slots.push((address, size));
+        address += size.into_bytes() as u64;
+    }
+
+    Some(slots)
+}
+
+/// Hardware breakpoints watching a range of memory together,
+/// created by `Context::watch_range`.
+///
+/// They share a callback and a hit count,
+/// and an access hitting several of them calls the callback once.
+/// If the range is watched by protecting pages instead, there are none,
+/// see `get_page_watch`.
+pub struct RangeWatch<R = CONTEXT> {
+    address: u64,
+    len: u64,
+    hwbps: Vec<HWBP<R>>,
+    page: Option<PageWatch<R>>,
+}
+
+impl<R> fmt::Debug for RangeWatch<R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RangeWatch")
+            .field("address", &self.address)
+            .field("len", &self.len)
+            .field("hwbps", &self.hwbps)
+            .field("page", &self.page)
+            .finish()
+    }
+}
+
+impl<R> Clone for RangeWatch<R> {
+    fn clone(&self) -> Self {
+        Self {
+            address: self.address,
+            len: self.len,
+            hwbps: self.hwbps.clone(),
+            page: self.page.clone(),
+        }
+    }
+}
+
+impl<R> RangeWatch<R> {
+    pub(crate) fn new(address: u64, len: u64, hwbps: Vec<HWBP<R>>) -> Self {
+        Self {
+            address,
+            len,
+            hwbps,
+            page: None,
+        }
+    }
+
+    pub(crate) fn with_pages(page: PageWatch<R>) -> Self {
+        Self {
+            address: page.get_address(),
+            len: page.get_len(),
+            hwbps: Vec::new(),
+            page: Some(page),
+        }
+    }
+
+    /// Gets the address of the range.
+    pub fn get_address(&self) -> u64 {
+        self.address
+    }
+
+    /// Gets the length of the range in bytes.
+    pub fn get_len(&self) -> u64 {
+        self.len
+    }
+
+    /// Gets the hardware breakpoints covering the range.
+    pub fn get_hwbps(&self) -> &[HWBP<R>] {
+        &self.hwbps
+    }
+
+    /// Gets the page watch watching the range, if it does not fit in hardware breakpoints.
+    pub fn get_page_watch(&self) -> Option<&PageWatch<R>> {
+        self.page.as_ref()
+    }
+
+    /// Gets how many times the range was hit.
+    pub fn get_hit_count(&self) -> u64 {
+        match &self.page {
+            Some(page) => page.get_hit_count(),
+            None => self.hwbps.first().map_or(0, HWBP::get_hit_count),
+        }
+    }
+
+    /// Gets whether the hardware breakpoints are enabled.
+    pub fn is_enabled(&self) -> bool {
+        match &self.page {
+            Some(page) => page.is_enabled(),
+            None => self.hwbps.iter().all(HWBP::is_enabled),
+        }
+    }
+
+    /// Enables the hardware breakpoints.
+    ///
+    /// A page watch is enabled right away, see `PageWatch::enable`.
+    pub fn enable(&mut self) {
+        self.hwbps.iter_mut().for_each(HWBP::enable);
+        if let Some(page) = &self.page {
+            // Only fails if the pages were unmapped, and then there is nothing to watch.
+            _ = page.enable();
+        }
+    }
+
+    /// Disables the hardware breakpoints.
+    ///
+    /// A page watch is disabled right away, see `PageWatch::disable`.
+    pub fn disable(&mut self) {
+        self.hwbps.iter_mut().for_each(HWBP::disable);
+        if let Some(page) = &self.page {
+            _ = page.disable();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plan_aligned() {
+        assert_eq!(plan_range(0x1000, 4), Some(vec![(0x1000, Size::FourBytes)]));
+        assert_eq!(
+            plan_range(0x1008, 8),
+            Some(vec![(0x1008, Size::EightBytes)])
+        );
+    }
+
+    #[test]
+    fn plan_unaligned() {
+        assert_eq!(
+            plan_range(0x1001, 7),
+            Some(vec![
+                (0x1001, Size::OneByte),
+                (0x1002, Size::TwoBytes),
+                (0x1004, Size::FourBytes),
+            ])
+        );
+        assert_eq!(
+            plan_range(0x1006, 4),
+            Some(vec![(0x1006, Size::TwoBytes), (0x1008, Size::TwoBytes)])
+        );
+    }
+
+    #[test]
+    fn plan_16_bytes() {
+        assert_eq!(
+            plan_range(0x1000, 16),
+            Some(vec![(0x1000, Size::EightBytes), (0x1008, Size::EightBytes)])
+        );
+    }
+
+    #[test]
+    fn plan_24_bytes() {
+        assert_eq!(
+            plan_range(0x1000, 24),
+            Some(vec![
+                (0x1000, Size::EightBytes),
+                (0x1008, Size::EightBytes),
+                (0x1010, Size::EightBytes),
+            ])
+        );
+    }
+
+    #[test]
+    fn plan_overflow() {
+        assert_eq!(plan_range(u64::MAX - 1, 4), None);
+        assert_eq!(plan_range(u64::MAX, 1), None);
+    }
+}
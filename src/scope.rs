@@ -0,0 +1,447 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+};
+
+use crate::{
+    hwbp::Hook, Backend, BuilderError, Condition, Context, ContextError, HWBPSlot, Index,
+    NativeBackend, Size,
+};
+
+type Result<T> = std::result::Result<T, ContextError>;
+
+/// The threads a breakpoint built by `HWBPBuilder::build_scoped` applies to.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Scope {
+    /// The thread building the breakpoint.
+    CurrentThread,
+    /// A specific thread by id.
+    Thread(u32),
+    /// Specific threads by id.
+    Threads(BTreeSet<u32>),
+    /// Every thread of the process, as of when the breakpoint is enabled,
+    /// see `ScopedBreakpoint::rescan` for the threads created since.
+    Process,
+}
+
+impl Scope {
+    /// Gets the threads of the scope, and whether they were enumerated,
+    /// in which case the ones exiting in the meantime are skipped.
+    fn get_threads<B: Backend>(&self, backend: &B) -> Result<(Vec<u32>, bool)> {
+        match self {
+            Self::CurrentThread => Ok((vec![backend.current_thread_id()], false)),
+            Self::Thread(thread_id) => Ok((vec![*thread_id], false)),
+            Self::Threads(threads) => Ok((threads.iter().copied().collect(), false)),
+            Self::Process => Ok((backend.enumerate_threads()?, true)),
+        }
+    }
+}
+
+/// A breakpoint applying to every thread of a scope,
+/// each in a hardware breakpoint of its own.
+///
+/// Every thread gets the first of its hardware breakpoints that is available,
+/// see `Context::get_ownership`, and keeps it while the breakpoint is enabled,
+/// so the breakpoint can be in the first one of a thread and the third one of another.
+/// The threads share the callback and the hit count.
+///
+/// Unlike `HWBP`, enabling or disabling it applies to the threads right away,
+/// and dropping it disables it, ignoring errors.
+pub struct ScopedBreakpoint<B: Backend = NativeBackend> {
+    backend: B,
+    scope: Scope,
+    slot: HWBPSlot,
+    hook: Hook<B::Registers>,
+    /// The hardware breakpoint of every thread the breakpoint is armed in.
+    indices: BTreeMap<u32, Index>,
+}
+
+impl<B: Backend> fmt::Debug for ScopedBreakpoint<B> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ScopedBreakpoint")
+            .field("scope", &self.scope)
+            .field("slot", &self.slot)
+            .field("hit_count", &self.get_hit_count())
+            .field("indices", &self.indices)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<B: Backend> ScopedBreakpoint<B> {
+    /// Gets the scope of the breakpoint.
+    ///
+    /// `Scope::CurrentThread` is the thread that built it.
+    pub fn get_scope(&self) -> &Scope {
+        &self.scope
+    }
+
+    /// Gets the address the breakpoint watches.
+    pub fn get_address(&self) -> u64 {
+        self.slot.address
+    }
+
+    /// Gets the condition the breakpoint watches for.
+    pub fn get_condition(&self) -> Condition {
+        self.slot.condition
+    }
+
+    /// Gets the size of the memory the breakpoint watches.
+    pub fn get_size(&self) -> Size {
+        self.slot.size
+    }
+
+    /// Gets how many times the breakpoint was hit, in every thread.
+    pub fn get_hit_count(&self) -> u64 {
+        self.hook.filter.get_hit_count()
+    }
+
+    /// Gets whether the breakpoint is enabled.
+    pub fn is_enabled(&self) -> bool {
+        self.slot.is_enabled
+    }
+
+    /// Gets the hardware breakpoint a thread has the breakpoint in, if it is armed in it.
+    pub fn get_index(&self, thread_id: u32) -> Option<Index> {
+        self.indices.get(&thread_id).copied()
+    }
+
+    /// Gets the threads the breakpoint is armed in, and their hardware breakpoint.
+    pub fn get_indices(&self) -> &BTreeMap<u32, Index> {
+        &self.indices
+    }
+
+    pub(crate) fn new(
+        backend: B,
+        scope: Scope,
+        slot: HWBPSlot,
+        hook: Hook<B::Registers>,
+    ) -> Result<Self> {
+        let scope = match scope {
+            Scope::CurrentThread => Scope::Thread(backend.current_thread_id()),
+            scope => scope,
+        };
+
+        let mut breakpoint = Self {
+            backend,
+            scope,
+            slot,
+            hook,
+            indices: BTreeMap::new(),
+        };
+        if breakpoint.slot.is_enabled {
+            breakpoint.arm()?;
+        }
+
+        Ok(breakpoint)
+    }
+
+    /// Enables the breakpoint in every thread of the scope.
+    ///
+    /// All or nothing, like `Context::apply_for_all_threads`:
+    /// if it fails for a thread, for instance because none of its hardware breakpoints
+    /// are available, the threads it was armed in so far are disarmed again.
+    pub fn enable(&mut self) -> Result<()> {
+        let is_enabled = self.slot.is_enabled;
+        self.slot.is_enabled = true;
+        let result = self.arm();
+        if result.is_err() && !is_enabled {
+            self.slot.is_enabled = false;
+        }
+
+        result
+    }
+
+    /// Disables the breakpoint in every thread it is armed in,
+    /// which frees their hardware breakpoint.
+    ///
+    /// Threads it fails for are kept, so that disabling again retries them.
+    pub fn disable(&mut self) -> Result<()> {
+        self.slot.is_enabled = false;
+
+        let mut result = Ok(());
+        for (thread_id, index) in std::mem::take(&mut self.indices) {
+            match self.disarm(thread_id, index) {
+                Ok(()) => {}
+                Err(_) if Context::has_exited(&self.backend, thread_id) => {}
+                Err(error) => {
+                    self.indices.insert(thread_id, index);
+                    if result.is_ok() {
+                        result = Err(ContextError::ThreadFailed {
+                            thread_id,
+                            error: Box::new(error),
+                        });
+                    }
+                }
+            }
+        }
+
+        result
+    }
+
+    /// Arms the breakpoint, if it is enabled, in the threads of the scope it is not armed in,
+    /// as the threads created since it was enabled with `Scope::Process`.
+    ///
+    /// All or nothing for these threads, like `enable`.
+    pub fn rescan(&mut self) -> Result<()> {
+        match self.slot.is_enabled {
+            true => self.arm(),
+            false => Ok(()),
+        }
+    }
+
+    /// Changes what the breakpoint watches in every thread it is armed in,
+    /// each keeping its hardware breakpoint.
+    ///
+    /// Like with `HWBPBuilder`, the size of execute breakpoints is one byte,
+    /// and the breakpoint is checked like `HWBPBuilder::build_and_set` does.
+    /// If it fails for a thread, the threads changed so far are changed back.
+    pub fn modify(
+        &mut self,
+        address: u64,
+        condition: Condition,
+        size: Size,
+    ) -> std::result::Result<(), BuilderError> {
+        let size = match condition {
+            Condition::Execute => Size::OneByte,
+            _ => size,
+        };
+        let slot = HWBPSlot {
+            is_enabled: self.slot.is_enabled,
+            address,
+            condition,
+            size,
+        };
+        slot.validate_for(&self.backend)?;
+
+        let mut changed = Vec::new();
+        for (&thread_id, &index) in &self.indices {
+            match self.set_thread(thread_id, index, slot) {
+                Ok(()) => changed.push((thread_id, index)),
+                Err(_) if Context::has_exited(&self.backend, thread_id) => {}
+                Err(error) => {
+                    for (thread_id, index) in changed.into_iter().rev() {
+                        _ = self.set_thread(thread_id, index, self.slot);
+                    }
+                    return Err(BuilderError::ApplyFailed(ContextError::ThreadFailed {
+                        thread_id,
+                        error: Box::new(error),
+                    }));
+                }
+            }
+        }
+
+        self.slot = slot;
+        Ok(())
+    }
+
+    /// Arms the breakpoint in the threads of the scope it is not armed in.
+    fn arm(&mut self) -> Result<()> {
+        let (threads, is_enumerated) = self.scope.get_threads(&self.backend)?;
+
+        let mut armed = Vec::new();
+        for thread_id in threads {
+            if self.indices.contains_key(&thread_id) {
+                continue;
+            }
+
+            match self.arm_thread(thread_id) {
+                Ok(index) => armed.push((thread_id, index)),
+                Err(_) if is_enumerated && Context::has_exited(&self.backend, thread_id) => {}
+                Err(error) => {
+                    for (thread_id, index) in armed.into_iter().rev() {
+                        _ = self.disarm(thread_id, index);
+                    }
+                    return Err(ContextError::ThreadFailed {
+                        thread_id,
+                        error: Box::new(error),
+                    });
+                }
+            }
+        }
+
+        self.indices.extend(armed);
+        Ok(())
+    }
+
+    /// Arms the breakpoint in the first available hardware breakpoint of a thread.
+    fn arm_thread(&self, thread_id: u32) -> Result<Index> {
+        let mut context = Context::for_thread_with(&self.backend, thread_id)?;
+        let index = context
+            .find_unused(None)
+            .ok_or(ContextError::NoUnusedSlot { thread_id })?;
+
+        context.build_and_set_hwbp(index, self.slot, self.hook.clone());
+        context.apply_for_thread(thread_id)?;
+        Ok(index)
+    }
+
+    /// Disarms the breakpoint from the hardware breakpoint of a thread.
+    fn disarm(&self, thread_id: u32, index: Index) -> Result<()> {
+        let slot = HWBPSlot {
+            is_enabled: false,
+            ..self.slot
+        };
+        self.set_thread(thread_id, index, slot)
+    }
+
+    /// Sets the hardware breakpoint of a thread the breakpoint is armed in,
+    /// unless it was taken over by another breakpoint since.
+    fn set_thread(&self, thread_id: u32, index: Index, slot: HWBPSlot) -> Result<()> {
+        let mut context = Context::for_thread_with(&self.backend, thread_id)?;
+        let is_ours = context
+            .get(index)
+            .get_hook()
+            .is_some_and(|x| x.is_same(&self.hook));
+        if !is_ours {
+            return Ok(());
+        }
+
+        context.build_and_set_hwbp(index, slot, self.hook.clone());
+        context.apply_for_thread(thread_id)
+    }
+}
+
+impl<B: Backend> Drop for ScopedBreakpoint<B> {
+    fn drop(&mut self) {
+        _ = self.disable();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{backend::FakeBackend, DebugRegisters};
+
+    /// Builds a breakpoint watching 4 bytes at 0x1000 in every thread.
+    fn scoped(backend: &FakeBackend, is_enabled: bool) -> ScopedBreakpoint<&FakeBackend> {
+        let mut context = Context::current_with(backend).unwrap();
+        context
+            .unused()
+            .unwrap()
+            .watch_memory_write(0x1000 as *const u8, Size::FourBytes, |_| {})
+            .with_enabled(is_enabled)
+            .build_scoped(Scope::Process)
+            .unwrap()
+    }
+
+    fn get_registers(backend: &FakeBackend, thread_id: u32) -> DebugRegisters {
+        backend.get_debug_registers(thread_id).unwrap()
+    }
+
+    #[test]
+    fn arm_in_available_slots() {
+        let backend = FakeBackend::new(&[1, 2, 3]);
+        let mut other = Context::for_thread_with(&backend, 2).unwrap();
+        other
+            .unused()
+            .unwrap()
+            .watch_memory_write(0x2000 as *const u8, Size::FourBytes, |_| {})
+            .with_enabled(true)
+            .build_and_set()
+            .unwrap();
+        other.apply_for_thread(2).unwrap();
+
+        let mut breakpoint = scoped(&backend, true);
+        let indices = BTreeMap::from([(1, Index::First), (2, Index::Second), (3, Index::First)]);
+        assert_eq!(breakpoint.get_indices(), &indices);
+        for (thread_id, index) in indices {
+            let registers = get_registers(&backend, thread_id);
+            assert!(registers.is_enabled(index));
+            assert_eq!(registers.address[index as usize], 0x1000);
+        }
+
+        // Disabling it frees them, and leaves the other breakpoint alone.
+        breakpoint.disable().unwrap();
+        assert!(breakpoint.get_indices().is_empty());
+        assert!(!get_registers(&backend, 1).is_enabled(Index::First));
+        assert!(!get_registers(&backend, 2).is_enabled(Index::Second));
+        assert!(get_registers(&backend, 2).is_enabled(Index::First));
+        assert!(!get_registers(&backend, 3).is_enabled(Index::First));
+    }
+
+    #[test]
+    fn enable_rolls_back() {
+        let backend = FakeBackend::new(&[1, 2, 3]).with_failing(3);
+        let mut breakpoint = scoped(&backend, false);
+
+        let error = breakpoint.enable().unwrap_err();
+        assert!(matches!(
+            error,
+            ContextError::ThreadFailed { thread_id: 3, .. }
+        ));
+        assert!(!breakpoint.is_enabled());
+        assert!(breakpoint.get_indices().is_empty());
+        for thread_id in [1, 2] {
+            assert!(!get_registers(&backend, thread_id).is_enabled(Index::First));
+        }
+    }
+
+    #[test]
+    fn rescan_new_threads() {
+        let backend = FakeBackend::new(&[1, 2]);
+        let mut breakpoint = scoped(&backend, true);
+
+        // Only the threads started since are armed.
+        backend.add_thread(3);
+        backend.set_failing(Some(1));
+        breakpoint.rescan().unwrap();
+        let indices = BTreeMap::from([(1, Index::First), (2, Index::First), (3, Index::First)]);
+        assert_eq!(breakpoint.get_indices(), &indices);
+        assert!(get_registers(&backend, 3).is_enabled(Index::First));
+
+        // Disabled, it is not armed in them.
+        backend.set_failing(None);
+        breakpoint.disable().unwrap();
+        backend.add_thread(4);
+        breakpoint.rescan().unwrap();
+        assert!(breakpoint.get_indices().is_empty());
+        assert!(!get_registers(&backend, 4).is_enabled(Index::First));
+    }
+
+    #[test]
+    fn modify_rolls_back() {
+        let backend = FakeBackend::new(&[1, 2, 3]);
+        let mut breakpoint = scoped(&backend, true);
+
+        breakpoint
+            .modify(0x2000, Condition::ReadWrite, Size::EightBytes)
+            .unwrap();
+        for thread_id in [1, 2, 3] {
+            assert_eq!(get_registers(&backend, thread_id).address[0], 0x2000);
+        }
+
+        // If it fails for a thread, the ones changed before it are changed back.
+        backend.set_failing(Some(3));
+        let error = breakpoint
+            .modify(0x3000, Condition::Write, Size::FourBytes)
+            .unwrap_err();
+        assert!(matches!(
+            error,
+            BuilderError::ApplyFailed(ContextError::ThreadFailed { thread_id: 3, .. })
+        ));
+        assert_eq!(breakpoint.get_address(), 0x2000);
+        assert_eq!(breakpoint.get_condition(), Condition::ReadWrite);
+        for thread_id in [1, 2, 3] {
+            let registers = get_registers(&backend, thread_id);
+            assert!(registers.is_enabled(Index::First));
+            assert_eq!(registers.address[0], 0x2000);
+        }
+
+        // Checked like a hardware breakpoint, before any thread is changed.
+        backend.set_failing(None);
+        let error = breakpoint
+            .modify(0x3001, Condition::Write, Size::FourBytes)
+            .unwrap_err();
+        assert!(matches!(error, BuilderError::Misaligned { .. }));
+        assert_eq!(get_registers(&backend, 1).address[0], 0x2000);
+    }
+
+    #[test]
+    fn drop_disables() {
+        let backend = FakeBackend::new(&[1, 2]);
+        drop(scoped(&backend, true));
+        for thread_id in [1, 2] {
+            assert!(!get_registers(&backend, thread_id).is_enabled(Index::First));
+        }
+    }
+}
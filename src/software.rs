@@ -0,0 +1,515 @@
+use std::{
+    collections::HashMap,
+    fmt, io,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+};
+
+use crate::{
+    hwbp::Hook, Condition, HWBPSlot, HitAction, HitContext, HitFlags, Index, MemoryReader, Size,
+    CONTEXT,
+};
+
+/// `int3`, the one-byte breakpoint instruction.
+const INT3: u8 = 0xCC;
+
+/// How many threads can single-step over original instructions at once.
+const MAX_PENDING: usize = 64;
+
+/// Writes a byte of code, making its page writable for the time being.
+///
+/// # Return value
+/// The byte it replaced.
+pub(crate) type WriteCode = unsafe fn(address: u64, byte: u8) -> io::Result<u8>;
+
+struct Entry<R> {
+    original: u8,
+    hook: Hook<R>,
+    is_enabled: Arc<AtomicBool>,
+    /// Removed entries are kept, for traps hit right before they were.
+    is_removed: bool,
+}
+
+/// The original instruction a thread is single-stepping over, which stays restored meanwhile.
+///
+/// Traps are handled in signal handlers, which must not allocate,
+/// so this is preallocated and only touched by the thread that claimed it.
+struct Pending {
+    /// The thread that claimed it, 0 if none.
+    thread_id: AtomicU32,
+    /// The address of the instruction, 0 if none yet.
+    address: AtomicU64,
+}
+
+impl Pending {
+    fn new() -> Self {
+        Self {
+            thread_id: AtomicU32::new(0),
+            address: AtomicU64::new(0),
+        }
+    }
+}
+
+type Entries<R> = HashMap<u64, Entry<R>>;
+
+struct Inner<R> {
+    write_code: WriteCode,
+    entries: Mutex<Entries<R>>,
+    pending: [Pending; MAX_PENDING],
+    /// The instructions whose `int3` the handler could not insert again,
+    /// since the lock was taken, 0 for none.
+    /// They are inserted again when the lock is next taken.
+    stale: [AtomicU64; MAX_PENDING],
+}
+
+impl<R> Inner<R> {
+    /// Takes the lock outside the handler, updating the stale instructions.
+    fn lock(&self) -> io::Result<MutexGuard<'_, Entries<R>>> {
+        let entries = self
+            .entries
+            .lock()
+            .map_err(|_| io::Error::other("poisoned"))?;
+        for stale in &self.stale {
+            let address = stale.swap(0, Ordering::AcqRel);
+            if address != 0 {
+                _ = self.update(&entries, address);
+            }
+        }
+
+        Ok(entries)
+    }
+
+    /// Gets whether a thread is single-stepping over the instruction at an address.
+    fn is_stepping(&self, address: u64) -> bool {
+        self.pending.iter().any(|x| {
+            x.thread_id.load(Ordering::Acquire) != 0 && x.address.load(Ordering::Acquire) == address
+        })
+    }
+
+    /// Inserts or restores the `int3` of an entry, according to its state.
+    fn update(&self, entries: &Entries<R>, address: u64) -> io::Result<()> {
+        let Some(entry) = entries.get(&address) else {
+            return Ok(());
+        };
+
+        let byte = match entry.is_enabled.load(Ordering::Relaxed) && !self.is_stepping(address) {
+            true => INT3,
+            false => entry.original,
+        };
+        unsafe { (self.write_code)(address, byte) }.map(|_| ())
+    }
+
+    fn remove(&self, address: u64) {
+        let Ok(mut entries) = self.lock() else {
+            return;
+        };
+        if let Some(entry) = entries.get_mut(&address) {
+            entry.is_enabled.store(false, Ordering::Relaxed);
+            entry.is_removed = true;
+        }
+        _ = self.update(&entries, address);
+    }
+
+    /// Gets the instruction a thread is single-stepping over, claiming it if asked to.
+    fn get_pending(&self, thread_id: u32, is_claimed: bool) -> Option<&Pending> {
+        let pending = self
+            .pending
+            .iter()
+            .find(|x| x.thread_id.load(Ordering::Acquire) == thread_id);
+        if pending.is_some() || !is_claimed {
+            return pending;
+        }
+
+        self.pending.iter().find(|x| {
+            x.thread_id
+                .compare_exchange(0, thread_id, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok()
+        })
+    }
+
+    /// Leaves the `int3` of an instruction to whoever takes the lock next.
+    ///
+    /// If too many are waiting already, it stays restored until it is enabled again.
+    fn defer_update(&self, address: u64) {
+        let is_deferred = self
+            .stale
+            .iter()
+            .any(|x| x.load(Ordering::Acquire) == address);
+        if !is_deferred {
+            self.stale.iter().any(|x| {
+                x.compare_exchange(0, address, Ordering::AcqRel, Ordering::Acquire)
+                    .is_ok()
+            });
+        }
+    }
+}
+
+/// Execute breakpoints in the current process that patch an `int3` into the code
+/// instead of taking up a debug register, so there can be any number of them.
+///
+/// When a thread hits one, the original instruction is restored and single-stepped,
+/// then the `int3` is inserted again.
+/// Threads hitting it at once all get the callback, and the `int3` stays restored
+/// until the last of them has stepped, so threads only reaching it meanwhile are missed.
+/// Up to 64 threads can step at once, and traps hit while another thread
+/// sets or changes a breakpoint are passed on to the next handler.
+pub struct SoftwareBreakpoints<R = CONTEXT> {
+    inner: Arc<Inner<R>>,
+}
+
+impl<R> fmt::Debug for SoftwareBreakpoints<R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SoftwareBreakpoints")
+            .finish_non_exhaustive()
+    }
+}
+
+impl<R> SoftwareBreakpoints<R> {
+    pub(crate) fn new(write_code: WriteCode) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                write_code,
+                entries: Mutex::new(HashMap::new()),
+                pending: std::array::from_fn(|_| Pending::new()),
+                stale: std::array::from_fn(|_| AtomicU64::new(0)),
+            }),
+        }
+    }
+
+    /// Sets a breakpoint on the instruction at `address`.
+    pub(crate) fn insert(&self, address: u64, hook: Hook<R>) -> io::Result<SoftwareBreakpoint<R>> {
+        let mut entries = self.inner.lock()?;
+        let is_enabled = Arc::new(AtomicBool::new(true));
+        match entries.get_mut(&address) {
+            Some(entry) if !entry.is_removed => return Err(io::ErrorKind::AlreadyExists.into()),
+            Some(entry) => {
+                entry.hook = hook.clone();
+                entry.is_enabled = is_enabled.clone();
+                entry.is_removed = false;
+                self.inner.update(&entries, address)?;
+            }
+            None => {
+                let original = unsafe { (self.inner.write_code)(address, INT3) }?;
+                entries.insert(
+                    address,
+                    Entry {
+                        original,
+                        hook: hook.clone(),
+                        is_enabled: is_enabled.clone(),
+                        is_removed: false,
+                    },
+                );
+            }
+        }
+
+        Ok(SoftwareBreakpoint {
+            breakpoints: self.inner.clone(),
+            address,
+            hook,
+            is_enabled,
+        })
+    }
+
+    /// Handles a breakpoint trap of the current thread at `address`, without allocating,
+    /// calling the callback and restoring the original instruction,
+    /// which the thread then has to single-step.
+    ///
+    /// # Return value
+    /// `None` if the `int3` is not ours, or cannot be handled right now,
+    /// otherwise whether a callback asked to break.
+    pub(crate) fn on_hit(
+        &self,
+        thread_id: u32,
+        address: u64,
+        registers: &mut R,
+        memory: Option<&MemoryReader>,
+    ) -> Option<bool> {
+        let (hook, is_enabled) = {
+            // The lock may be taken by this very thread, so it is not waited for.
+            let entries = self.inner.entries.try_lock().ok()?;
+            let entry = entries.get(&address)?;
+            let pending = self.inner.get_pending(thread_id, true)?;
+
+            // Restore the instruction until this thread has stepped over it.
+            pending.address.store(address, Ordering::Release);
+            _ = self.inner.update(&entries, address);
+
+            // Cloning them only takes references, they are not allocated.
+            (entry.hook.clone(), entry.is_enabled.clone())
+        };
+
+        // A trap from before the breakpoint was disabled or removed just continues.
+        let mut is_break = false;
+        if is_enabled.load(Ordering::Relaxed) {
+            let slot = HWBPSlot {
+                is_enabled: true,
+                address,
+                condition: Condition::Execute,
+                size: Size::OneByte,
+            };
+            let mut hit = HitContext::new(
+                Index::First,
+                &slot,
+                thread_id,
+                address,
+                HitFlags::default(),
+                [true, false, false, false],
+                registers,
+                memory,
+            );
+            if hook.filter.accept(&hit) {
+                match (hook.callback)(&mut hit) {
+                    // The `int3` is not inserted again after the step.
+                    HitAction::Disable | HitAction::Remove => {
+                        is_enabled.store(false, Ordering::Relaxed)
+                    }
+                    HitAction::Break => is_break = true,
+                    _ => {}
+                }
+            }
+        }
+
+        Some(is_break)
+    }
+
+    /// Gets whether the current thread is single-stepping over an original instruction.
+    pub(crate) fn is_pending(&self, thread_id: u32) -> bool {
+        self.inner.get_pending(thread_id, false).is_some()
+    }
+
+    /// Handles the single step of the current thread over an original instruction,
+    /// inserting the `int3` again once no thread is stepping over it.
+    ///
+    /// # Return value
+    /// Whether the thread was single-stepping over an original instruction.
+    pub(crate) fn on_step(&self, thread_id: u32) -> bool {
+        let Some(pending) = self.inner.get_pending(thread_id, false) else {
+            return false;
+        };
+        let address = pending.address.swap(0, Ordering::AcqRel);
+        pending.thread_id.store(0, Ordering::Release);
+
+        match self.inner.entries.try_lock() {
+            Ok(entries) => _ = self.inner.update(&entries, address),
+            Err(_) => self.inner.defer_update(address),
+        }
+        true
+    }
+}
+
+/// An execute breakpoint patching an `int3` into the code, see `SoftwareBreakpoints`.
+///
+/// Like page watches, it applies to every thread at once,
+/// and enabling or disabling it takes effect immediately.
+/// Callbacks get `Index::First` as the index of the hit.
+pub struct SoftwareBreakpoint<R = CONTEXT> {
+    breakpoints: Arc<Inner<R>>,
+    address: u64,
+    hook: Hook<R>,
+    is_enabled: Arc<AtomicBool>,
+}
+
+impl<R> Clone for SoftwareBreakpoint<R> {
+    fn clone(&self) -> Self {
+        Self {
+            breakpoints: self.breakpoints.clone(),
+            address: self.address,
+            hook: self.hook.clone(),
+            is_enabled: self.is_enabled.clone(),
+        }
+    }
+}
+
+impl<R> fmt::Debug for SoftwareBreakpoint<R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SoftwareBreakpoint")
+            .field("address", &self.address)
+            .field("is_enabled", &self.is_enabled())
+            .field("hit_count", &self.get_hit_count())
+            .finish()
+    }
+}
+
+impl<R> SoftwareBreakpoint<R> {
+    /// Gets the address of the instruction.
+    pub fn get_address(&self) -> u64 {
+        self.address
+    }
+
+    /// Gets how many times the instruction was hit.
+    pub fn get_hit_count(&self) -> u64 {
+        self.hook.filter.get_hit_count()
+    }
+
+    /// Gets whether the breakpoint is enabled.
+    pub fn is_enabled(&self) -> bool {
+        self.is_enabled.load(Ordering::Relaxed)
+    }
+
+    /// Enables the breakpoint, inserting the `int3`.
+    pub fn enable(&self) -> io::Result<()> {
+        self.set_enabled(true)
+    }
+
+    /// Disables the breakpoint, restoring the original instruction.
+    pub fn disable(&self) -> io::Result<()> {
+        self.set_enabled(false)
+    }
+
+    /// Removes the breakpoint for good.
+    pub fn remove(self) {
+        self.breakpoints.remove(self.address);
+    }
+
+    fn set_enabled(&self, is_enabled: bool) -> io::Result<()> {
+        let entries = self.breakpoints.lock()?;
+        let is_removed = entries.get(&self.address).is_none_or(|x| x.is_removed);
+        if is_removed && is_enabled {
+            return Err(io::ErrorKind::NotFound.into());
+        }
+
+        self.is_enabled.store(is_enabled, Ordering::Relaxed);
+        self.breakpoints.update(&entries, self.address)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, collections::BTreeMap};
+
+    use super::*;
+    use crate::{backend::FakeBackend, ArmedWatch, Backend, Context, WatchStrategy};
+
+    /// The original byte of every fake instruction.
+    const NOP: u8 = 0x90;
+
+    thread_local! {
+        /// The fake code of the test running on the thread.
+        static CODE: RefCell<BTreeMap<u64, u8>> = const { RefCell::new(BTreeMap::new()) };
+    }
+
+    unsafe fn write_code(address: u64, byte: u8) -> io::Result<u8> {
+        Ok(CODE.with_borrow_mut(|code| code.insert(address, byte).unwrap_or(NOP)))
+    }
+
+    fn read_code(address: u64) -> u8 {
+        CODE.with_borrow(|code| code.get(&address).copied().unwrap_or(NOP))
+    }
+
+    /// Sets a breakpoint at `address` with every hardware breakpoint taken,
+    /// counting the hits in `hits`.
+    fn watch(
+        backend: &FakeBackend,
+        address: u64,
+        action: HitAction,
+        hits: &Arc<AtomicU64>,
+    ) -> ArmedWatch<()> {
+        let mut context = Context::current_with(backend).unwrap();
+        context.set_strategy(WatchStrategy::Fallback);
+        for index in Index::ALL {
+            context.reserve(index, "other").unwrap();
+        }
+
+        let hits = hits.clone();
+        context
+            .builder()
+            .with_address(address)
+            .with_condition(Condition::Execute)
+            .with_callback(move |_| {
+                hits.fetch_add(1, Ordering::Relaxed);
+                action
+            })
+            .with_enabled(true)
+            .build_and_watch()
+            .unwrap()
+    }
+
+    #[test]
+    fn fallback_to_int3() {
+        let backend = FakeBackend::new(&[1]).with_software_breakpoints(write_code);
+        let hits = Arc::new(AtomicU64::new(0));
+        let watch = watch(&backend, 0x40_1000, HitAction::Continue, &hits);
+        assert!(matches!(watch, ArmedWatch::Software(_)));
+        assert_eq!(read_code(0x40_1000), INT3);
+
+        // The hit restores the instruction until the thread has stepped over it.
+        let breakpoints = backend.software_breakpoints().unwrap();
+        assert_eq!(breakpoints.on_hit(1, 0x40_1000, &mut (), None), Some(false));
+        assert_eq!(read_code(0x40_1000), NOP);
+        assert!(breakpoints.is_pending(1));
+        assert!(breakpoints.on_step(1));
+        assert!(!breakpoints.is_pending(1));
+        assert_eq!(read_code(0x40_1000), INT3);
+        assert_eq!(hits.load(Ordering::Relaxed), 1);
+        assert_eq!(watch.get_hit_count(), 1);
+
+        // Traps that are not ours are passed on.
+        assert_eq!(breakpoints.on_hit(1, 0x40_2000, &mut (), None), None);
+        assert!(!breakpoints.on_step(1));
+    }
+
+    #[test]
+    fn threads_hitting_at_once() {
+        let backend = FakeBackend::new(&[1, 2]).with_software_breakpoints(write_code);
+        let hits = Arc::new(AtomicU64::new(0));
+        watch(&backend, 0x40_1000, HitAction::Continue, &hits);
+        let breakpoints = backend.software_breakpoints().unwrap();
+
+        // The `int3` is inserted again once the last of them has stepped.
+        assert_eq!(breakpoints.on_hit(1, 0x40_1000, &mut (), None), Some(false));
+        assert_eq!(breakpoints.on_hit(2, 0x40_1000, &mut (), None), Some(false));
+        assert!(breakpoints.on_step(1));
+        assert_eq!(read_code(0x40_1000), NOP);
+        assert!(breakpoints.on_step(2));
+        assert_eq!(read_code(0x40_1000), INT3);
+        assert_eq!(hits.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn remove_from_callback() {
+        let backend = FakeBackend::new(&[1]).with_software_breakpoints(write_code);
+        let hits = Arc::new(AtomicU64::new(0));
+        let watch = watch(&backend, 0x40_1000, HitAction::Remove, &hits);
+        let breakpoints = backend.software_breakpoints().unwrap();
+
+        assert_eq!(breakpoints.on_hit(1, 0x40_1000, &mut (), None), Some(false));
+        assert!(breakpoints.on_step(1));
+        assert_eq!(read_code(0x40_1000), NOP);
+        let ArmedWatch::Software(breakpoint) = watch else {
+            unreachable!();
+        };
+        assert!(!breakpoint.is_enabled());
+    }
+
+    #[test]
+    fn trap_while_locked() {
+        let backend = FakeBackend::new(&[1]).with_software_breakpoints(write_code);
+        let hits = Arc::new(AtomicU64::new(0));
+        watch(&backend, 0x40_1000, HitAction::Continue, &hits);
+        let breakpoints = backend.software_breakpoints().unwrap();
+
+        // A trap of the thread holding the lock is passed on instead of waiting for it.
+        let entries = breakpoints.inner.entries.lock().unwrap();
+        assert_eq!(breakpoints.on_hit(1, 0x40_1000, &mut (), None), None);
+        assert!(!breakpoints.is_pending(1));
+        drop(entries);
+
+        // A step while it is held leaves the `int3` to whoever takes it next.
+        assert_eq!(breakpoints.on_hit(1, 0x40_1000, &mut (), None), Some(false));
+        let entries = breakpoints.inner.entries.lock().unwrap();
+        assert!(breakpoints.on_step(1));
+        assert_eq!(read_code(0x40_1000), NOP);
+        drop(entries);
+
+        drop(breakpoints.inner.lock().unwrap());
+        assert_eq!(read_code(0x40_1000), INT3);
+        assert!(breakpoints
+            .inner
+            .stale
+            .iter()
+            .all(|x| x.load(Ordering::Acquire) == 0));
+        assert_eq!(hits.load(Ordering::Relaxed), 1);
+    }
+}
@@ -0,0 +1,106 @@
+use std::{fmt, ops::Range, sync::Arc};
+
+use crate::{HitContext, HitPredicate, Index, CONTEXT};
+
+/// A callback that is called for every instruction of a trace.
+pub type TraceCallback<R = CONTEXT> = Arc<dyn Fn(&mut HitContext<'_, R>) + Send + Sync>;
+
+/// Single-steps a thread from a hardware breakpoint hit,
+/// calling a callback for every instruction until a stop condition is met.
+///
+/// Started from a callback with `HitContext::start_trace`.
+/// Stop conditions are checked after every instruction, before calling the callback,
+/// and the trace stops as soon as any of them is met.
+/// Without any, the trace goes on until the thread exits.
+pub struct Trace<R = CONTEXT> {
+    callback: TraceCallback<R>,
+    max_steps: Option<usize>,
+    predicate: Option<HitPredicate<R>>,
+    range: Option<Range<u64>>,
+    steps: usize,
+    index: Index,
+}
+
+impl<R> Trace<R> {
+    /// Creates a trace calling `callback` for every instruction.
+    ///
+    /// The callback gets the registers after each instruction,
+    /// with `HitFlags::single_step` set.
+    pub fn new(callback: impl Fn(&mut HitContext<'_, R>) + Send + Sync + 'static) -> Self {
+        Self {
+            callback: Arc::new(callback),
+            max_steps: None,
+            predicate: None,
+            range: None,
+            steps: 0,
+            index: Index::First,
+        }
+    }
+
+    /// Stops the trace after the given number of instructions.
+    pub fn with_max_steps(mut self, max_steps: usize) -> Self {
+        self.max_steps = Some(max_steps);
+        self
+    }
+
+    /// Stops the trace once `predicate` returns `true`.
+    pub fn with_stop_condition(
+        mut self,
+        predicate: impl Fn(&HitContext<'_, R>) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.predicate = Some(Arc::new(predicate));
+        self
+    }
+
+    /// Stops the trace once the instruction pointer leaves `range`,
+    /// for example the body of a function to step until it returns.
+    pub fn with_range(mut self, range: Range<u64>) -> Self {
+        self.range = Some(range);
+        self
+    }
+
+    /// Gets the number of instructions traced so far.
+    pub fn get_steps(&self) -> usize {
+        self.steps
+    }
+
+    /// Gets the index of the hardware breakpoint the trace was started from.
+    pub fn get_index(&self) -> Index {
+        self.index
+    }
+
+    pub(crate) fn set_index(&mut self, index: Index) {
+        self.index = index;
+    }
+
+    /// Traces one instruction.
+    ///
+    /// # Return value
+    /// Whether the trace goes on.
+    pub(crate) fn step(&mut self, hit: &mut HitContext<'_, R>) -> bool {
+        let is_done = self.max_steps.is_some_and(|x| self.steps >= x)
+            || self
+                .range
+                .as_ref()
+                .is_some_and(|x| !x.contains(&hit.get_instruction_pointer()))
+            || self.predicate.as_ref().is_some_and(|x| x(hit));
+        if is_done {
+            return false;
+        }
+
+        (self.callback)(hit);
+        self.steps += 1;
+        self.max_steps.is_none_or(|x| self.steps < x)
+    }
+}
+
+impl<R> fmt::Debug for Trace<R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Trace")
+            .field("max_steps", &self.max_steps)
+            .field("range", &self.range)
+            .field("steps", &self.steps)
+            .field("index", &self.index)
+            .finish_non_exhaustive()
+    }
+}
@@ -7,6 +7,11 @@ pub enum Index {
     Fourth = 3,
 }
 
+impl Index {
+    /// All indices, in the order breakpoints are dispatched.
+    pub const ALL: [Index; 4] = [Index::First, Index::Second, Index::Third, Index::Fourth];
+}
+
 /// The size of a hardware breakpoint.
 #[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
 #[repr(u8)]
@@ -43,6 +48,10 @@ impl Size {
             _ => None,
         }
     }
+
+    pub(crate) const fn into_bytes(self) -> usize {
+        1 << self as usize
+    }
 }
 
 /// The condition of a hardware breakpoint.
@@ -71,3 +80,15 @@ impl Condition {
         }
     }
 }
+
+/// Who a hardware breakpoint belongs to, see `Context::get_ownership`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Ownership {
+    /// Not enabled by anyone.
+    Free,
+    /// Enabled by this crate.
+    Ours,
+    /// Enabled by someone else, like a debugger or another library,
+    /// with its local or global enable bit.
+    Foreign,
+}
@@ -0,0 +1,256 @@
+use std::{
+    cell::Cell,
+    fmt, io,
+    marker::PhantomData,
+    mem::{align_of, size_of, MaybeUninit},
+    sync::{
+        atomic::{
+            AtomicBool, AtomicI16, AtomicI32, AtomicI8, AtomicU16, AtomicU32, AtomicU64, AtomicU8,
+            Ordering,
+        },
+        Arc,
+    },
+};
+
+#[cfg(target_arch = "x86_64")]
+use std::sync::atomic::{AtomicI64, AtomicIsize, AtomicPtr, AtomicUsize};
+
+use crate::{
+    hwbp::ArmCallback, Backend, ContextError, HWBPCallback, HWBPGuard, HitAction, HitContext,
+    NativeBackend, Size, HWBP,
+};
+
+mod sealed {
+    pub trait Sealed {}
+}
+
+/// A type that a hardware breakpoint can watch as a whole.
+///
+/// It is implemented for the primitive types, their atomics and `Cell`s of them,
+/// whose size is 1, 2, 4 or 8 bytes and equal to their alignment,
+/// so that a naturally aligned breakpoint covers them exactly.
+/// This trait is sealed.
+pub trait Watchable: sealed::Sealed + Sized {
+    /// The size of the breakpoint watching the type.
+    const SIZE: Size;
+}
+
+macro_rules! impl_watchable {
+    ($size:expr => $($ty:ty),+ $(,)?) => {
+        $(
+            impl sealed::Sealed for $ty {}
+            impl Watchable for $ty {
+                const SIZE: Size = {
+                    assert!(size_of::<$ty>() == $size.into_bytes());
+                    assert!(align_of::<$ty>() == size_of::<$ty>());
+                    $size
+                };
+            }
+        )+
+    };
+}
+
+impl_watchable!(Size::OneByte => u8, i8, bool, AtomicU8, AtomicI8, AtomicBool);
+impl_watchable!(Size::TwoBytes => u16, i16, AtomicU16, AtomicI16);
+impl_watchable!(Size::FourBytes => u32, i32, f32, char, AtomicU32, AtomicI32);
+#[cfg(target_arch = "x86_64")]
+impl_watchable!(
+    Size::EightBytes => u64, i64, f64, usize, isize, AtomicU64, AtomicI64, AtomicUsize, AtomicIsize,
+);
+
+#[cfg(target_arch = "x86_64")]
+impl<T> sealed::Sealed for *const T {}
+#[cfg(target_arch = "x86_64")]
+impl<T> Watchable for *const T {
+    const SIZE: Size = Size::EightBytes;
+}
+
+#[cfg(target_arch = "x86_64")]
+impl<T> sealed::Sealed for *mut T {}
+#[cfg(target_arch = "x86_64")]
+impl<T> Watchable for *mut T {
+    const SIZE: Size = Size::EightBytes;
+}
+
+#[cfg(target_arch = "x86_64")]
+impl<T> sealed::Sealed for AtomicPtr<T> {}
+#[cfg(target_arch = "x86_64")]
+impl<T> Watchable for AtomicPtr<T> {
+    const SIZE: Size = Size::EightBytes;
+}
+
+impl<T: Watchable> sealed::Sealed for Cell<T> {}
+impl<T: Watchable> Watchable for Cell<T> {
+    const SIZE: Size = T::SIZE;
+}
+
+/// A hardware breakpoint watching a variable,
+/// which it borrows so that it cannot outlive it.
+///
+/// Since the variable stays borrowed, it can only be changed
+/// through interior mutability, like atomics or `Cell`.
+///
+/// It is a guard, see `HWBPGuard`: it has to be applied to the threads,
+/// and dropping it puts back what its hardware breakpoint held before in all of them,
+/// so that neither the breakpoint nor its callback outlive the variable.
+pub struct Watch<'a, T, B: Backend = NativeBackend> {
+    guard: HWBPGuard<'a, B>,
+    variable: PhantomData<&'a T>,
+}
+
+impl<T, B: Backend> fmt::Debug for Watch<'_, T, B> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Watch").field("guard", &self.guard).finish()
+    }
+}
+
+impl<'a, T: Watchable, B: Backend> Watch<'a, T, B> {
+    pub(crate) fn new(guard: HWBPGuard<'a, B>, _: &'a T) -> Self {
+        Self {
+            guard,
+            variable: PhantomData,
+        }
+    }
+
+    /// Gets the hardware breakpoint of the watch.
+    pub fn get_hwbp(&self) -> &HWBP<B::Registers> {
+        self.guard.get_hwbp()
+    }
+
+    /// Gets how many times the variable was hit.
+    pub fn get_hit_count(&self) -> u64 {
+        self.guard.get_hit_count()
+    }
+
+    /// Applies the watch to all existing threads, see `HWBPGuard::apply_for_all_threads`.
+    pub fn apply_for_all_threads(&mut self) -> Result<(), ContextError> {
+        self.guard.apply_for_all_threads()
+    }
+
+    /// Applies the watch to the current thread.
+    pub fn apply_for_current_thread(&mut self) -> Result<(), ContextError> {
+        self.guard.apply_for_current_thread()
+    }
+
+    /// Applies the watch to a specific thread by id.
+    pub fn apply_for_thread(&mut self, thread_id: u32) -> Result<(), ContextError> {
+        self.guard.apply_for_thread(thread_id)
+    }
+
+    /// Removes the watch from every thread it was applied to,
+    /// which dropping it does too, but ignoring errors, see `HWBPGuard::restore`.
+    pub fn restore(self) -> Result<(), ContextError> {
+        self.guard.restore()
+    }
+}
+
+/// Wraps a callback taking a snapshot of the watched variable.
+///
+/// Hits whose value cannot be read continue without calling the callback.
+pub(crate) fn snapshot_callback<T: Watchable, R, A: Into<HitAction>>(
+    callback: impl Fn(&mut HitContext<'_, R>, &T) -> A + Send + Sync + 'static,
+) -> HWBPCallback<R> {
+    Arc::new(move |hit| {
+        // The breakpoint watches a `T`, so its memory holds one.
+        match unsafe { read_value::<T, R>(hit) } {
+            Ok(value) => callback(hit, &value).into(),
+            Err(_) => HitAction::Continue,
+        }
+    })
+}
+
+/// The value of a watched variable before and after a write.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct ValueChange<T> {
+    /// The value before the write,
+    /// as of the previous hit or when the watch was armed.
+    pub old: T,
+    /// The value after the write.
+    pub new: T,
+}
+
+impl<T: PartialEq> ValueChange<T> {
+    /// Gets whether the write changed the value.
+    pub fn is_changed(&self) -> bool {
+        self.old != self.new
+    }
+}
+
+/// Reads a `T` from the memory of the target of a hit.
+///
+/// # Safety
+/// The memory must hold a valid `T`.
+unsafe fn read_value<T, R>(hit: &HitContext<'_, R>) -> io::Result<T> {
+    let mut value = MaybeUninit::<T>::zeroed();
+    let buffer = std::slice::from_raw_parts_mut(value.as_mut_ptr().cast::<u8>(), size_of::<T>());
+    hit.read_memory(hit.get_address(), buffer)?;
+    Ok(value.assume_init())
+}
+
+/// Wraps a callback taking the old and new values of a watched variable,
+/// keeping a shadow copy of it that starts at `*variable`,
+/// is taken again by the returned `ArmCallback` and is refreshed on every hit.
+/// `T` must be 1, 2, 4 or 8 bytes, so that the copy fits in an atomic.
+///
+/// Hits whose value cannot be read, and no-op writes if `only_changes` is set,
+/// continue without calling the callback.
+pub(crate) fn change_callback<T, R, A: Into<HitAction>>(
+    variable: &T,
+    only_changes: bool,
+    callback: impl Fn(&mut HitContext<'_, R>, ValueChange<T>) -> A + Send + Sync + 'static,
+) -> (HWBPCallback<R>, ArmCallback)
+where
+    T: Copy + PartialEq + Send + 'static,
+{
+    debug_assert!(size_of::<T>() <= size_of::<u64>());
+    let address = variable as *const T as u64;
+    let shadow = Arc::new(AtomicU64::new(to_bits(*variable)));
+
+    let arm_shadow = shadow.clone();
+    let arm: ArmCallback = Arc::new(move |memory| {
+        let mut bits = [0; 8];
+        if let Some(Ok(())) = memory.map(|memory| memory(address, &mut bits[..size_of::<T>()])) {
+            arm_shadow.store(u64::from_ne_bytes(bits), Ordering::SeqCst);
+        }
+    });
+
+    let callback: HWBPCallback<R> = Arc::new(move |hit| {
+        // The breakpoint watches a `T`, so its memory holds one.
+        let Ok(new) = (unsafe { read_value::<T, R>(hit) }) else {
+            return HitAction::Continue;
+        };
+        // The shadow only ever holds the bytes of a `T`.
+        let old = unsafe { from_bits(shadow.swap(to_bits(new), Ordering::SeqCst)) };
+
+        let change = ValueChange { old, new };
+        if only_changes && !change.is_changed() {
+            return HitAction::Continue;
+        }
+
+        callback(hit, change).into()
+    });
+    (callback, arm)
+}
+
+/// Copies the bytes of a `T` of at most 8 bytes into a `u64`.
+fn to_bits<T: Copy>(value: T) -> u64 {
+    let mut bits = [0; 8];
+    // `bits` has room for the bytes of `value`.
+    unsafe {
+        std::ptr::copy_nonoverlapping(
+            (&value as *const T).cast::<u8>(),
+            bits.as_mut_ptr(),
+            size_of::<T>(),
+        );
+    }
+    u64::from_ne_bytes(bits)
+}
+
+/// Gets the `T` whose bytes were copied by `to_bits`.
+///
+/// # Safety
+/// The bytes must hold a valid `T`.
+unsafe fn from_bits<T: Copy>(bits: u64) -> T {
+    let bits = bits.to_ne_bytes();
+    std::ptr::read_unaligned(bits.as_ptr().cast::<T>())
+}
@@ -8,15 +8,15 @@ pub struct DR6 {
     pub bp_detected_1: bool,
     pub bp_detected_2: bool,
     pub bp_detected_3: bool,
-    #[bits(6)]
+    #[bits(7)]
     reserved_0: u8,
     blt_exception: bool,
-    smm_or_ice_mode: bool, // see DR7 bit 12
-    dra_detected: bool,    // see DR7 bit 13
-    is_single_step: bool,
-    task_switch: bool,
-    rtm: bool,
-    #[bits(16)]
+    smm_or_ice_mode: bool,  // see DR7 bit 12
+    pub dra_detected: bool, // see DR7 bit 13
+    pub is_single_step: bool,
+    pub task_switch: bool,
+    pub rtm: bool,
+    #[bits(15)]
     reserved_1: u16,
     #[bits(32)]
     reserved_2: u32,